@@ -0,0 +1,107 @@
+use axum::body::Body;
+use axum::extract::Request;
+use axum::Router;
+use hyper::body::Incoming;
+use hyper::server::conn::http1;
+use hyper_util::rt::TokioIo;
+use std::net::SocketAddr;
+use std::os::unix::fs::{FileTypeExt, PermissionsExt};
+use std::path::PathBuf;
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::net::{TcpListener, UnixListener};
+use tower::ServiceExt;
+
+const UNIX_SOCKET_MODE: u32 = 0o660; // Owner and group may use the admin socket
+
+// Where the admin router (the `/api` endpoints) listens: a TCP address such as
+// `127.0.0.1:9090`, or a Unix domain socket given as `unix:/run/proxy/admin.sock`
+#[derive(Clone, Debug)]
+pub enum AdminAddr {
+    Tcp(SocketAddr),
+    Unix(PathBuf),
+}
+
+impl std::str::FromStr for AdminAddr {
+    type Err = ();
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value.strip_prefix("unix:") {
+            Some(path) if !path.is_empty() => Ok(AdminAddr::Unix(PathBuf::from(path))),
+            Some(_) => Err(()),
+            None => value.parse().map(AdminAddr::Tcp).map_err(|_| ()),
+        }
+    }
+}
+
+impl AdminAddr {
+    // Whether only this host can connect
+    pub fn is_local(&self) -> bool {
+        match self {
+            AdminAddr::Tcp(addr) => addr.ip().is_loopback(),
+            AdminAddr::Unix(_) => true,
+        }
+    }
+}
+
+impl std::fmt::Display for AdminAddr {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            AdminAddr::Tcp(addr) => write!(f, "{}", addr),
+            AdminAddr::Unix(path) => write!(f, "unix:{}", path.display()),
+        }
+    }
+}
+
+// Serve the admin router on its own listener, outside the proxy's connection limit
+pub async fn serve_admin(addr: AdminAddr, router: Router) -> std::io::Result<()> {
+    match addr {
+        AdminAddr::Tcp(addr) => {
+            let listener = TcpListener::bind(addr).await?;
+            loop {
+                match listener.accept().await {
+                    Ok((stream, _)) => serve_connection(stream, router.clone()),
+                    Err(e) => tracing::error!("Failed to accept admin connection: {:?}", e),
+                }
+            }
+        }
+        AdminAddr::Unix(path) => {
+            // A socket left behind by a previous run would make the bind fail; anything
+            // else at the path is not ours to remove
+            match std::fs::symlink_metadata(&path) {
+                Ok(metadata) if metadata.file_type().is_socket() => std::fs::remove_file(&path)?,
+                Ok(_) => {
+                    return Err(std::io::Error::new(
+                        std::io::ErrorKind::AlreadyExists,
+                        format!("{} exists and is not a socket", path.display()),
+                    ));
+                }
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+                Err(e) => return Err(e),
+            }
+            let listener = UnixListener::bind(&path)?;
+            std::fs::set_permissions(&path, std::fs::Permissions::from_mode(UNIX_SOCKET_MODE))?;
+
+            loop {
+                match listener.accept().await {
+                    Ok((stream, _)) => serve_connection(stream, router.clone()),
+                    Err(e) => tracing::error!("Failed to accept admin connection: {:?}", e),
+                }
+            }
+        }
+    }
+}
+
+fn serve_connection<I>(stream: I, router: Router)
+where
+    I: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+    tokio::spawn(async move {
+        let service = hyper::service::service_fn(move |req: Request<Incoming>| {
+            router.clone().oneshot(req.map(Body::new))
+        });
+
+        if let Err(e) = http1::Builder::new().serve_connection(TokioIo::new(stream), service).await {
+            tracing::debug!("Admin connection closed: {}", e);
+        }
+    });
+}
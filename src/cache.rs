@@ -0,0 +1,1103 @@
+use axum::body::Body;
+use axum::http::{header, HeaderMap, HeaderName, HeaderValue, Method, Request, Response, StatusCode};
+use bytes::{Bytes, BytesMut};
+use chrono::{DateTime, Utc};
+use http_body::Frame;
+use ring::digest::{digest, SHA256};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::pin::Pin;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+
+use crate::config::Config;
+use crate::filter::BoxError;
+use crate::forward::is_hop_by_hop;
+use crate::AppState;
+
+const MAX_HEURISTIC_SECS: i64 = 24 * 60 * 60; // Cap on freshness guessed from Last-Modified
+const HEURISTIC_FRACTION: i64 = 10; // Guessed freshness is a tenth of the time since Last-Modified
+const MEMORY_OBJECT_FRACTION: u64 = 8; // Larger objects skip the memory tier and live on disk only
+const MAX_RECORDED_LOOKUPS: usize = 100; // Cache results kept on one connection record
+
+// Status codes that may be cached without explicit freshness (RFC 9110 §15.1)
+const HEURISTICALLY_CACHEABLE: &[u16] = &[200, 203, 204, 300, 301, 308, 404, 405, 410, 414, 501];
+// Status codes stored when the response gives an explicit lifetime
+const CACHEABLE_WITH_LIFETIME: &[u16] = &[302, 307];
+
+// Not copied from a 304 onto the stored response (RFC 9111 §3.2)
+const UNUPDATED_HEADERS: &[&str] = &["content-length", "content-encoding", "transfer-encoding", "content-range"];
+
+// Result of looking up a request, recorded on the connection
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CacheLookup {
+    pub url: String,
+    pub result: String, // "hit", "revalidated", "miss" or "bypass"
+}
+
+// A stored response's metadata; the body is kept alongside it in memory or on disk
+#[derive(Clone, Debug, Serialize, Deserialize)]
+struct Meta {
+    url: String,
+    variant: Vec<(String, String)>, // Request headers named by Vary, with the values this response was selected by
+    status: u16,
+    headers: Vec<(String, String)>,
+    request_time: i64, // When the request that fetched it was sent (Unix seconds)
+    response_time: i64, // When its response arrived
+    body_bytes: u64,
+}
+
+impl Meta {
+    fn id(&self) -> String {
+        entry_id(&self.url, &self.variant)
+    }
+
+    fn size(&self) -> u64 {
+        let headers: usize = self.headers.iter().map(|(name, value)| name.len() + value.len()).sum();
+        self.body_bytes + (self.url.len() + headers) as u64
+    }
+
+    fn header(&self, name: &str) -> Option<&str> {
+        self.headers.iter()
+            .find(|(stored, _)| stored.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_str())
+    }
+
+    fn header_map(&self) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        for (name, value) in &self.headers {
+            if let (Ok(name), Ok(value)) = (HeaderName::from_bytes(name.as_bytes()), HeaderValue::from_str(value)) {
+                headers.append(name, value);
+            }
+        }
+        headers
+    }
+
+    fn cache_control(&self) -> CacheControl {
+        CacheControl::parse(&self.header_map())
+    }
+
+    fn date(&self) -> Option<i64> {
+        self.header("date").and_then(parse_http_date)
+    }
+
+    // RFC 9111 §4.2.1, with the heuristic of §4.2.2 for responses without one
+    fn freshness_lifetime(&self) -> i64 {
+        let cache_control = self.cache_control();
+        if let Some(lifetime) = cache_control.s_maxage.or(cache_control.max_age) {
+            return lifetime;
+        }
+        if let Some(expires) = self.header("expires") {
+            // An invalid Expires, such as "0", means already expired
+            return match (parse_http_date(expires), self.date()) {
+                (Some(expires), Some(date)) => expires - date,
+                (Some(expires), None) => expires - self.response_time,
+                (None, _) => 0,
+            };
+        }
+
+        match (self.header("last-modified").and_then(parse_http_date), HEURISTICALLY_CACHEABLE.contains(&self.status)) {
+            (Some(last_modified), true) => {
+                let since = self.date().unwrap_or(self.response_time) - last_modified;
+                (since / HEURISTIC_FRACTION).clamp(0, MAX_HEURISTIC_SECS)
+            }
+            _ => 0,
+        }
+    }
+
+    // RFC 9111 §4.2.3
+    fn current_age(&self, now: i64) -> i64 {
+        let apparent_age = self.date().map_or(0, |date| (self.response_time - date).max(0));
+        let age_value = self.header("age").and_then(|age| age.trim().parse::<i64>().ok()).unwrap_or(0);
+        let corrected_age = age_value + (self.response_time - self.request_time);
+        apparent_age.max(corrected_age) + (now - self.response_time).max(0)
+    }
+
+    fn has_validators(&self) -> bool {
+        self.header("etag").is_some() || self.header("last-modified").is_some()
+    }
+}
+
+// Cache-Control directives from a request or response. Field-qualified forms such as
+// `private="Set-Cookie"` are treated as applying to the whole response.
+#[derive(Debug, Default)]
+struct CacheControl {
+    no_store: bool,
+    no_cache: bool,
+    private: bool,
+    must_revalidate: bool,
+    max_age: Option<i64>,
+    s_maxage: Option<i64>,
+    max_stale: Option<i64>,
+    min_fresh: Option<i64>,
+}
+
+impl CacheControl {
+    fn parse(headers: &HeaderMap) -> Self {
+        let mut cache_control = CacheControl::default();
+
+        let directives = headers.get_all(header::CACHE_CONTROL)
+            .iter()
+            .filter_map(|value| value.to_str().ok())
+            .flat_map(|value| value.split(','))
+            .map(str::trim)
+            .filter(|directive| !directive.is_empty());
+        for directive in directives {
+            let (name, value) = directive.split_once('=').unwrap_or((directive, ""));
+            let seconds = value.trim().trim_matches('"').parse::<i64>().ok().map(|seconds| seconds.max(0));
+
+            match name.trim().to_ascii_lowercase().as_str() {
+                "no-store" => cache_control.no_store = true,
+                "no-cache" => cache_control.no_cache = true,
+                "private" => cache_control.private = true,
+                "must-revalidate" | "proxy-revalidate" => cache_control.must_revalidate = true,
+                "max-age" => cache_control.max_age = seconds,
+                // s-maxage also forbids serving the response stale (§5.2.2.10)
+                "s-maxage" => {
+                    cache_control.s_maxage = seconds;
+                    cache_control.must_revalidate = true;
+                }
+                "max-stale" => cache_control.max_stale = Some(seconds.unwrap_or(i64::MAX)),
+                "min-fresh" => cache_control.min_fresh = seconds,
+                _ => {}
+            }
+        }
+
+        // HTTP/1.0 clients ask for an end-to-end reload with Pragma (§5.4)
+        let pragma_no_cache = headers.get(header::PRAGMA)
+            .and_then(|value| value.to_str().ok())
+            .is_some_and(|value| value.to_ascii_lowercase().contains("no-cache"));
+        if pragma_no_cache && headers.get(header::CACHE_CONTROL).is_none() {
+            cache_control.no_cache = true;
+        }
+
+        cache_control
+    }
+}
+
+// The parts of a request the cache needs after it has been forwarded
+pub struct CacheRequest {
+    method: Method,
+    url: String,
+    headers: HeaderMap,
+    time: i64,
+}
+
+impl CacheRequest {
+    pub fn new<B>(req: &Request<B>, url: &str) -> Self {
+        CacheRequest {
+            method: req.method().clone(),
+            url: url.to_string(),
+            headers: req.headers().clone(),
+            time: Utc::now().timestamp(),
+        }
+    }
+
+    // Only plain GET and HEAD requests are answered from the cache. Requests carrying
+    // credentials or asking for a range go straight through, as does `no-store`.
+    fn cacheable(&self) -> bool {
+        (self.method == Method::GET || self.method == Method::HEAD)
+            && !self.headers.contains_key(header::AUTHORIZATION)
+            && !self.headers.contains_key(header::RANGE)
+            && !CacheControl::parse(&self.headers).no_store
+    }
+
+    fn conditional(&self) -> bool {
+        self.headers.contains_key(header::IF_NONE_MATCH) || self.headers.contains_key(header::IF_MODIFIED_SINCE)
+    }
+
+    fn matches(&self, variant: &[(String, String)]) -> bool {
+        variant.iter().all(|(name, value)| &header_values(&self.headers, name) == value)
+    }
+
+    // Whether the client's own validators match the stored response, so a 304 will do
+    fn not_modified(&self, meta: &Meta) -> bool {
+        if let Some(if_none_match) = self.headers.get(header::IF_NONE_MATCH).and_then(|value| value.to_str().ok()) {
+            let Some(etag) = meta.header("etag") else {
+                return false;
+            };
+            return if_none_match.split(',')
+                .map(str::trim)
+                .any(|candidate| candidate == "*" || weak_tag(candidate) == weak_tag(etag));
+        }
+
+        let if_modified_since = self.headers.get(header::IF_MODIFIED_SINCE)
+            .and_then(|value| value.to_str().ok())
+            .and_then(parse_http_date);
+        let last_modified = meta.header("last-modified").and_then(parse_http_date);
+        matches!((if_modified_since, last_modified), (Some(since), Some(modified)) if modified <= since)
+    }
+}
+
+// A stored response found for a request
+pub struct Found {
+    meta: Arc<Meta>,
+    body: Bytes,
+}
+
+impl Found {
+    // Ask the origin whether the stored response is still current
+    pub fn add_validators(&self, headers: &mut HeaderMap) {
+        if let Some(etag) = self.meta.header("etag").and_then(|etag| HeaderValue::from_str(etag).ok()) {
+            headers.insert(header::IF_NONE_MATCH, etag);
+        }
+        if let Some(last_modified) = self.meta.header("last-modified").and_then(|date| HeaderValue::from_str(date).ok()) {
+            headers.insert(header::IF_MODIFIED_SINCE, last_modified);
+        }
+    }
+}
+
+pub enum Lookup {
+    Hit(Response<Body>), // A fresh stored response, or a 304 for the client's own validators
+    Revalidate(Found), // Stale, but can be checked with a conditional request
+    Miss,
+    Bypass, // The request may not be answered from the cache
+}
+
+// One storage tier, evicting the least recently used entries beyond its capacity
+struct Tier {
+    capacity: u64,
+    used: u64,
+    clock: u64,
+    entries: HashMap<String, Stored>,
+    by_url: HashMap<String, HashSet<String>>,
+    recency: BTreeMap<u64, String>,
+}
+
+struct Stored {
+    meta: Arc<Meta>,
+    body: Option<Bytes>, // Memory tier only; the disk tier reads it from its file
+    last_used: u64,
+}
+
+impl Tier {
+    fn new(capacity: u64) -> Self {
+        Tier {
+            capacity,
+            used: 0,
+            clock: 0,
+            entries: HashMap::new(),
+            by_url: HashMap::new(),
+            recency: BTreeMap::new(),
+        }
+    }
+
+    // The newest stored response for `url` whose Vary values match the request
+    fn find(&mut self, request: &CacheRequest) -> Option<(Arc<Meta>, Option<Bytes>)> {
+        let id = self.by_url.get(&request.url)?
+            .iter()
+            .filter_map(|id| self.entries.get(id).map(|stored| (id, stored)))
+            .filter(|(_, stored)| request.matches(&stored.meta.variant))
+            .max_by_key(|(_, stored)| stored.meta.response_time)
+            .map(|(id, _)| id.clone())?;
+
+        self.clock += 1;
+        let stored = self.entries.get_mut(&id)?;
+        self.recency.remove(&stored.last_used);
+        stored.last_used = self.clock;
+        self.recency.insert(self.clock, id);
+
+        Some((stored.meta.clone(), stored.body.clone()))
+    }
+
+    // Store an entry, returning the ids evicted to make room for it
+    fn insert(&mut self, meta: Arc<Meta>, body: Option<Bytes>) -> Vec<String> {
+        let id = meta.id();
+        self.remove(&id);
+        if meta.size() > self.capacity {
+            return Vec::new();
+        }
+
+        let mut evicted = Vec::new();
+        while self.used + meta.size() > self.capacity {
+            let Some((_, oldest)) = self.recency.pop_first() else {
+                break;
+            };
+            self.remove(&oldest);
+            evicted.push(oldest);
+        }
+
+        self.clock += 1;
+        self.used += meta.size();
+        self.by_url.entry(meta.url.clone()).or_default().insert(id.clone());
+        self.recency.insert(self.clock, id.clone());
+        self.entries.insert(id, Stored { meta, body, last_used: self.clock });
+
+        evicted
+    }
+
+    fn remove(&mut self, id: &str) -> Option<Stored> {
+        let stored = self.entries.remove(id)?;
+        self.used -= stored.meta.size();
+        self.recency.remove(&stored.last_used);
+        if let Some(ids) = self.by_url.get_mut(&stored.meta.url) {
+            ids.remove(id);
+            if ids.is_empty() {
+                self.by_url.remove(&stored.meta.url);
+            }
+        }
+        Some(stored)
+    }
+
+    fn purge(&mut self, url: Option<&str>) -> Vec<String> {
+        let ids: Vec<String> = match url {
+            Some(url) => self.by_url.get(url).map(|ids| ids.iter().cloned().collect()).unwrap_or_default(),
+            None => self.entries.keys().cloned().collect(),
+        };
+        ids.into_iter().filter(|id| self.remove(id).is_some()).collect()
+    }
+
+    fn stats(&self) -> TierStats {
+        TierStats {
+            entries: self.entries.len(),
+            bytes: self.used,
+            capacity: self.capacity,
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct TierStats {
+    pub entries: usize,
+    pub bytes: u64,
+    pub capacity: u64,
+}
+
+#[derive(Debug, Serialize)]
+pub struct CacheStats {
+    pub memory: Option<TierStats>,
+    pub disk: Option<TierStats>,
+    pub hits: u64,
+    pub revalidations: u64,
+    pub misses: u64,
+}
+
+// The disk tier keeps each entry as `<id>.meta` (JSON) and `<id>.body` in CACHE_DIR,
+// with its index in memory so lookups only touch the disk for bodies
+struct DiskTier {
+    dir: PathBuf,
+    index: Arc<Mutex<Tier>>,
+}
+
+impl DiskTier {
+    // Rebuild the index from the files a previous run left behind
+    fn open(dir: &str, capacity: u64) -> std::io::Result<Self> {
+        let dir = PathBuf::from(dir);
+        fs::create_dir_all(&dir)?;
+
+        let mut found = Vec::new();
+        for file in fs::read_dir(&dir)? {
+            let path = file?.path();
+            // Left by a write that was interrupted
+            if path.extension().is_some_and(|extension| extension == "tmp") {
+                let _ = fs::remove_file(&path);
+            }
+            if path.extension().is_some_and(|extension| extension == "meta") {
+                let meta = fs::read(&path).ok().and_then(|contents| serde_json::from_slice::<Meta>(&contents).ok());
+                let body_len = fs::metadata(path.with_extension("body")).map(|body| body.len()).ok();
+
+                match meta {
+                    Some(meta) if body_len == Some(meta.body_bytes) => found.push(meta),
+                    _ => remove_files(&path.with_extension("")),
+                }
+            }
+        }
+
+        // Without access times, the most recently fetched entries are kept
+        found.sort_by_key(|meta| meta.response_time);
+        let mut index = Tier::new(capacity);
+        for meta in found {
+            for evicted in index.insert(Arc::new(meta), None) {
+                remove_files(&dir.join(evicted));
+            }
+        }
+
+        Ok(DiskTier { dir, index: Arc::new(Mutex::new(index)) })
+    }
+
+    fn path(&self, id: &str) -> PathBuf {
+        self.dir.join(id)
+    }
+
+    // The entry is only indexed once its files are in place, so a lookup never finds a
+    // body that is missing or still being written
+    fn insert(&self, meta: Arc<Meta>, body: Bytes) {
+        let id = meta.id();
+        let mut index = self.index.lock().unwrap();
+        if meta.size() > index.capacity {
+            return;
+        }
+        // A replaced entry's files are about to be overwritten
+        index.remove(&id);
+        drop(index);
+
+        let dir = self.dir.clone();
+        let index = self.index.clone();
+        tokio::task::spawn_blocking(move || {
+            let base = dir.join(&id);
+            if let Err(e) = write_entry(&base, &meta, Some(&body)) {
+                tracing::warn!("⚠️ Failed to write cache entry {}: {}", base.display(), e);
+                remove_files(&base);
+                return;
+            }
+
+            let evicted = index.lock().unwrap().insert(meta, None);
+            for evicted in evicted {
+                remove_files(&dir.join(evicted));
+            }
+        });
+    }
+
+    // Replace an entry's metadata after revalidation, keeping its body file
+    fn update(&self, meta: Arc<Meta>) {
+        let mut index = self.index.lock().unwrap();
+        if !index.entries.contains_key(&meta.id()) {
+            return;
+        }
+        index.insert(meta.clone(), None);
+        drop(index);
+
+        let base = self.path(&meta.id());
+        tokio::task::spawn_blocking(move || {
+            if let Err(e) = write_entry(&base, &meta, None) {
+                tracing::warn!("⚠️ Failed to update cache entry {}: {}", base.display(), e);
+            }
+        });
+    }
+
+    async fn find(&self, request: &CacheRequest) -> Option<Found> {
+        let (meta, _) = self.index.lock().unwrap().find(request)?;
+        match tokio::fs::read(self.path(&meta.id()).with_extension("body")).await {
+            Ok(body) if body.len() as u64 == meta.body_bytes => Some(Found { meta, body: Bytes::from(body) }),
+            _ => {
+                self.index.lock().unwrap().remove(&meta.id());
+                self.discard(vec![meta.id()]);
+                None
+            }
+        }
+    }
+
+    fn purge(&self, url: Option<&str>) -> usize {
+        let ids = self.index.lock().unwrap().purge(url);
+        let purged = ids.len();
+        self.discard(ids);
+        purged
+    }
+
+    // Delete the files of entries already dropped from the index
+    fn discard(&self, ids: Vec<String>) {
+        if ids.is_empty() {
+            return;
+        }
+        let paths: Vec<PathBuf> = ids.iter().map(|id| self.path(id)).collect();
+
+        tokio::task::spawn_blocking(move || {
+            for path in paths {
+                remove_files(&path);
+            }
+        });
+    }
+}
+
+fn write_entry(base: &Path, meta: &Meta, body: Option<&Bytes>) -> std::io::Result<()> {
+    // Written under temporary names and renamed, so a crash never leaves a torn entry.
+    // The names are unique, since two responses for the same entry may be stored at once.
+    static SEQUENCE: AtomicU64 = AtomicU64::new(0);
+    let write = |extension: &str, contents: &[u8]| {
+        let temp = base.with_extension(format!("{}.{}.tmp", extension, SEQUENCE.fetch_add(1, Ordering::Relaxed)));
+        fs::write(&temp, contents)?;
+        fs::rename(&temp, base.with_extension(extension))
+    };
+
+    if let Some(body) = body {
+        write("body", body)?;
+    }
+    write("meta", &serde_json::to_vec(meta)?)
+}
+
+fn remove_files(base: &Path) {
+    let _ = fs::remove_file(base.with_extension("meta"));
+    let _ = fs::remove_file(base.with_extension("body"));
+}
+
+// Shared HTTP cache (RFC 9111) for forwarded and intercepted requests, with a memory
+// tier for small hot objects and an optional disk tier that survives restarts
+pub struct Cache {
+    memory: Option<Mutex<Tier>>,
+    disk: Option<DiskTier>,
+    max_object_bytes: u64,
+    hits: AtomicU64,
+    revalidations: AtomicU64,
+    misses: AtomicU64,
+}
+
+impl Cache {
+    // Caching is only enabled when CACHE_MEMORY_BYTES or CACHE_DIR is set
+    pub fn from_config(config: &Config) -> std::io::Result<Option<Self>> {
+        if config.cache_memory_bytes.is_none() && config.cache_dir.is_none() {
+            return Ok(None);
+        }
+
+        let disk = match &config.cache_dir {
+            Some(dir) => Some(DiskTier::open(dir, config.cache_disk_bytes)?),
+            None => None,
+        };
+
+        Ok(Some(Cache {
+            memory: config.cache_memory_bytes.map(|capacity| Mutex::new(Tier::new(capacity))),
+            disk,
+            max_object_bytes: config.cache_max_object_bytes,
+            hits: AtomicU64::new(0),
+            revalidations: AtomicU64::new(0),
+            misses: AtomicU64::new(0),
+        }))
+    }
+
+    pub async fn lookup(&self, request: &CacheRequest) -> Lookup {
+        if !request.cacheable() {
+            return Lookup::Bypass;
+        }
+
+        let Some(found) = self.find(request).await else {
+            self.misses.fetch_add(1, Ordering::Relaxed);
+            return Lookup::Miss;
+        };
+
+        let now = Utc::now().timestamp();
+        let age = found.meta.current_age(now);
+        let lifetime = found.meta.freshness_lifetime();
+        let stored_cache_control = found.meta.cache_control();
+        let request_cache_control = CacheControl::parse(&request.headers);
+
+        let mut fresh = lifetime > age;
+        if let Some(max_age) = request_cache_control.max_age {
+            fresh &= age <= max_age;
+        }
+        if let Some(min_fresh) = request_cache_control.min_fresh {
+            fresh &= lifetime - age >= min_fresh;
+        }
+        // A client may accept a stale response, unless the origin forbade serving it stale
+        if let Some(max_stale) = request_cache_control.max_stale {
+            fresh |= !stored_cache_control.must_revalidate && age - lifetime <= max_stale;
+        }
+        if request_cache_control.no_cache || stored_cache_control.no_cache {
+            fresh = false;
+        }
+
+        if fresh {
+            self.hits.fetch_add(1, Ordering::Relaxed);
+            return Lookup::Hit(respond(request, &found, age));
+        }
+
+        // A client's own conditional request is forwarded as it is
+        if found.meta.has_validators() && !request.conditional() {
+            return Lookup::Revalidate(found);
+        }
+
+        self.misses.fetch_add(1, Ordering::Relaxed);
+        Lookup::Miss
+    }
+
+    async fn find(&self, request: &CacheRequest) -> Option<Found> {
+        if let Some(memory) = &self.memory {
+            if let Some((meta, Some(body))) = memory.lock().unwrap().find(request) {
+                return Some(Found { meta, body });
+            }
+        }
+
+        let found = self.disk.as_ref()?.find(request).await?;
+        // Promote to memory, so the next hit does not touch the disk
+        if let Some(memory) = &self.memory {
+            let mut memory = memory.lock().unwrap();
+            if found.meta.size() <= memory.capacity / MEMORY_OBJECT_FRACTION {
+                memory.insert(found.meta.clone(), Some(found.body.clone()));
+            }
+        }
+        Some(found)
+    }
+
+    // The origin answered a revalidation with 304: refresh the stored headers and
+    // serve the stored body (RFC 9111 §4.3.4)
+    pub fn refresh(&self, request: &CacheRequest, found: Found, not_modified: &HeaderMap) -> Response<Body> {
+        let mut meta = (*found.meta).clone();
+        for name in not_modified.keys() {
+            if UNUPDATED_HEADERS.contains(&name.as_str()) {
+                continue;
+            }
+            meta.headers.retain(|(stored, _)| !stored.eq_ignore_ascii_case(name.as_str()));
+            for value in not_modified.get_all(name).iter().filter_map(|value| value.to_str().ok()) {
+                meta.headers.push((name.to_string(), value.to_string()));
+            }
+        }
+        meta.request_time = request.time;
+        meta.response_time = Utc::now().timestamp();
+        let meta = Arc::new(meta);
+
+        if let Some(memory) = &self.memory {
+            let mut memory = memory.lock().unwrap();
+            if memory.entries.contains_key(&meta.id()) {
+                memory.insert(meta.clone(), Some(found.body.clone()));
+            }
+        }
+        if let Some(disk) = &self.disk {
+            disk.update(meta.clone());
+        }
+
+        self.revalidations.fetch_add(1, Ordering::Relaxed);
+        let found = Found { meta, body: found.body };
+        let age = found.meta.current_age(Utc::now().timestamp());
+        respond(request, &found, age)
+    }
+
+    // Pass a response on to the client, storing a copy once its body has been read in full
+    // if it may be cached
+    pub fn store(self: &Arc<Self>, request: &CacheRequest, response: Response<Body>) -> Response<Body> {
+        if request.method != Method::GET || !request.cacheable() {
+            return response;
+        }
+        let Some(meta) = self.storable(request, &response) else {
+            return response;
+        };
+
+        let (parts, body) = response.into_parts();
+        let body = Recording {
+            inner: body,
+            cache: self.clone(),
+            meta: Some(meta),
+            collected: BytesMut::new(),
+        };
+
+        Response::from_parts(parts, Body::new(body))
+    }
+
+    // RFC 9111 §3, for a shared cache
+    fn storable(&self, request: &CacheRequest, response: &Response<Body>) -> Option<Meta> {
+        let status = response.status().as_u16();
+        let cache_control = CacheControl::parse(response.headers());
+        if cache_control.no_store || cache_control.private {
+            return None;
+        }
+        // Cookies are per user, so responses setting them are never shared
+        if response.headers().contains_key(header::SET_COOKIE) {
+            return None;
+        }
+
+        let explicit = cache_control.max_age.is_some()
+            || cache_control.s_maxage.is_some()
+            || response.headers().contains_key(header::EXPIRES);
+        let understood = HEURISTICALLY_CACHEABLE.contains(&status)
+            || (explicit && CACHEABLE_WITH_LIFETIME.contains(&status));
+        if !understood {
+            return None;
+        }
+        if response.headers().get(header::CONTENT_LENGTH)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.parse::<u64>().ok())
+            .is_some_and(|length| length > self.max_object_bytes)
+        {
+            return None;
+        }
+
+        let mut variant = Vec::new();
+        for name in vary_names(response.headers()) {
+            if name == "*" {
+                return None;
+            }
+            variant.push((name.clone(), header_values(&request.headers, &name)));
+        }
+
+        let meta = Meta {
+            url: request.url.clone(),
+            variant,
+            status,
+            headers: response.headers().iter()
+                .filter(|(name, _)| !is_hop_by_hop(name.as_str()))
+                .filter_map(|(name, value)| Some((name.to_string(), value.to_str().ok()?.to_string())))
+                .collect(),
+            request_time: request.time,
+            response_time: Utc::now().timestamp(),
+            body_bytes: 0,
+        };
+
+        // A response that is stale on arrival and cannot be revalidated is of no use later
+        if meta.freshness_lifetime() <= 0 && !meta.has_validators() {
+            return None;
+        }
+
+        Some(meta)
+    }
+
+    fn insert(&self, mut meta: Meta, body: Bytes) {
+        meta.body_bytes = body.len() as u64;
+        let meta = Arc::new(meta);
+        tracing::debug!("💾 Cached {} ({} bytes)", meta.url, meta.body_bytes);
+
+        if let Some(memory) = &self.memory {
+            let mut memory = memory.lock().unwrap();
+            if meta.size() <= memory.capacity / MEMORY_OBJECT_FRACTION {
+                memory.insert(meta.clone(), Some(body.clone()));
+            }
+        }
+        if let Some(disk) = &self.disk {
+            disk.insert(meta, body);
+        }
+    }
+
+    // A successful unsafe request (POST, PUT, DELETE...) makes stored responses for
+    // its URL stale (RFC 9111 §4.4)
+    pub fn invalidate(&self, request: &CacheRequest, status: StatusCode) {
+        let unsafe_method = !matches!(request.method, Method::GET | Method::HEAD | Method::OPTIONS | Method::TRACE);
+        if unsafe_method && (status.is_success() || status.is_redirection()) {
+            self.purge(Some(&request.url));
+        }
+    }
+
+    // Remove the stored responses for `url` (all of its variants), or everything
+    pub fn purge(&self, url: Option<&str>) -> usize {
+        let memory = self.memory.as_ref().map_or(0, |memory| memory.lock().unwrap().purge(url).len());
+        let disk = self.disk.as_ref().map_or(0, |disk| disk.purge(url));
+        memory.max(disk)
+    }
+
+    pub fn stats(&self) -> CacheStats {
+        CacheStats {
+            memory: self.memory.as_ref().map(|memory| memory.lock().unwrap().stats()),
+            disk: self.disk.as_ref().map(|disk| disk.index.lock().unwrap().stats()),
+            hits: self.hits.load(Ordering::Relaxed),
+            revalidations: self.revalidations.load(Ordering::Relaxed),
+            misses: self.misses.load(Ordering::Relaxed),
+        }
+    }
+}
+
+// Build the response for a stored entry, or a 304 when the client's validators match
+fn respond(request: &CacheRequest, found: &Found, age: i64) -> Response<Body> {
+    let not_modified = request.not_modified(&found.meta);
+    let status = match not_modified {
+        true => StatusCode::NOT_MODIFIED,
+        false => StatusCode::from_u16(found.meta.status).unwrap_or(StatusCode::OK),
+    };
+
+    let mut headers = found.meta.header_map();
+    headers.insert(header::AGE, HeaderValue::from(age.max(0)));
+    if not_modified {
+        headers.remove(header::CONTENT_LENGTH);
+    }
+
+    let body = match not_modified || request.method == Method::HEAD {
+        true => Body::empty(),
+        false => Body::from(found.body.clone()),
+    };
+
+    let mut response = Response::new(body);
+    *response.status_mut() = status;
+    *response.headers_mut() = headers;
+    response
+}
+
+pub fn record(app_state: &AppState, conn_key: &str, url: &str, result: &str) {
+    if let Some(mut conn) = app_state.monitoring_state.get_mut(conn_key) {
+        if conn.cache.len() < MAX_RECORDED_LOOKUPS {
+            conn.cache.push(CacheLookup {
+                url: url.to_string(),
+                result: result.to_string(),
+            });
+        }
+    }
+}
+
+// Copies a response body as it streams to the client, storing it once complete
+struct Recording {
+    inner: Body,
+    cache: Arc<Cache>,
+    meta: Option<Meta>, // Taken once stored, or when the body turns out not to be storable
+    collected: BytesMut,
+}
+
+impl Recording {
+    fn finish(&mut self) {
+        if let Some(meta) = self.meta.take() {
+            let body = std::mem::take(&mut self.collected).freeze();
+            self.cache.insert(meta, body);
+        }
+    }
+}
+
+impl http_body::Body for Recording {
+    type Data = Bytes;
+    type Error = BoxError;
+
+    fn poll_frame(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Result<Frame<Bytes>, BoxError>>> {
+        let this = &mut *self;
+        let frame = match Pin::new(&mut this.inner).poll_frame(cx) {
+            Poll::Ready(Some(Ok(frame))) => frame,
+            Poll::Ready(Some(Err(e))) => {
+                this.meta = None;
+                return Poll::Ready(Some(Err(e.into())));
+            }
+            Poll::Ready(None) => {
+                this.finish();
+                return Poll::Ready(None);
+            }
+            Poll::Pending => return Poll::Pending,
+        };
+
+        if let (Some(data), Some(_)) = (frame.data_ref(), &this.meta) {
+            if this.collected.len() as u64 + data.len() as u64 > this.cache.max_object_bytes {
+                this.meta = None;
+                this.collected = BytesMut::new();
+            } else {
+                this.collected.extend_from_slice(data);
+            }
+        }
+        // Bodies of known length are not polled again once the last byte is in
+        if this.inner.is_end_stream() {
+            this.finish();
+        }
+
+        Poll::Ready(Some(Ok(frame)))
+    }
+
+    fn is_end_stream(&self) -> bool {
+        self.inner.is_end_stream()
+    }
+
+    fn size_hint(&self) -> http_body::SizeHint {
+        self.inner.size_hint()
+    }
+}
+
+fn entry_id(url: &str, variant: &[(String, String)]) -> String {
+    let mut key = url.to_string();
+    for (name, value) in variant {
+        key.push_str(&format!("\n{}: {}", name, value));
+    }
+
+    digest(&SHA256, key.as_bytes()).as_ref()[..16]
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect()
+}
+
+fn vary_names(headers: &HeaderMap) -> Vec<String> {
+    let mut names: Vec<String> = headers.get_all(header::VARY)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .map(|name| name.trim().to_ascii_lowercase())
+        .filter(|name| !name.is_empty())
+        .collect();
+    names.sort();
+    names.dedup();
+    names
+}
+
+// All values of a request header, normalised for comparing Vary variants
+fn header_values(headers: &HeaderMap, name: &str) -> String {
+    headers.get_all(name)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .map(str::trim)
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+fn weak_tag(tag: &str) -> &str {
+    tag.trim().trim_start_matches("W/")
+}
+
+fn parse_http_date(value: &str) -> Option<i64> {
+    DateTime::parse_from_rfc2822(value.trim()).ok().map(|date| date.timestamp())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    const DATE: &str = "Mon, 19 Oct 2026 12:00:00 GMT";
+
+    fn headers(pairs: &[(&str, &str)]) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        for (name, value) in pairs {
+            headers.append(HeaderName::from_bytes(name.as_bytes()).unwrap(), HeaderValue::from_str(value).unwrap());
+        }
+        headers
+    }
+
+    fn meta(status: u16, pairs: &[(&str, &str)]) -> Meta {
+        let response_time = parse_http_date(DATE).unwrap();
+        Meta {
+            url: "http://example.com/".to_string(),
+            variant: Vec::new(),
+            status,
+            headers: pairs.iter().map(|(name, value)| (name.to_string(), value.to_string())).collect(),
+            request_time: response_time,
+            response_time,
+            body_bytes: 0,
+        }
+    }
+
+    fn request(method: Method, pairs: &[(&str, &str)]) -> CacheRequest {
+        CacheRequest {
+            method,
+            url: "http://example.com/".to_string(),
+            headers: headers(pairs),
+            time: Utc::now().timestamp(),
+        }
+    }
+
+    fn memory_cache() -> Cache {
+        Cache {
+            memory: Some(Mutex::new(Tier::new(1 << 20))),
+            disk: None,
+            max_object_bytes: 1 << 16,
+            hits: AtomicU64::new(0),
+            revalidations: AtomicU64::new(0),
+            misses: AtomicU64::new(0),
+        }
+    }
+
+    fn response(pairs: &[(&str, &str)]) -> Response<Body> {
+        let mut response = Response::new(Body::empty());
+        *response.headers_mut() = headers(pairs);
+        response
+    }
+
+    #[test]
+    fn parses_cache_control_directives() {
+        let cache_control = CacheControl::parse(&headers(&[
+            ("cache-control", "No-Cache, max-age=\"60\", s-maxage=-5"),
+            ("cache-control", "private=\"Set-Cookie\", max-stale, min-fresh=10, unknown=1"),
+        ]));
+
+        assert!(cache_control.no_cache && cache_control.private && !cache_control.no_store);
+        assert_eq!(cache_control.max_age, Some(60));
+        assert_eq!(cache_control.s_maxage, Some(0));
+        assert!(cache_control.must_revalidate);
+        assert_eq!(cache_control.max_stale, Some(i64::MAX));
+        assert_eq!(cache_control.min_fresh, Some(10));
+    }
+
+    #[test]
+    fn honours_pragma_only_without_cache_control() {
+        assert!(CacheControl::parse(&headers(&[("pragma", "no-cache")])).no_cache);
+        assert!(!CacheControl::parse(&headers(&[("pragma", "no-cache"), ("cache-control", "max-age=5")])).no_cache);
+    }
+
+    #[test]
+    fn computes_freshness_lifetimes() {
+        assert_eq!(meta(200, &[("cache-control", "max-age=60, s-maxage=600")]).freshness_lifetime(), 600);
+        assert_eq!(meta(200, &[("date", DATE), ("expires", "Mon, 19 Oct 2026 13:00:00 GMT")]).freshness_lifetime(), 3600);
+        assert_eq!(meta(200, &[("cache-control", "max-age=60"), ("expires", "0")]).freshness_lifetime(), 60);
+        assert_eq!(meta(200, &[("expires", "0")]).freshness_lifetime(), 0);
+
+        // A tenth of the time since Last-Modified, capped at a day
+        assert_eq!(meta(200, &[("date", DATE), ("last-modified", "Mon, 19 Oct 2026 02:00:00 GMT")]).freshness_lifetime(), 3600);
+        assert_eq!(meta(200, &[("date", DATE), ("last-modified", "Sat, 19 Oct 2024 12:00:00 GMT")]).freshness_lifetime(), MAX_HEURISTIC_SECS);
+        assert_eq!(meta(302, &[("date", DATE), ("last-modified", "Mon, 19 Oct 2026 02:00:00 GMT")]).freshness_lifetime(), 0);
+    }
+
+    #[test]
+    fn computes_the_current_age() {
+        let mut stored = meta(200, &[("date", "Mon, 19 Oct 2026 11:59:50 GMT"), ("age", "30")]);
+        stored.request_time -= 2;
+
+        assert_eq!(stored.current_age(stored.response_time + 100), 32 + 100);
+        assert_eq!(meta(200, &[("date", "Mon, 19 Oct 2026 11:59:50 GMT")]).current_age(parse_http_date(DATE).unwrap()), 10);
+    }
+
+    #[tokio::test]
+    async fn indexes_disk_entries_once_written() {
+        let dir = std::env::temp_dir().join(format!("proxy-cache-test-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        let tier = DiskTier::open(dir.to_str().unwrap(), 1 << 20).unwrap();
+        let get = request(Method::GET, &[]);
+
+        let body = Bytes::from_static(b"cached body");
+        let meta = Meta { body_bytes: body.len() as u64, ..meta(200, &[("cache-control", "max-age=60")]) };
+        let base = dir.join(meta.id());
+        tier.insert(Arc::new(meta), body.clone());
+
+        // Every lookup either misses or finds the complete entry
+        let found = tokio::time::timeout(Duration::from_secs(5), async {
+            loop {
+                if let Some(found) = tier.find(&get).await {
+                    return found;
+                }
+                tokio::time::sleep(Duration::from_millis(1)).await;
+            }
+        }).await.unwrap();
+        assert_eq!(found.body, body);
+        assert!(base.with_extension("meta").is_file());
+
+        // Interrupted writes are cleared away, finished ones survive a restart
+        fs::write(base.with_extension("body.7.tmp"), b"partial").unwrap();
+        drop(tier);
+        let reopened = DiskTier::open(dir.to_str().unwrap(), 1 << 20).unwrap();
+        assert_eq!(reopened.find(&get).await.unwrap().body, body);
+        assert!(!base.with_extension("body.7.tmp").exists());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn normalises_vary_names() {
+        let names = vary_names(&headers(&[("vary", "Accept-Encoding, accept-language"), ("vary", "accept-encoding,, User-Agent")]));
+
+        assert_eq!(names, ["accept-encoding", "accept-language", "user-agent"]);
+    }
+
+    #[test]
+    fn refuses_responses_a_shared_cache_must_not_store() {
+        let cache = memory_cache();
+        let get = request(Method::GET, &[]);
+
+        assert!(cache.storable(&get, &response(&[("cache-control", "max-age=60")])).is_some());
+        assert!(cache.storable(&get, &response(&[("cache-control", "private, max-age=60")])).is_none());
+        assert!(cache.storable(&get, &response(&[("cache-control", "max-age=60"), ("set-cookie", "a=b")])).is_none());
+        assert!(cache.storable(&get, &response(&[("cache-control", "max-age=60"), ("vary", "*")])).is_none());
+        assert!(cache.storable(&get, &response(&[("cache-control", "max-age=60"), ("content-length", "100000")])).is_none());
+        assert!(cache.storable(&get, &response(&[])).is_none());
+        assert!(cache.storable(&get, &response(&[("etag", "\"v1\"")])).is_some());
+
+        let mut redirect = response(&[("location", "/next")]);
+        *redirect.status_mut() = StatusCode::FOUND;
+        assert!(cache.storable(&get, &redirect).is_none());
+        redirect.headers_mut().insert(header::CACHE_CONTROL, HeaderValue::from_static("max-age=60"));
+        assert!(cache.storable(&get, &redirect).is_some());
+    }
+
+    #[tokio::test]
+    async fn serves_only_the_matching_vary_variant() {
+        let cache = memory_cache();
+        let gzip = request(Method::GET, &[("accept-encoding", "gzip")]);
+        let meta = cache.storable(&gzip, &response(&[("cache-control", "max-age=60"), ("vary", "Accept-Encoding")])).unwrap();
+        cache.insert(meta, Bytes::from_static(b"compressed"));
+
+        assert!(matches!(cache.lookup(&gzip).await, Lookup::Hit(_)));
+        assert!(matches!(cache.lookup(&request(Method::GET, &[("accept-encoding", "br")])).await, Lookup::Miss));
+        assert!(matches!(cache.lookup(&request(Method::GET, &[])).await, Lookup::Miss));
+    }
+
+    #[tokio::test]
+    async fn revalidates_stale_or_refused_entries() {
+        let cache = memory_cache();
+        let get = request(Method::GET, &[]);
+        let meta = cache.storable(&get, &response(&[("cache-control", "max-age=60"), ("etag", "\"v1\"")])).unwrap();
+        cache.insert(meta, Bytes::from_static(b"body"));
+
+        assert!(matches!(cache.lookup(&get).await, Lookup::Hit(_)));
+        assert!(matches!(cache.lookup(&request(Method::GET, &[("cache-control", "no-cache")])).await, Lookup::Revalidate(_)));
+        assert!(matches!(cache.lookup(&request(Method::GET, &[("cache-control", "min-fresh=120")])).await, Lookup::Revalidate(_)));
+        assert!(matches!(cache.lookup(&request(Method::GET, &[("authorization", "Basic eDp5")])).await, Lookup::Bypass));
+
+        let Lookup::Hit(not_modified) = cache.lookup(&request(Method::GET, &[("if-none-match", "W/\"v1\"")])).await else {
+            panic!("expected a hit");
+        };
+        assert_eq!(not_modified.status(), StatusCode::NOT_MODIFIED);
+    }
+}
@@ -0,0 +1,90 @@
+use crate::config::Config;
+use crate::policy::{self, Access, Verdict};
+
+const USAGE: &str = "Usage: proxy policy test --client <ip> --target <host:port> [--user <name>] [--access connect|forward|udp] [--json]";
+
+// Subcommands run in place of the proxy server. Returns the exit code, or None to start
+// the server as usual when no subcommand was given.
+//
+//   proxy policy test --client 10.0.0.5 --target www.example.com:443 [--user alice] [--access udp] [--json]
+//
+// The exit code of `policy test` is 0 when the connection would be allowed, 1 when it
+// would be refused and 2 for invalid arguments.
+pub fn run(args: &[String]) -> Option<i32> {
+    match args.iter().map(String::as_str).collect::<Vec<_>>().as_slice() {
+        [] => None,
+        ["policy", "test", options @ ..] => Some(policy_test(options)),
+        ["policy", ..] => {
+            eprintln!("{}", USAGE);
+            Some(2)
+        }
+        _ => None,
+    }
+}
+
+fn policy_test(options: &[&str]) -> i32 {
+    let mut client = None;
+    let mut target = None;
+    let mut user = None;
+    let mut access = None;
+    let mut json = false;
+
+    let mut options = options.iter();
+    while let Some(option) = options.next() {
+        match *option {
+            "--json" => json = true,
+            "--client" | "--target" | "--user" | "--access" => {
+                let Some(value) = options.next() else {
+                    eprintln!("Missing value for {}\n{}", option, USAGE);
+                    return 2;
+                };
+                let slot = match *option {
+                    "--client" => &mut client,
+                    "--target" => &mut target,
+                    "--access" => &mut access,
+                    _ => &mut user,
+                };
+                *slot = Some(value.to_string());
+            }
+            _ => {
+                eprintln!("Unknown option {}\n{}", option, USAGE);
+                return 2;
+            }
+        }
+    }
+
+    let (Some(client), Some(target)) = (client, target) else {
+        eprintln!("{}", USAGE);
+        return 2;
+    };
+    let access = match access.as_deref().map(str::parse::<Access>) {
+        None => Access::Connect,
+        Some(Ok(access)) => access,
+        Some(Err(())) => {
+            eprintln!("Invalid access, expected connect, forward or udp\n{}", USAGE);
+            return 2;
+        }
+    };
+    let client = match policy::evaluation_client(&client, &target, user) {
+        Ok(client) => client,
+        Err(error) => {
+            eprintln!("{}", error);
+            return 2;
+        }
+    };
+
+    let evaluation = policy::evaluate(&client, &target, access, &Config::from_env());
+
+    if json {
+        println!("{}", serde_json::to_string_pretty(&evaluation).unwrap_or_default());
+    } else {
+        println!("{} → {}: {}", evaluation.client, evaluation.target, evaluation.verdict.as_str());
+        println!("Rule: {}", evaluation.rule.as_deref().unwrap_or("none"));
+        println!("Trace:");
+        for step in &evaluation.trace {
+            println!("  {:<5} {}", step.effect, step.rule);
+        }
+    }
+
+    if evaluation.verdict == Verdict::Allowed { 0 } else { 1 }
+}
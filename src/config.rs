@@ -0,0 +1,176 @@
+use std::env;
+use std::net::IpAddr;
+
+use crate::admin::AdminAddr;
+use crate::icap::IcapFailure;
+use crate::ports::PortSet;
+use crate::protocol::ProtocolSet;
+use crate::sni::SniMismatchAction;
+
+const DEFAULT_PROXY_PORT: u16 = 8080;
+const DEFAULT_TLS_PORT: u16 = 8443;
+const DEFAULT_ADMIN_ADDR: &str = "127.0.0.1:9090";
+const DEFAULT_DNS_CACHE_SIZE: usize = 1024;
+const DEFAULT_ICAP_PREVIEW_BYTES: usize = 1024;
+const DEFAULT_CACHE_DISK_BYTES: u64 = 1024 * 1024 * 1024;
+const DEFAULT_CACHE_MAX_OBJECT_BYTES: u64 = 64 * 1024 * 1024;
+
+// Runtime configuration read from environment variables
+#[derive(Clone, Debug)]
+pub struct Config {
+    pub proxy_port: u16,
+    pub tls_port: u16,
+    pub admin_addr: AdminAddr,
+    pub h3_port: Option<u16>,
+    pub tls_cert_file: Option<String>,
+    pub tls_key_file: Option<String>,
+    pub tls_client_ca_file: Option<String>,
+    pub tls_crl_file: Option<String>,
+    pub tls_client_identity: ClientIdentitySource,
+    pub dns_servers: Option<String>,
+    pub dns_cache_size: usize,
+    pub preferred_ip_family: IpFamily,
+    pub connect_ports: PortSet,
+    pub sni_inspection: bool,
+    pub sni_mismatch: SniMismatchAction,
+    pub mitm_ca_cert_file: Option<String>,
+    pub mitm_ca_key_file: Option<String>,
+    pub mitm_upstream_ca_file: Option<String>,
+    pub tunnel_protocols: Option<ProtocolSet>,
+    pub icap_reqmod_url: Option<String>,
+    pub icap_respmod_url: Option<String>,
+    pub icap_preview_bytes: usize,
+    pub icap_failure: IcapFailure,
+    pub cache_memory_bytes: Option<u64>,
+    pub cache_dir: Option<String>,
+    pub cache_disk_bytes: u64,
+    pub cache_max_object_bytes: u64,
+    pub pac_proxy_addr: Option<String>,
+    pub trusted_proxies: Vec<String>, // Peers whose X-Real-IP and X-Forwarded-For are believed
+}
+
+// Which part of a client certificate names the user
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ClientIdentitySource {
+    CommonName,
+    SubjectAltName,
+}
+
+impl std::str::FromStr for ClientIdentitySource {
+    type Err = ();
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value.to_ascii_lowercase().as_str() {
+            "cn" => Ok(ClientIdentitySource::CommonName),
+            "san" => Ok(ClientIdentitySource::SubjectAltName),
+            _ => Err(()),
+        }
+    }
+}
+
+// Address family tried first when a name resolves to both
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum IpFamily {
+    V4,
+    V6,
+}
+
+impl std::str::FromStr for IpFamily {
+    type Err = ();
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value.to_ascii_lowercase().as_str() {
+            "ipv4" | "4" => Ok(IpFamily::V4),
+            "ipv6" | "6" => Ok(IpFamily::V6),
+            _ => Err(()),
+        }
+    }
+}
+
+impl Config {
+    pub fn from_env() -> Self {
+        Config::from_vars(|name| env::var(name).ok())
+    }
+
+    // The same settings from another source of variables, such as fixed values in tests
+    pub fn from_vars(lookup: impl Fn(&str) -> Option<String>) -> Self {
+        let vars = Vars(&lookup);
+        Config {
+            proxy_port: vars.parse("PROXY_PORT").unwrap_or(DEFAULT_PROXY_PORT),
+            tls_port: vars.parse("TLS_PORT").unwrap_or(DEFAULT_TLS_PORT),
+            admin_addr: vars.parse("ADMIN_ADDR").unwrap_or_else(|| DEFAULT_ADMIN_ADDR.parse().unwrap()),
+            h3_port: vars.parse("H3_PORT"),
+            tls_cert_file: vars.string("TLS_CERT_FILE"),
+            tls_key_file: vars.string("TLS_KEY_FILE"),
+            tls_client_ca_file: vars.string("TLS_CLIENT_CA_FILE"),
+            tls_crl_file: vars.string("TLS_CRL_FILE"),
+            tls_client_identity: vars.parse("TLS_CLIENT_IDENTITY").unwrap_or(ClientIdentitySource::CommonName),
+            dns_servers: vars.string("DNS_SERVERS"),
+            dns_cache_size: vars.parse("DNS_CACHE_SIZE").unwrap_or(DEFAULT_DNS_CACHE_SIZE),
+            preferred_ip_family: vars.parse("PREFERRED_IP_FAMILY").unwrap_or(IpFamily::V6),
+            connect_ports: vars.parse("CONNECT_PORTS").unwrap_or_default(),
+            sni_inspection: vars.parse("SNI_INSPECTION").unwrap_or(false),
+            sni_mismatch: vars.parse("SNI_MISMATCH").unwrap_or(SniMismatchAction::Log),
+            mitm_ca_cert_file: vars.string("MITM_CA_CERT_FILE"),
+            mitm_ca_key_file: vars.string("MITM_CA_KEY_FILE"),
+            mitm_upstream_ca_file: vars.string("MITM_UPSTREAM_CA_FILE"),
+            tunnel_protocols: vars.parse("TUNNEL_PROTOCOLS"),
+            icap_reqmod_url: vars.string("ICAP_REQMOD_URL"),
+            icap_respmod_url: vars.string("ICAP_RESPMOD_URL"),
+            icap_preview_bytes: vars.parse("ICAP_PREVIEW_BYTES").unwrap_or(DEFAULT_ICAP_PREVIEW_BYTES),
+            icap_failure: vars.parse("ICAP_FAILURE").unwrap_or(IcapFailure::Closed),
+            cache_memory_bytes: vars.parse("CACHE_MEMORY_BYTES"),
+            cache_dir: vars.string("CACHE_DIR"),
+            cache_disk_bytes: vars.parse("CACHE_DISK_BYTES").unwrap_or(DEFAULT_CACHE_DISK_BYTES),
+            cache_max_object_bytes: vars.parse("CACHE_MAX_OBJECT_BYTES").unwrap_or(DEFAULT_CACHE_MAX_OBJECT_BYTES),
+            pac_proxy_addr: vars.string("PAC_PROXY_ADDR"),
+            trusted_proxies: vars.ip_list("TRUSTED_PROXIES"),
+        }
+    }
+
+    // The TLS listener is only started when both a certificate and a key are configured
+    pub fn tls_enabled(&self) -> bool {
+        self.tls_cert_file.is_some() && self.tls_key_file.is_some()
+    }
+}
+
+// Where settings are read from, normally the process environment
+struct Vars<'a>(&'a dyn Fn(&str) -> Option<String>);
+
+impl Vars<'_> {
+    fn string(&self, name: &str) -> Option<String> {
+        (self.0)(name).filter(|value| !value.trim().is_empty())
+    }
+
+    fn parse<T: std::str::FromStr>(&self, name: &str) -> Option<T> {
+        let value = self.string(name)?;
+        match value.trim().parse() {
+            Ok(parsed) => Some(parsed),
+            Err(_) => {
+                tracing::warn!("⚠️ Ignoring invalid value for {}: {:?}", name, value);
+                None
+            }
+        }
+    }
+
+    // Comma-separated IP addresses and CIDR ranges
+    fn ip_list(&self, name: &str) -> Vec<String> {
+        let Some(value) = self.string(name) else {
+            return Vec::new();
+        };
+
+        value.split(',')
+            .map(str::trim)
+            .filter(|entry| !entry.is_empty())
+            .filter(|entry| {
+                let valid = entry.split('/').next().is_some_and(|ip| ip.parse::<IpAddr>().is_ok())
+                    && entry.split_once('/').is_none_or(|(_, prefix_len)| prefix_len.parse::<u8>().is_ok());
+                if !valid {
+                    tracing::warn!("⚠️ Ignoring invalid entry in {}: {:?}", name, entry);
+                }
+                valid
+            })
+            .map(str::to_string)
+            .collect()
+    }
+}
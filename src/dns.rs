@@ -0,0 +1,154 @@
+use hickory_resolver::config::{LookupIpStrategy, NameServerConfig, ResolverConfig};
+use hickory_resolver::name_server::TokioConnectionProvider;
+use hickory_resolver::proto::xfer::Protocol;
+use hickory_resolver::TokioResolver;
+use std::fs;
+use std::net::{IpAddr, SocketAddr};
+use std::time::Instant;
+
+use crate::config::{Config, IpFamily};
+use crate::routing::split_host_port;
+
+// Addresses for a `host:port` target and how long the lookup took
+pub struct Resolution {
+    pub addrs: Vec<SocketAddr>,
+    pub duration_ms: Option<u64>, // None when the host was already an IP address
+}
+
+// Async resolver with a TTL-respecting cache, shared by every listener
+pub struct Resolver {
+    inner: TokioResolver,
+    preferred_family: IpFamily,
+}
+
+impl Resolver {
+    // Use the nameservers from DNS_SERVERS, or the system configuration when unset
+    pub fn from_config(config: &Config) -> Result<Self, Box<dyn std::error::Error + Send + Sync>> {
+        let mut builder = match &config.dns_servers {
+            Some(servers) => {
+                let mut resolver_config = ResolverConfig::new();
+                for server in servers.split(',').map(str::trim).filter(|server| !server.is_empty()) {
+                    let name_server = parse_name_server(server)
+                        .ok_or_else(|| format!("invalid DNS server {:?}", server))?;
+                    resolver_config.add_name_server(name_server);
+                }
+                TokioResolver::builder_with_config(resolver_config, TokioConnectionProvider::default())
+            }
+            None => TokioResolver::builder_tokio()?,
+        };
+
+        let options = builder.options_mut();
+        options.cache_size = config.dns_cache_size;
+        options.ip_strategy = LookupIpStrategy::Ipv4AndIpv6;
+
+        Ok(Resolver {
+            inner: builder.build(),
+            preferred_family: config.preferred_ip_family,
+        })
+    }
+
+    pub async fn resolve(&self, host_addr: &str) -> std::io::Result<Resolution> {
+        let (host, port) = split_host_port(host_addr);
+        let port: u16 = port.and_then(|port| port.parse().ok())
+            .ok_or_else(|| std::io::Error::new(std::io::ErrorKind::InvalidInput, "Target has no valid port"))?;
+        let host = host.trim_start_matches('[').trim_end_matches(']');
+
+        if let Ok(ip) = host.parse::<IpAddr>() {
+            return Ok(Resolution { addrs: vec![SocketAddr::new(ip, port)], duration_ms: None });
+        }
+
+        let started = Instant::now();
+        let addrs = match resolve_override(host, port) {
+            Some(addrs) => addrs,
+            None => self.inner.lookup_ip(host).await
+                .map_err(|e| std::io::Error::new(std::io::ErrorKind::NotFound, e))?
+                .iter()
+                .map(|ip| SocketAddr::new(ip, port))
+                .collect(),
+        };
+
+        if addrs.is_empty() {
+            return Err(std::io::Error::new(std::io::ErrorKind::NotFound, "No address resolved"));
+        }
+
+        Ok(Resolution {
+            addrs: interleave_families(addrs, self.preferred_family),
+            duration_ms: Some(started.elapsed().as_millis() as u64),
+        })
+    }
+}
+
+// Order addresses for connection racing (RFC 8305 section 4): alternate between
+// families, starting with the preferred one, keeping the resolver's order within each
+fn interleave_families(addrs: Vec<SocketAddr>, preferred: IpFamily) -> Vec<SocketAddr> {
+    let (v6, v4): (Vec<SocketAddr>, Vec<SocketAddr>) = addrs.into_iter().partition(SocketAddr::is_ipv6);
+    let (first, second) = match preferred {
+        IpFamily::V6 => (v6, v4),
+        IpFamily::V4 => (v4, v6),
+    };
+
+    let mut ordered = Vec::with_capacity(first.len() + second.len());
+    let (mut first, mut second) = (first.into_iter(), second.into_iter());
+    loop {
+        match (first.next(), second.next()) {
+            (None, None) => break,
+            (a, b) => ordered.extend(a.into_iter().chain(b)),
+        }
+    }
+    ordered
+}
+
+// Nameserver entries: `1.1.1.1`, `udp://1.1.1.1:53`, `tcp://1.1.1.1`,
+// `tls://1.1.1.1#cloudflare-dns.com` or `https://1.1.1.1#cloudflare-dns.com`
+fn parse_name_server(server: &str) -> Option<NameServerConfig> {
+    let (scheme, rest) = server.split_once("://").unwrap_or(("udp", server));
+    let (addr, tls_name) = match rest.split_once('#') {
+        Some((addr, name)) => (addr, Some(name.to_string())),
+        None => (rest, None),
+    };
+
+    let (protocol, default_port) = match scheme.to_ascii_lowercase().as_str() {
+        "udp" => (Protocol::Udp, 53),
+        "tcp" => (Protocol::Tcp, 53),
+        "tls" => (Protocol::Tls, 853),
+        "https" => (Protocol::Https, 443),
+        _ => return None,
+    };
+    // Encrypted transports verify the server certificate against this name
+    if matches!(protocol, Protocol::Tls | Protocol::Https) && tls_name.is_none() {
+        return None;
+    }
+
+    let socket_addr = match addr.parse::<SocketAddr>() {
+        Ok(socket_addr) => socket_addr,
+        Err(_) => SocketAddr::new(addr.trim_start_matches('[').trim_end_matches(']').parse().ok()?, default_port),
+    };
+
+    let mut name_server = NameServerConfig::new(socket_addr, protocol);
+    name_server.tls_dns_name = tls_name;
+    Some(name_server)
+}
+
+// Static overrides from `resolve.txt`, in curl `--resolve` form:
+// `host:port:address[,address...]`, where the port may be `*`
+fn resolve_override(host: &str, port: u16) -> Option<Vec<SocketAddr>> {
+    let contents = fs::read_to_string("./resolve.txt").ok()?;
+
+    contents.lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .find_map(|line| {
+            let mut fields = line.splitn(3, ':');
+            let (entry_host, entry_port, addrs) = (fields.next()?, fields.next()?, fields.next()?);
+
+            if !entry_host.eq_ignore_ascii_case(host) || (entry_port != "*" && entry_port.parse() != Ok(port)) {
+                return None;
+            }
+
+            let addrs = addrs.split(',')
+                .filter_map(|addr| addr.trim().trim_start_matches('[').trim_end_matches(']').parse::<IpAddr>().ok())
+                .map(|ip| SocketAddr::new(ip, port))
+                .collect();
+            Some(addrs)
+        })
+}
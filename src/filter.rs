@@ -0,0 +1,263 @@
+use axum::body::Body;
+use axum::http::{header, HeaderMap, Response};
+use bytes::Bytes;
+use http_body::{Body as _, Frame};
+use regex::bytes::Regex;
+use std::collections::VecDeque;
+use std::fs;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+
+pub type BoxError = Box<dyn std::error::Error + Send + Sync>;
+
+const LOOKAHEAD_BYTES: usize = 64 * 1024; // Body held back so an early trip can still return a block page
+const KEYWORD_OVERLAP_BYTES: usize = 1024; // Carried between chunks so keywords split across them still match
+
+// Response filters from `content_filters.txt`, one per line:
+//
+//   block_type <content-type prefix>   e.g. application/x-msdownload or video/
+//   max_size <bytes>
+//   keyword <regex>                    matched against text bodies, e.g. (?i)confidential
+#[derive(Clone, Debug, Default)]
+pub struct ContentFilters {
+    blocked_types: Vec<String>,
+    max_size: Option<u64>,
+    keywords: Vec<Regex>,
+}
+
+impl ContentFilters {
+    pub fn is_empty(&self) -> bool {
+        self.blocked_types.is_empty() && self.max_size.is_none() && self.keywords.is_empty()
+    }
+
+    // Keyword filters need bodies the proxy can read, so compression is not requested
+    pub fn scans_bodies(&self) -> bool {
+        !self.keywords.is_empty()
+    }
+
+    fn check_headers(&self, headers: &HeaderMap) -> Result<(), String> {
+        let content_type = content_type(headers);
+        if let Some(blocked) = self.blocked_types.iter().find(|blocked| content_type.starts_with(blocked.as_str())) {
+            return Err(format!("content type {} is blocked ({})", content_type, blocked));
+        }
+
+        let content_length = headers.get(header::CONTENT_LENGTH)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.parse::<u64>().ok());
+        if let (Some(max_size), Some(length)) = (self.max_size, content_length) {
+            if length > max_size {
+                return Err(format!("response of {} bytes exceeds the {} byte limit", length, max_size));
+            }
+        }
+
+        Ok(())
+    }
+}
+
+pub fn read_content_filters() -> ContentFilters {
+    let contents = fs::read_to_string("./content_filters.txt").unwrap_or_default();
+    let mut filters = ContentFilters::default();
+
+    for line in contents.lines().map(str::trim).filter(|line| !line.is_empty() && !line.starts_with('#')) {
+        let (directive, value) = line.split_once(char::is_whitespace).unwrap_or((line, ""));
+        let value = value.trim();
+
+        match directive {
+            "block_type" => filters.blocked_types.push(value.to_ascii_lowercase()),
+            "max_size" => match value.parse() {
+                Ok(max_size) => filters.max_size = Some(max_size),
+                Err(_) => tracing::warn!("⚠️ Ignoring invalid line in content_filters.txt: {}", line),
+            },
+            "keyword" => match Regex::new(value) {
+                Ok(keyword) => filters.keywords.push(keyword),
+                Err(e) => tracing::warn!("⚠️ Ignoring invalid keyword in content_filters.txt: {} ({})", value, e),
+            },
+            _ => tracing::warn!("⚠️ Ignoring invalid line in content_filters.txt: {}", line),
+        }
+    }
+
+    filters
+}
+
+// A filter tripped; carries the reason shown on the block page
+#[derive(Debug)]
+struct Blocked(String);
+
+impl std::fmt::Display for Blocked {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "response blocked: {}", self.0)
+    }
+}
+
+impl std::error::Error for Blocked {}
+
+// Apply the filters to a response without buffering its body. Header checks and any
+// trip within the first LOOKAHEAD_BYTES return the reason, so the caller can answer
+// with a block page instead; a trip after that aborts the response mid-stream.
+pub async fn filter_response<B>(response: Response<B>, filters: ContentFilters, url: &str) -> Result<Response<Body>, String>
+where
+    B: http_body::Body<Data = Bytes> + Send + Unpin + 'static,
+    B::Error: Into<BoxError>,
+{
+    let (parts, body) = response.into_parts();
+    if let Err(reason) = filters.check_headers(&parts.headers) {
+        tracing::warn!("🚫 CONTENT BLOCKED: {} | {}", url, reason);
+        return Err(reason);
+    }
+
+    let mut body = FilteredBody {
+        inner: body,
+        scan_text: !filters.keywords.is_empty() && is_scannable(&parts.headers),
+        filters,
+        url: url.to_string(),
+        total: 0,
+        overlap: Vec::new(),
+    };
+
+    let mut buffered = VecDeque::new();
+    let mut buffered_bytes = 0;
+    let mut error = None;
+    let mut finished = false;
+
+    while buffered_bytes < LOOKAHEAD_BYTES {
+        match std::future::poll_fn(|cx| Pin::new(&mut body).poll_frame(cx)).await {
+            Some(Ok(frame)) => {
+                buffered_bytes += frame.data_ref().map_or(0, Bytes::len);
+                buffered.push_back(frame);
+            }
+            Some(Err(e)) => match e.downcast::<Blocked>() {
+                Ok(blocked) => return Err(blocked.0),
+                Err(e) => {
+                    error = Some(e);
+                    break;
+                }
+            },
+            None => {
+                finished = true;
+                break;
+            }
+        }
+    }
+
+    let body = Prefetched {
+        buffered,
+        error,
+        rest: (!finished).then_some(body),
+    };
+
+    Ok(Response::from_parts(parts, Body::new(body)))
+}
+
+fn content_type(headers: &HeaderMap) -> String {
+    headers.get(header::CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok())
+        .map(|value| value.split(';').next().unwrap_or_default().trim().to_ascii_lowercase())
+        .unwrap_or_default()
+}
+
+// Keywords are only matched against uncompressed text
+fn is_scannable(headers: &HeaderMap) -> bool {
+    let encoded = headers.get(header::CONTENT_ENCODING)
+        .and_then(|value| value.to_str().ok())
+        .is_some_and(|encoding| !encoding.eq_ignore_ascii_case("identity"));
+    let content_type = content_type(headers);
+    let textual = content_type.starts_with("text/")
+        || ["json", "xml", "javascript"].iter().any(|kind| content_type.contains(kind));
+
+    textual && !encoded
+}
+
+// Counts and scans each chunk of the body as it passes through
+struct FilteredBody<B> {
+    inner: B,
+    filters: ContentFilters,
+    scan_text: bool,
+    url: String,
+    total: u64,
+    overlap: Vec<u8>,
+}
+
+impl<B> FilteredBody<B> {
+    fn inspect(&mut self, data: &[u8]) -> Result<(), String> {
+        self.total += data.len() as u64;
+        if let Some(max_size) = self.filters.max_size {
+            if self.total > max_size {
+                return Err(format!("response exceeds the {} byte limit", max_size));
+            }
+        }
+
+        if self.scan_text {
+            let mut window = std::mem::take(&mut self.overlap);
+            window.extend_from_slice(data);
+            if let Some(keyword) = self.filters.keywords.iter().find(|keyword| keyword.is_match(&window)) {
+                return Err(format!("body matches the keyword filter {}", keyword.as_str()));
+            }
+            self.overlap = window[window.len().saturating_sub(KEYWORD_OVERLAP_BYTES)..].to_vec();
+        }
+
+        Ok(())
+    }
+}
+
+impl<B> http_body::Body for FilteredBody<B>
+where
+    B: http_body::Body<Data = Bytes> + Unpin,
+    B::Error: Into<BoxError>,
+{
+    type Data = Bytes;
+    type Error = BoxError;
+
+    fn poll_frame(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Result<Frame<Bytes>, BoxError>>> {
+        let frame = match Pin::new(&mut self.inner).poll_frame(cx) {
+            Poll::Ready(Some(Ok(frame))) => frame,
+            Poll::Ready(Some(Err(e))) => return Poll::Ready(Some(Err(e.into()))),
+            Poll::Ready(None) => return Poll::Ready(None),
+            Poll::Pending => return Poll::Pending,
+        };
+
+        if let Some(data) = frame.data_ref() {
+            if let Err(reason) = self.inspect(data) {
+                tracing::warn!("🚫 CONTENT BLOCKED: {} | {}", self.url, reason);
+                return Poll::Ready(Some(Err(Box::new(Blocked(reason)))));
+            }
+        }
+
+        Poll::Ready(Some(Ok(frame)))
+    }
+}
+
+// Frames already read from a body, followed by the rest of it
+pub struct Prefetched<R> {
+    buffered: VecDeque<Frame<Bytes>>,
+    error: Option<BoxError>,
+    rest: Option<R>,
+}
+
+impl<R> Prefetched<R> {
+    pub fn new(buffered: VecDeque<Frame<Bytes>>, rest: R) -> Self {
+        Prefetched { buffered, error: None, rest: Some(rest) }
+    }
+}
+
+impl<R> http_body::Body for Prefetched<R>
+where
+    R: http_body::Body<Data = Bytes> + Unpin,
+    R::Error: Into<BoxError>,
+{
+    type Data = Bytes;
+    type Error = BoxError;
+
+    fn poll_frame(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Result<Frame<Bytes>, BoxError>>> {
+        if let Some(frame) = self.buffered.pop_front() {
+            return Poll::Ready(Some(Ok(frame)));
+        }
+        if let Some(error) = self.error.take() {
+            return Poll::Ready(Some(Err(error)));
+        }
+
+        match self.rest.as_mut() {
+            Some(rest) => Pin::new(rest).poll_frame(cx).map_err(Into::into),
+            None => Poll::Ready(None),
+        }
+    }
+}
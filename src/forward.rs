@@ -0,0 +1,410 @@
+use axum::body::Body;
+use axum::http::{header, uri::Scheme, HeaderMap, HeaderValue, Request, Response, StatusCode, Uri, Version};
+use bytes::Bytes;
+use chrono::Utc;
+use http_body::Frame;
+use hyper::body::Incoming;
+use hyper::client::conn::http1::SendRequest;
+use hyper_util::rt::TokioIo;
+use std::fs;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use tokio::sync::Mutex;
+
+use crate::cache::{self, CacheRequest, Lookup};
+use crate::filter::{self, BoxError, ContentFilters};
+use crate::icap::{self, Scanned};
+use crate::pages::{self, Page, PageDetails};
+use crate::policy::{self, Access, Verdict};
+use crate::ports::DEFAULT_HTTP_PORT;
+use crate::routing::{destination_matches, select_route, Route};
+use crate::{
+    check_policy, dial, mark_connection_failed, record_allowed, record_blocked, record_tunnel_result, release_upstream,
+    AppState, ClientIdentity,
+};
+
+const MAX_RECORDED_URLS: usize = 100; // URLs kept on one connection record
+
+// Where a forwarded request goes
+pub enum Upstream {
+    // The verified TLS connection of an intercepted tunnel, or why it could not be made
+    Intercepted(Result<Arc<Mutex<SendRequest<Body>>>, String>),
+    // A new connection to the target, through the route picked by the routing rules
+    Dial,
+}
+
+// A plain HTTP request sent to the proxy in absolute form, e.g. `GET http://host/path`
+pub fn is_forward_request<B>(req: &Request<B>) -> bool {
+    req.version() < Version::HTTP_2
+        && req.uri().scheme() == Some(&Scheme::HTTP)
+        && req.uri().authority().is_some()
+}
+
+// Forward a plain HTTP request under the same policy as CONNECT, with port 80 allowed as
+// well. Each request gets its own connection record, completed once the response body
+// has been passed on.
+pub async fn forward_http(
+    req: Request<Body>,
+    app_state: AppState,
+    client: ClientIdentity,
+) -> Result<Response<Body>, hyper::Error> {
+    let Some(authority) = req.uri().authority().cloned() else {
+        return Ok(error_response(StatusCode::BAD_REQUEST, "Forwarded requests need an absolute URL".to_string()));
+    };
+    let host_addr = match authority.port_u16() {
+        Some(_) => authority.to_string(),
+        None => format!("{}:{}", authority.host(), DEFAULT_HTTP_PORT),
+    };
+    let url = req.uri().to_string();
+    let user_agent = req.headers().get(header::USER_AGENT)
+        .and_then(|value| value.to_str().ok())
+        .map(|value| value.to_string());
+
+    let mut evaluation = check_policy(&app_state, &client, &host_addr, Access::Forward);
+    if evaluation.verdict == Verdict::Allowed {
+        if let Some(rule) = resolved_local_destination(&app_state, &client, &host_addr).await {
+            evaluation.verdict = Verdict::DestinationDenied;
+            evaluation.rule = Some(rule);
+        }
+    }
+
+    if evaluation.verdict != Verdict::Allowed {
+        let conn_key = record_blocked(&app_state, &client, &host_addr, user_agent, "tcp", evaluation.verdict.as_str()).await;
+        let details = PageDetails {
+            url: Some(url),
+            rule: evaluation.rule,
+            reason: evaluation.verdict.reason().map(str::to_string),
+            ..PageDetails::for_connection(&client, &host_addr, &conn_key)
+        };
+        return Ok(pages::render(Page::Blocked, details, req.headers()));
+    }
+
+    let conn_key = record_allowed(&app_state, &client, &host_addr, user_agent, "tcp").await;
+    let start_time = Utc::now();
+
+    let (mut parts, body) = req.into_parts();
+    strip_hop_by_hop(&mut parts.headers);
+    if !parts.headers.contains_key(header::HOST) {
+        if let Ok(host) = HeaderValue::from_str(authority.as_str()) {
+            parts.headers.insert(header::HOST, host);
+        }
+    }
+    // The target expects the origin form, `GET /path`
+    parts.uri = parts.uri.path_and_query().cloned().map(Uri::from).unwrap_or_else(|| Uri::from_static("/"));
+
+    let sent = Arc::new(AtomicU64::new(0));
+    let received = Arc::new(AtomicU64::new(0));
+    let req = Request::from_parts(parts, Body::new(Counted { inner: body, bytes: sent.clone(), _finish: None }));
+
+    let response = forward_request(req, &app_state, &conn_key, &client, &host_addr, &url, Upstream::Dial).await;
+
+    let (mut parts, body) = response.into_parts();
+    strip_hop_by_hop(&mut parts.headers);
+    let finish = Finish { app_state, conn_key, client, host_addr, start_time, sent, received: received.clone() };
+    let body = Counted { inner: body, bytes: received, _finish: Some(finish) };
+
+    Ok(Response::from_parts(parts, Body::new(body)))
+}
+
+// Names other than `localhost` are refused once they resolve to the proxy host, unless an
+// upstream proxy resolves them instead
+async fn resolved_local_destination(app_state: &AppState, client: &ClientIdentity, host_addr: &str) -> Option<String> {
+    if !matches!(select_route(client, host_addr), Route::Direct) {
+        return None;
+    }
+    // A name that does not resolve fails when the request is sent
+    let resolution = app_state.resolver.resolve(host_addr).await.ok()?;
+    resolution.addrs.into_iter().find_map(|addr| policy::local_destination(addr, &app_state.config))
+}
+
+// Serve one HTTP request, whether it arrived inside an intercepted tunnel or as plain
+// HTTP: the URL policy, ICAP scanning, the cache and content filters, in that order
+pub async fn forward_request(
+    req: Request<Body>,
+    app_state: &AppState,
+    conn_key: &str,
+    client: &ClientIdentity,
+    host_addr: &str,
+    url: &str,
+    upstream: Upstream,
+) -> Response<Body> {
+    let method = req.method().clone();
+    // Kept for block and error pages, which are negotiated on the client's original headers
+    let request_headers = req.headers().clone();
+    let details = PageDetails { url: Some(url.to_string()), ..PageDetails::for_connection(client, host_addr, conn_key) };
+
+    if let Some(mut conn) = app_state.monitoring_state.get_mut(conn_key) {
+        if conn.urls.len() < MAX_RECORDED_URLS {
+            conn.urls.push(format!("{} {}", method, url));
+        }
+    }
+
+    if let Some(rule) = url_blocked(host_addr, req.uri().path()) {
+        tracing::warn!("🚫 URL BLOCKED: {} requested {} {}", client, method, url);
+        return blocked_page(&details, &request_headers, rule, None);
+    }
+
+    let filters = filter::read_content_filters();
+    let mut req = req;
+    if filters.scans_bodies() {
+        req.headers_mut().remove(header::ACCEPT_ENCODING);
+    }
+
+    if let Some(icap) = &app_state.icap {
+        req = match icap.scan_request(app_state, conn_key, req, url).await {
+            Scanned::Allow(req) => req,
+            Scanned::Respond(response) => return response,
+            Scanned::Deny(reason) => return blocked_page(&details, &request_headers, "icap".to_string(), Some(reason)),
+        };
+    }
+
+    // Looked up after REQMOD, so a rewritten request selects the right variant
+    let cache_request = app_state.cache.as_ref().map(|_| CacheRequest::new(&req, url));
+    let mut revalidating = None;
+    if let (Some(cache), Some(cache_request)) = (&app_state.cache, &cache_request) {
+        match cache.lookup(cache_request).await {
+            Lookup::Hit(response) => {
+                tracing::info!("🔍 {} {} {} → {} (cached)", client, method, url, response.status().as_u16());
+                cache::record(app_state, conn_key, url, "hit");
+                return apply_filters(response, filters, url, &details, &request_headers).await;
+            }
+            Lookup::Revalidate(found) => {
+                found.add_validators(req.headers_mut());
+                revalidating = Some(found);
+            }
+            Lookup::Miss => cache::record(app_state, conn_key, url, "miss"),
+            Lookup::Bypass => cache::record(app_state, conn_key, url, "bypass"),
+        }
+    }
+
+    // RESPMOD sends the request headers along with the response for context
+    let req_head = match &app_state.icap {
+        Some(icap) if icap.scans_responses() => {
+            let (parts, body) = req.into_parts();
+            let req_head = icap::request_head(&parts, url);
+            req = Request::from_parts(parts, body);
+            req_head
+        }
+        _ => Vec::new(),
+    };
+
+    let response = match send(req, app_state, conn_key, client, host_addr, upstream).await {
+        Ok(response) => response,
+        Err(e) => {
+            tracing::error!("❌ Forwarded request failed: {} {} {} | Error: {}", client, method, url, e);
+            let details = PageDetails { reason: Some(e), ..details };
+            return pages::render(Page::UpstreamError, details, &request_headers);
+        }
+    };
+    tracing::info!("🔍 {} {} {} → {}", client, method, url, response.status().as_u16());
+    let mut response = response.map(Body::new);
+
+    if let (Some(cache), Some(cache_request)) = (&app_state.cache, &cache_request) {
+        cache.invalidate(cache_request, response.status());
+
+        if let Some(found) = revalidating {
+            if response.status() == StatusCode::NOT_MODIFIED {
+                cache::record(app_state, conn_key, url, "revalidated");
+                let response = cache.refresh(cache_request, found, response.headers());
+                return apply_filters(response, filters, url, &details, &request_headers).await;
+            }
+            cache::record(app_state, conn_key, url, "miss");
+        }
+    }
+
+    if let Some(icap) = &app_state.icap {
+        response = match icap.scan_response(app_state, conn_key, &req_head, response, url).await {
+            Scanned::Allow(response) => response,
+            // The scanner's own answer, such as its block page, is never stored
+            Scanned::Respond(response) => return apply_filters(response, filters, url, &details, &request_headers).await,
+            Scanned::Deny(reason) => return blocked_page(&details, &request_headers, "icap".to_string(), Some(reason)),
+        };
+    }
+
+    // Stored after RESPMOD, so hits are served as scanned
+    if let (Some(cache), Some(cache_request)) = (&app_state.cache, &cache_request) {
+        response = cache.store(cache_request, response);
+    }
+
+    apply_filters(response, filters, url, &details, &request_headers).await
+}
+
+async fn send(
+    req: Request<Body>,
+    app_state: &AppState,
+    conn_key: &str,
+    client: &ClientIdentity,
+    host_addr: &str,
+    upstream: Upstream,
+) -> Result<Response<Incoming>, String> {
+    match upstream {
+        Upstream::Intercepted(Ok(sender)) => {
+            let mut sender = sender.lock().await;
+            sender.ready().await.map_err(|e| format!("Upstream request failed: {}", e))?;
+            sender.send_request(req).await.map_err(|e| format!("Upstream request failed: {}", e))
+        }
+        Upstream::Intercepted(Err(e)) => Err(format!("Unable to reach {} securely: {}", host_addr, e)),
+        Upstream::Dial => {
+            let result = async {
+                let server = dial(app_state, conn_key, client, host_addr).await
+                    .map_err(|e| format!("Unable to reach {}: {}", host_addr, e))?;
+                let (mut sender, connection) = hyper::client::conn::http1::handshake(TokioIo::new(server)).await
+                    .map_err(|e| format!("Unable to reach {}: {}", host_addr, e))?;
+                tokio::spawn(async move {
+                    if let Err(e) = connection.await {
+                        tracing::debug!("Forwarding connection closed: {}", e);
+                    }
+                });
+                sender.send_request(req).await.map_err(|e| format!("Upstream request failed: {}", e))
+            }.await;
+
+            if result.is_err() {
+                mark_connection_failed(&app_state.monitoring_state, conn_key);
+            }
+            result
+        }
+    }
+}
+
+async fn apply_filters(
+    response: Response<Body>,
+    filters: ContentFilters,
+    url: &str,
+    details: &PageDetails,
+    request_headers: &HeaderMap,
+) -> Response<Body> {
+    if filters.is_empty() {
+        return response;
+    }
+
+    match filter::filter_response(response, filters, url).await {
+        Ok(response) => response,
+        Err(reason) => blocked_page(details, request_headers, "content_filters.txt".to_string(), Some(reason)),
+    }
+}
+
+// URL policy from `blocked_urls.txt`: one `host-pattern/path-prefix` per line,
+// e.g. `www.example.com/admin` or `*.example.com/downloads/`. Returns each entry's
+// line number, host pattern and path prefix.
+pub fn read_blocked_urls() -> Vec<(usize, String, String)> {
+    fs::read_to_string("./blocked_urls.txt")
+        .unwrap_or_default()
+        .lines()
+        .enumerate()
+        .map(|(index, line)| (index + 1, line.trim()))
+        .filter(|(_, line)| !line.is_empty() && !line.starts_with('#'))
+        .filter_map(|(line_number, line)| {
+            let (host_pattern, path_prefix) = line.split_at(line.find('/')?);
+            Some((line_number, host_pattern.to_string(), path_prefix.to_string()))
+        })
+        .collect()
+}
+
+// The `blocked_urls.txt:<line>` entry that blocks a request
+fn url_blocked(host_addr: &str, path: &str) -> Option<String> {
+    read_blocked_urls()
+        .into_iter()
+        .find(|(_, host_pattern, path_prefix)| {
+            destination_matches(host_pattern, host_addr) && path.starts_with(path_prefix.as_str())
+        })
+        .map(|(line_number, _, _)| format!("blocked_urls.txt:{}", line_number))
+}
+
+fn blocked_page(details: &PageDetails, request_headers: &HeaderMap, rule: String, reason: Option<String>) -> Response<Body> {
+    let details = PageDetails { rule: Some(rule), reason, ..details.clone() };
+    pages::render(Page::Blocked, details, request_headers)
+}
+
+fn error_response(status: StatusCode, message: String) -> Response<Body> {
+    Response::builder()
+        .status(status)
+        .body(Body::from(message))
+        .unwrap()
+}
+
+// Headers that only describe one connection, so are not passed on (RFC 9110 §7.6.1)
+pub fn is_hop_by_hop(name: &str) -> bool {
+    matches!(
+        name.to_ascii_lowercase().as_str(),
+        "connection" | "keep-alive" | "proxy-connection" | "proxy-authenticate" | "proxy-authorization"
+            | "te" | "trailer" | "transfer-encoding" | "upgrade"
+    )
+}
+
+fn strip_hop_by_hop(headers: &mut HeaderMap) {
+    let listed: Vec<String> = headers.get_all(header::CONNECTION)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .map(|name| name.trim().to_ascii_lowercase())
+        .collect();
+    for name in listed {
+        headers.remove(name.as_str());
+    }
+
+    let hop_by_hop: Vec<_> = headers.keys().filter(|name| is_hop_by_hop(name.as_str())).cloned().collect();
+    for name in hop_by_hop {
+        headers.remove(name);
+    }
+}
+
+// Counts the bytes of a forwarded body as they pass through
+struct Counted {
+    inner: Body,
+    bytes: Arc<AtomicU64>,
+    _finish: Option<Finish>, // Completes the connection record when the response is done with
+}
+
+impl http_body::Body for Counted {
+    type Data = Bytes;
+    type Error = BoxError;
+
+    fn poll_frame(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Result<Frame<Bytes>, BoxError>>> {
+        let result = Pin::new(&mut self.inner).poll_frame(cx);
+        if let Poll::Ready(Some(Ok(frame))) = &result {
+            self.bytes.fetch_add(frame.data_ref().map_or(0, Bytes::len) as u64, Ordering::Relaxed);
+        }
+        result.map_err(Into::into)
+    }
+
+    fn is_end_stream(&self) -> bool {
+        self.inner.is_end_stream()
+    }
+
+    fn size_hint(&self) -> http_body::SizeHint {
+        self.inner.size_hint()
+    }
+}
+
+struct Finish {
+    app_state: AppState,
+    conn_key: String,
+    client: ClientIdentity,
+    host_addr: String,
+    start_time: chrono::DateTime<Utc>,
+    sent: Arc<AtomicU64>,
+    received: Arc<AtomicU64>,
+}
+
+impl Drop for Finish {
+    fn drop(&mut self) {
+        let app_state = self.app_state.clone();
+        let conn_key = std::mem::take(&mut self.conn_key);
+        let client = self.client.clone();
+        let host_addr = std::mem::take(&mut self.host_addr);
+        let start_time = self.start_time;
+        let bytes = (self.sent.load(Ordering::Relaxed), self.received.load(Ordering::Relaxed));
+
+        tokio::spawn(async move {
+            // A failed request has already been recorded as such
+            let active = app_state.monitoring_state.get(&conn_key).is_some_and(|conn| conn.status == "active");
+            if !active {
+                release_upstream(&app_state, &conn_key);
+                return;
+            }
+            record_tunnel_result(&app_state, &conn_key, &client, &host_addr, start_time, Ok(Ok(bytes))).await;
+        });
+    }
+}
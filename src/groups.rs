@@ -0,0 +1,68 @@
+use std::fs;
+use std::net::IpAddr;
+
+use crate::ClientIdentity;
+
+// Client groups, one group per line: `group-name: member, member, ...`
+// Members are client IPs, CIDR ranges or authenticated user names.
+pub fn read_client_groups() -> Vec<(String, Vec<String>)> {
+    fs::read_to_string("./client_groups.txt")
+        .unwrap_or_default()
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .filter_map(|line| line.split_once(':'))
+        .map(|(group, members)| {
+            let members = members.split(',')
+                .map(|member| member.trim().to_string())
+                .filter(|member| !member.is_empty())
+                .collect();
+            (group.trim().to_string(), members)
+        })
+        .collect()
+}
+
+// Names of every group the client belongs to
+pub fn client_groups(client: &ClientIdentity) -> Vec<String> {
+    let client_ip = client.ip.parse::<IpAddr>().ok();
+
+    read_client_groups()
+        .into_iter()
+        .filter(|(_, members)| {
+            members.iter().any(|member| {
+                client.user.as_deref() == Some(member.as_str())
+                    || client_ip.is_some_and(|ip| ip_matches(member, ip))
+            })
+        })
+        .map(|(group, _)| group)
+        .collect()
+}
+
+// Whether `ip` equals an address or falls inside a CIDR range such as `10.0.0.0/8`
+pub fn ip_matches(pattern: &str, ip: IpAddr) -> bool {
+    let (network, prefix_len) = match pattern.split_once('/') {
+        Some((network, prefix_len)) => match prefix_len.parse::<u32>() {
+            Ok(prefix_len) => (network, prefix_len),
+            Err(_) => return false,
+        },
+        None => (pattern, u32::MAX),
+    };
+
+    let Ok(network) = network.parse::<IpAddr>() else {
+        return false;
+    };
+
+    match (network, ip.to_canonical()) {
+        (IpAddr::V4(network), IpAddr::V4(ip)) => {
+            let prefix_len = prefix_len.min(32);
+            let mask = u32::MAX.checked_shl(32 - prefix_len).unwrap_or(0);
+            u32::from(network) & mask == u32::from(ip) & mask
+        }
+        (IpAddr::V6(network), IpAddr::V6(ip)) => {
+            let prefix_len = prefix_len.min(128);
+            let mask = u128::MAX.checked_shl(128 - prefix_len).unwrap_or(0);
+            u128::from(network) & mask == u128::from(ip) & mask
+        }
+        _ => false,
+    }
+}
@@ -0,0 +1,296 @@
+use base64::Engine;
+use serde_json::json;
+use axum::extract::Request;
+use axum::http::{header, HeaderMap, Method, StatusCode};
+use axum::middleware::Next;
+use axum::Extension;
+use axum::response::{IntoResponse, Json, Response};
+use ring::digest::{digest, SHA256};
+use std::fs;
+
+// What an API caller may do: viewers can only read, admins can also change state
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ApiRole {
+    Viewer,
+    Admin,
+}
+
+impl ApiRole {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ApiRole::Viewer => "viewer",
+            ApiRole::Admin => "admin",
+        }
+    }
+}
+
+impl std::str::FromStr for ApiRole {
+    type Err = ();
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value.to_ascii_lowercase().as_str() {
+            "viewer" => Ok(ApiRole::Viewer),
+            "admin" => Ok(ApiRole::Admin),
+            _ => Err(()),
+        }
+    }
+}
+
+// The authenticated caller, added to the request for handlers that record who made a change
+#[derive(Clone, Debug)]
+pub struct ApiCaller {
+    pub name: String,
+}
+
+// Name recorded for a change: the authenticated caller, or "anonymous" with authentication off
+pub fn caller_name(caller: Option<Extension<ApiCaller>>) -> String {
+    caller.map(|Extension(caller)| caller.name).unwrap_or_else(|| "anonymous".to_string())
+}
+
+enum Secret {
+    Token(String),
+    Basic(String, String),
+}
+
+pub struct ApiCredential {
+    name: String,
+    secret: Secret,
+    role: ApiRole,
+    scope: Option<Vec<String>>, // API endpoints the credential is limited to, e.g. `connections`
+}
+
+// API credentials from `api_access.txt`:
+//
+//   token <token> <viewer|admin> [name=<label>] [scope=<endpoint>,...]
+//   basic <user>:<password> <viewer|admin> [scope=<endpoint>,...]
+//
+// Tokens and passwords may be given as `sha256:<hex>` instead of in the clear. A missing
+// or empty file leaves the API open, as before authentication was added.
+pub fn read_api_credentials() -> Vec<ApiCredential> {
+    parse_api_credentials(&fs::read_to_string("./api_access.txt").unwrap_or_default())
+}
+
+fn parse_api_credentials(contents: &str) -> Vec<ApiCredential> {
+    let mut credentials = Vec::new();
+
+    for (index, line) in contents.lines().enumerate() {
+        let fields: Vec<&str> = line.split_whitespace().collect();
+        let (secret, role, options) = match fields.as_slice() {
+            ["token", token, role, options @ ..] => (Secret::Token(token.to_string()), role, options),
+            ["basic", userinfo, role, options @ ..] => match userinfo.split_once(':') {
+                Some((user, pass)) => (Secret::Basic(user.to_string(), pass.to_string()), role, options),
+                None => {
+                    tracing::warn!("⚠️ Ignoring basic credential without a password on line {} of api_access.txt", index + 1);
+                    continue;
+                }
+            },
+            [] => continue,
+            [comment, ..] if comment.starts_with('#') => continue,
+            _ => {
+                tracing::warn!("⚠️ Ignoring invalid line {} in api_access.txt", index + 1);
+                continue;
+            }
+        };
+
+        let Ok(role) = role.parse::<ApiRole>() else {
+            tracing::warn!("⚠️ Ignoring credential with unknown role {:?} on line {} of api_access.txt", role, index + 1);
+            continue;
+        };
+
+        let name = options.iter()
+            .find_map(|option| option.strip_prefix("name="))
+            .map(|name| name.to_string())
+            .unwrap_or_else(|| match &secret {
+                Secret::Basic(user, _) => user.clone(),
+                Secret::Token(_) => format!("token:{}", index + 1),
+            });
+        let scope = options.iter()
+            .find_map(|option| option.strip_prefix("scope="))
+            .map(|scope| scope.split(',').map(|endpoint| endpoint.trim().to_string()).collect());
+
+        credentials.push(ApiCredential { name, secret, role, scope });
+    }
+
+    credentials
+}
+
+// Whether a presented secret matches a configured one. Both sides are compared as SHA-256
+// digests, so the comparison takes the same time however much of the secret is right.
+fn secret_matches(configured: &str, presented: &str) -> bool {
+    let presented = digest(&SHA256, presented.as_bytes());
+    match configured.strip_prefix("sha256:") {
+        Some(hex) => hex_digest(presented.as_ref()).eq_ignore_ascii_case(hex),
+        None => digest(&SHA256, configured.as_bytes()).as_ref() == presented.as_ref(),
+    }
+}
+
+fn hex_digest(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+fn authenticate(credentials: &[ApiCredential], authorization: &str) -> Option<usize> {
+    let (scheme, value) = authorization.trim().split_once(' ')?;
+    let value = value.trim();
+
+    if scheme.eq_ignore_ascii_case("bearer") {
+        return credentials.iter().position(|credential| {
+            matches!(&credential.secret, Secret::Token(token) if secret_matches(token, value))
+        });
+    }
+
+    if scheme.eq_ignore_ascii_case("basic") {
+        let decoded = base64::engine::general_purpose::STANDARD.decode(value).ok()?;
+        let decoded = String::from_utf8(decoded).ok()?;
+        let (user, pass) = decoded.split_once(':')?;
+        return credentials.iter().position(|credential| {
+            matches!(&credential.secret, Secret::Basic(name, password) if name == user && secret_matches(password, pass))
+        });
+    }
+
+    None
+}
+
+// The caller behind an API credential presented outside the `/api` router, for endpoints
+// on the proxy port that reveal more to API callers. None when no credentials are set up.
+pub fn authenticated_caller(headers: &HeaderMap) -> Option<ApiCaller> {
+    let credentials = read_api_credentials();
+    let authorization = headers.get(header::AUTHORIZATION)?.to_str().ok()?;
+    authenticate(&credentials, authorization).map(|index| ApiCaller { name: credentials[index].name.clone() })
+}
+
+// Guards the `/api` router: every request needs a credential from `api_access.txt`,
+// requests that change state need the admin role, and scoped credentials only reach
+// their own endpoints
+pub async fn require_api_auth(mut req: Request, next: Next) -> Response {
+    match authorize(&read_api_credentials(), &req) {
+        Ok(Some(caller)) => {
+            req.extensions_mut().insert(caller);
+            next.run(req).await
+        }
+        Ok(None) => next.run(req).await,
+        Err(Refusal::Unauthenticated) => (
+            StatusCode::UNAUTHORIZED,
+            [(header::WWW_AUTHENTICATE, "Bearer realm=\"proxy api\", Basic realm=\"proxy api\"")],
+            Json(json!({ "error": "Authentication required" })),
+        ).into_response(),
+        Err(Refusal::Forbidden(message)) => (StatusCode::FORBIDDEN, Json(json!({ "error": message }))).into_response(),
+    }
+}
+
+enum Refusal {
+    Unauthenticated,
+    Forbidden(String),
+}
+
+// The caller a request is made by, or None when no credentials are set up
+fn authorize(credentials: &[ApiCredential], req: &Request) -> Result<Option<ApiCaller>, Refusal> {
+    if credentials.is_empty() {
+        return Ok(None);
+    }
+
+    let authorization = req.headers().get(header::AUTHORIZATION).and_then(|value| value.to_str().ok());
+    let Some(credential) = authorization.and_then(|authorization| authenticate(credentials, authorization)).map(|index| &credentials[index]) else {
+        tracing::warn!("🔐 Unauthenticated API request: {} {}", req.method(), req.uri().path());
+        return Err(Refusal::Unauthenticated);
+    };
+
+    // Paths are relative to `/api` here, e.g. `/connections/…`
+    let endpoint = req.uri().path().trim_start_matches('/').split('/').next().unwrap_or_default();
+    if let Some(scope) = &credential.scope {
+        if !scope.iter().any(|allowed| allowed == endpoint) {
+            tracing::warn!("🚫 API caller {} is not scoped for /api/{}", credential.name, endpoint);
+            return Err(Refusal::Forbidden(format!("Not permitted to access /api/{}", endpoint)));
+        }
+    }
+
+    let read_only = matches!(*req.method(), Method::GET | Method::HEAD | Method::OPTIONS);
+    if !read_only && credential.role != ApiRole::Admin {
+        tracing::warn!("🚫 API caller {} ({}) attempted {} {}", credential.name, credential.role.as_str(), req.method(), req.uri().path());
+        return Err(Refusal::Forbidden(format!("The {} role cannot {} /api/{}", credential.role.as_str(), req.method(), endpoint)));
+    }
+
+    Ok(Some(ApiCaller { name: credential.name.clone() }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::body::Body;
+
+    // The second token is "secret" given as its SHA-256 digest
+    const API_ACCESS: &str = "\
+        # monitoring\n\
+        token viewer-token viewer name=grafana\n\
+        token sha256:2bb80d537b1da3e38bd30361aa855686bde0eacd7162fef6a25fe97bf527a25b admin name=ops\n\
+        basic alice:wonderland admin\n\
+        token scoped-token admin name=terminator scope=connections\n\
+        token broken-token superuser\n";
+
+    fn request(method: Method, path: &str, authorization: Option<&str>) -> Request {
+        let mut builder = Request::builder().method(method).uri(path);
+        if let Some(authorization) = authorization {
+            builder = builder.header(header::AUTHORIZATION, authorization);
+        }
+        builder.body(Body::empty()).unwrap()
+    }
+
+    fn outcome(method: Method, path: &str, authorization: Option<&str>) -> Result<Option<String>, StatusCode> {
+        authorize(&parse_api_credentials(API_ACCESS), &request(method, path, authorization))
+            .map(|caller| caller.map(|caller| caller.name))
+            .map_err(|refusal| match refusal {
+                Refusal::Unauthenticated => StatusCode::UNAUTHORIZED,
+                Refusal::Forbidden(_) => StatusCode::FORBIDDEN,
+            })
+    }
+
+    #[test]
+    fn parses_the_credentials_file() {
+        let credentials = parse_api_credentials(API_ACCESS);
+
+        let names: Vec<_> = credentials.iter().map(|credential| credential.name.as_str()).collect();
+        assert_eq!(names, ["grafana", "ops", "alice", "terminator"]);
+        assert_eq!(credentials[3].scope.as_deref(), Some(&["connections".to_string()][..]));
+    }
+
+    #[test]
+    fn requires_a_valid_secret() {
+        assert_eq!(outcome(Method::GET, "/connections", None), Err(StatusCode::UNAUTHORIZED));
+        assert_eq!(outcome(Method::GET, "/connections", Some("Bearer wrong")), Err(StatusCode::UNAUTHORIZED));
+        assert_eq!(outcome(Method::GET, "/connections", Some("Bearer broken-token")), Err(StatusCode::UNAUTHORIZED));
+        // A hashed secret does not accept its own digest
+        assert_eq!(
+            outcome(Method::GET, "/connections", Some("Bearer sha256:2bb80d537b1da3e38bd30361aa855686bde0eacd7162fef6a25fe97bf527a25b")),
+            Err(StatusCode::UNAUTHORIZED),
+        );
+        // alice:wrong and alice:wonderland
+        assert_eq!(outcome(Method::GET, "/connections", Some("Basic YWxpY2U6d3Jvbmc=")), Err(StatusCode::UNAUTHORIZED));
+        assert_eq!(outcome(Method::GET, "/connections", Some("Basic YWxpY2U6d29uZGVybGFuZA==")), Ok(Some("alice".to_string())));
+    }
+
+    #[test]
+    fn viewers_can_only_read() {
+        assert_eq!(outcome(Method::GET, "/policy", Some("Bearer viewer-token")), Ok(Some("grafana".to_string())));
+        assert_eq!(outcome(Method::PUT, "/policy", Some("Bearer viewer-token")), Err(StatusCode::FORBIDDEN));
+        assert_eq!(outcome(Method::DELETE, "/connections/abc", Some("bearer viewer-token")), Err(StatusCode::FORBIDDEN));
+    }
+
+    #[test]
+    fn scoped_credentials_only_reach_their_endpoints() {
+        assert_eq!(outcome(Method::DELETE, "/connections/abc", Some("Bearer scoped-token")), Ok(Some("terminator".to_string())));
+        assert_eq!(outcome(Method::GET, "/policy", Some("Bearer scoped-token")), Err(StatusCode::FORBIDDEN));
+        assert_eq!(outcome(Method::GET, "/connectionsx", Some("Bearer scoped-token")), Err(StatusCode::FORBIDDEN));
+    }
+
+    #[test]
+    fn admins_may_change_state() {
+        assert_eq!(outcome(Method::PUT, "/policy", Some("Bearer secret")), Ok(Some("ops".to_string())));
+        assert_eq!(outcome(Method::POST, "/policy/shadow/promote", Some("Bearer secret")), Ok(Some("ops".to_string())));
+    }
+
+    #[test]
+    fn stays_open_without_credentials() {
+        let outcome = authorize(&parse_api_credentials("# nothing yet\n"), &request(Method::PUT, "/policy", None));
+
+        assert!(matches!(outcome, Ok(None)));
+    }
+}
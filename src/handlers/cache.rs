@@ -0,0 +1,44 @@
+use serde::Deserialize;
+use serde_json::{Value, json};
+use axum::http::StatusCode;
+use axum::response::Json;
+use std::sync::Arc;
+
+use crate::cache::Cache;
+use crate::handlers::auth::{caller_name, ApiCaller};
+
+pub type CacheState = Option<Arc<Cache>>;
+
+#[derive(Deserialize)]
+pub struct PurgeQuery {
+    url: Option<String>, // Purge this URL (every stored variant of it) instead of everything
+}
+
+pub async fn get_cache(
+    axum::extract::State(state): axum::extract::State<CacheState>
+) -> Result<Json<Value>, (StatusCode, Json<Value>)> {
+    let cache = state.ok_or_else(not_enabled)?;
+
+    Ok(Json(json!(cache.stats())))
+}
+
+pub async fn purge_cache(
+    axum::extract::State(state): axum::extract::State<CacheState>,
+    axum::extract::Query(query): axum::extract::Query<PurgeQuery>,
+    caller: Option<axum::Extension<ApiCaller>>,
+) -> Result<Json<Value>, (StatusCode, Json<Value>)> {
+    let cache = state.ok_or_else(not_enabled)?;
+    let purged = cache.purge(query.url.as_deref());
+
+    let caller = caller_name(caller);
+    tracing::info!("🗑️ {} purged {} cache entries ({})", caller, purged, query.url.as_deref().unwrap_or("all"));
+
+    Ok(Json(json!({
+        "purged": purged,
+        "url": query.url
+    })))
+}
+
+fn not_enabled() -> (StatusCode, Json<Value>) {
+    (StatusCode::NOT_FOUND, Json(json!({ "error": "HTTP caching is not enabled" })))
+}
@@ -1,21 +1,131 @@
 use serde_json::{Value, json};
 use chrono::{DateTime, Utc};
 use serde::{Deserialize, Serialize};
-use std::{collections::HashMap};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::{collections::HashMap, sync::Arc};
+use axum::extract::{Extension, Path, Query, State};
+use axum::http::StatusCode;
 use axum::response::Json;
+use dashmap::DashMap;
+use tokio::sync::oneshot;
 
+use crate::cache::CacheLookup;
+use crate::handlers::auth::{caller_name, ApiCaller};
+use crate::icap::ScanVerdict;
+use crate::routing::split_host_port;
 use crate::OptimizedMonitoringState;
 
+// Handles for the running tunnels and UDP flows, by connection id (the monitoring key)
+pub type TunnelHandles = Arc<DashMap<String, TunnelHandle>>;
+
+pub struct TunnelHandle {
+    pub cancel: oneshot::Sender<()>,
+    pub transferred: Transferred,
+}
+
+// Bytes a tunnel has relayed so far, counted as they pass so that a tunnel closed
+// before it finishes can still be accounted for
+#[derive(Clone, Default)]
+pub struct Transferred {
+    pub sent: Arc<AtomicU64>,     // From the client
+    pub received: Arc<AtomicU64>, // To the client
+}
+
+impl Transferred {
+    pub fn add_sent(&self, bytes: u64) {
+        self.sent.fetch_add(bytes, Ordering::Relaxed);
+    }
+
+    pub fn add_received(&self, bytes: u64) {
+        self.received.fetch_add(bytes, Ordering::Relaxed);
+    }
+
+    // (sent, received)
+    pub fn totals(&self) -> (u64, u64) {
+        (self.sent.load(Ordering::Relaxed), self.received.load(Ordering::Relaxed))
+    }
+}
+
+#[derive(Clone)]
+pub struct TerminateState {
+    pub monitoring_state: OptimizedMonitoringState,
+    pub tunnels: TunnelHandles,
+}
+
+#[derive(Deserialize)]
+pub struct TerminateQuery {
+    client: Option<String>, // Client IP or authenticated user
+    target: Option<String>, // host:port, or a host for every port
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct ConnectionInfo {
     pub client_ip: String,
+    pub user: Option<String>, // Authenticated user, when the client proved an identity
     pub target_host: String,
     pub timestamp: DateTime<Utc>,
     pub user_agent: Option<String>,
     pub bytes_sent: u64,
     pub bytes_received: u64,
-    pub status: String, // "active", "completed", "blocked", "failed", "payment_required"
+    pub status: String, // "active", "completed", "blocked", "port_denied", "destination_denied", "sni_blocked", "sni_mismatch", "protocol_denied", "failed", "payment_required", "timeout", "admin_terminated"
     pub duration_ms: Option<u64>,
+    pub transport: String, // "tcp" or "udp"
+    pub route: Option<String>, // "direct" or the upstream proxy the tunnel was chained through
+    pub dns_ms: Option<u64>, // Time spent resolving the target (or upstream) name
+    pub remote_addr: Option<String>, // Address that won the connection race (the upstream's when chained)
+    pub sni: Option<String>, // Server name from the TLS ClientHello, with SNI inspection on
+    pub alpn: Option<Vec<String>>, // Protocols offered in the TLS ClientHello
+    pub intercepted: bool, // TLS was terminated by the proxy for inspection
+    pub urls: Vec<String>, // Requests seen inside an intercepted tunnel, or the forwarded HTTP request, as `METHOD url`
+    pub protocol: Option<String>, // "tls", "ssh", "http", "http2" or "unknown", from the first bytes of the tunnel
+    pub tls_version: Option<String>, // Version negotiated in the ServerHello
+    pub ja4: Option<String>, // JA4 fingerprint of the client's ClientHello
+    pub scans: Vec<ScanVerdict>, // ICAP verdicts for the requests in `urls`
+    pub cache: Vec<CacheLookup>, // HTTP cache results for the requests in `urls`
+}
+
+impl ConnectionInfo {
+    // A connection as first recorded, before any bytes were relayed; one refused outright
+    // (any status but "active") is recorded as finished on the spot
+    pub fn new(
+        client_ip: String,
+        user: Option<String>,
+        target_host: String,
+        timestamp: DateTime<Utc>,
+        user_agent: Option<String>,
+        transport: &str,
+        status: &str,
+    ) -> Self {
+        ConnectionInfo {
+            client_ip,
+            user,
+            target_host,
+            timestamp,
+            user_agent,
+            bytes_sent: 0,
+            bytes_received: 0,
+            status: status.to_string(),
+            duration_ms: if is_terminal(status) { Some(0) } else { None },
+            transport: transport.to_string(),
+            route: None,
+            dns_ms: None,
+            remote_addr: None,
+            sni: None,
+            alpn: None,
+            intercepted: false,
+            urls: Vec::new(),
+            protocol: None,
+            tls_version: None,
+            ja4: None,
+            scans: Vec::new(),
+            cache: Vec::new(),
+        }
+    }
+}
+
+// Whether a connection has ended: every status but "active" records an outcome
+pub fn is_terminal(status: &str) -> bool {
+    status != "active"
 }
 
 pub async fn get_connections(
@@ -47,10 +157,25 @@ pub async fn get_active_connections(
         if conn.status == "active" {
             active_count += 1;
             active_connections.push(json!({
+                "id": client_ip,
                 "client_ip": client_ip,
+                "user": conn.user,
                 "target_host": conn.target_host,
                 "timestamp": conn.timestamp,
-                "user_agent": conn.user_agent
+                "user_agent": conn.user_agent,
+                "transport": conn.transport,
+                "route": conn.route,
+                "dns_ms": conn.dns_ms,
+                "remote_addr": conn.remote_addr,
+                "sni": conn.sni,
+                "alpn": conn.alpn,
+                "intercepted": conn.intercepted,
+                "urls": conn.urls,
+                "protocol": conn.protocol,
+                "tls_version": conn.tls_version,
+                "ja4": conn.ja4,
+                "scans": conn.scans,
+                "cache": conn.cache
             }));
         }
     }
@@ -59,4 +184,201 @@ pub async fn get_active_connections(
         "active_connections": active_count,
         "connections": active_connections
     }))
-}
\ No newline at end of file
+}
+
+// Close one running tunnel; the tunnel task records it as `admin_terminated`
+pub async fn terminate_connection(
+    State(state): State<TerminateState>,
+    Path(id): Path<String>,
+    caller: Option<Extension<ApiCaller>>,
+) -> Result<Json<Value>, (StatusCode, Json<Value>)> {
+    if !terminate(&state, &id, &caller_name(caller)) {
+        return Err((StatusCode::NOT_FOUND, Json(json!({ "error": format!("No running tunnel with id {}", id) }))));
+    }
+
+    Ok(Json(json!({
+        "terminated": 1,
+        "connections": [id]
+    })))
+}
+
+// Close every running tunnel of a client, to a destination, or both
+pub async fn terminate_connections(
+    State(state): State<TerminateState>,
+    Query(query): Query<TerminateQuery>,
+    caller: Option<Extension<ApiCaller>>,
+) -> Result<Json<Value>, (StatusCode, Json<Value>)> {
+    if query.client.is_none() && query.target.is_none() {
+        return Err((StatusCode::BAD_REQUEST, Json(json!({ "error": "Give a client, a target or both" }))));
+    }
+
+    let matching: Vec<String> = state.tunnels.iter()
+        .map(|entry| entry.key().clone())
+        .filter(|id| state.monitoring_state.get(id).is_some_and(|conn| {
+            let client_matches = query.client.as_deref().is_none_or(|client| {
+                conn.client_ip == client || conn.user.as_deref() == Some(client)
+            });
+            let target_matches = query.target.as_deref().is_none_or(|target| {
+                conn.target_host == target || split_host_port(&conn.target_host).0 == target
+            });
+            client_matches && target_matches
+        }))
+        .collect();
+
+    let caller = caller_name(caller);
+    let terminated: Vec<String> = matching.into_iter()
+        .filter(|id| terminate(&state, id, &caller))
+        .collect();
+
+    Ok(Json(json!({
+        "terminated": terminated.len(),
+        "connections": terminated
+    })))
+}
+
+fn terminate(state: &TerminateState, id: &str, caller: &str) -> bool {
+    let Some((_, handle)) = state.tunnels.remove(id) else {
+        return false;
+    };
+    // The tunnel may have finished in the meantime, leaving nothing to cancel
+    if handle.cancel.send(()).is_err() {
+        return false;
+    }
+
+    if let Some(conn) = state.monitoring_state.get(id) {
+        tracing::warn!("🛑 {} terminated tunnel {}: {} → {}", caller, id, conn.client_ip, conn.target_host);
+    }
+    true
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // A running tunnel, with the receiver its task would wait on
+    fn running(state: &TerminateState, id: &str, client_ip: &str, user: Option<&str>, target_host: &str) -> oneshot::Receiver<()> {
+        let conn = ConnectionInfo::new(
+            client_ip.to_string(),
+            user.map(str::to_string),
+            target_host.to_string(),
+            Utc::now(),
+            None,
+            "tcp",
+            "active",
+        );
+        state.monitoring_state.insert(id.to_string(), conn);
+
+        let (cancel, cancelled) = oneshot::channel();
+        state.tunnels.insert(id.to_string(), TunnelHandle { cancel, transferred: Transferred::default() });
+        cancelled
+    }
+
+    fn state() -> TerminateState {
+        TerminateState { monitoring_state: Arc::new(DashMap::new()), tunnels: Arc::new(DashMap::new()) }
+    }
+
+    fn query(client: Option<&str>, target: Option<&str>) -> Query<TerminateQuery> {
+        Query(TerminateQuery { client: client.map(str::to_string), target: target.map(str::to_string) })
+    }
+
+    fn ids(response: &Json<Value>) -> Vec<String> {
+        let mut ids: Vec<String> = serde_json::from_value(response["connections"].clone()).unwrap();
+        ids.sort();
+        ids
+    }
+
+    #[test]
+    fn only_active_connections_are_running() {
+        assert!(!is_terminal("active"));
+        for status in ["completed", "blocked", "sni_blocked", "failed", "timeout", "admin_terminated"] {
+            assert!(is_terminal(status), "{}", status);
+        }
+    }
+
+    #[test]
+    fn refused_connections_are_recorded_as_finished() {
+        let active = ConnectionInfo::new("192.0.2.10".into(), None, "example.com:443".into(), Utc::now(), None, "tcp", "active");
+        assert_eq!(active.status, "active");
+        assert_eq!(active.duration_ms, None);
+        assert_eq!(active.transport, "tcp");
+
+        let blocked = ConnectionInfo::new("192.0.2.10".into(), Some("alice".into()), "example.com:443".into(), Utc::now(), None, "udp", "blocked");
+        assert_eq!(blocked.duration_ms, Some(0));
+        assert_eq!(blocked.user.as_deref(), Some("alice"));
+        assert_eq!((blocked.bytes_sent, blocked.bytes_received), (0, 0));
+    }
+
+    #[test]
+    fn counts_the_bytes_relayed_so_far() {
+        let transferred = Transferred::default();
+        let shared = transferred.clone();
+
+        shared.add_sent(100);
+        shared.add_received(2048);
+        transferred.add_sent(20);
+
+        assert_eq!(transferred.totals(), (120, 2048));
+    }
+
+    #[tokio::test]
+    async fn terminates_one_tunnel() {
+        let state = state();
+        let mut cancelled = running(&state, "a", "192.0.2.10", None, "example.com:443");
+        let mut other = running(&state, "b", "192.0.2.10", None, "example.com:443");
+
+        let response = terminate_connection(State(state.clone()), Path("a".to_string()), None).await.ok().unwrap();
+
+        assert_eq!(response["terminated"], 1);
+        assert!(cancelled.try_recv().is_ok());
+        assert!(other.try_recv().is_err());
+        assert!(!state.tunnels.contains_key("a"));
+        assert!(state.tunnels.contains_key("b"));
+    }
+
+    #[tokio::test]
+    async fn unknown_or_finished_tunnels_are_not_found() {
+        let state = state();
+        let (status, _) = terminate_connection(State(state.clone()), Path("missing".to_string()), None).await.err().unwrap();
+        assert_eq!(status, StatusCode::NOT_FOUND);
+
+        // The task already ended and dropped its receiver
+        drop(running(&state, "done", "192.0.2.10", None, "example.com:443"));
+        let (status, _) = terminate_connection(State(state), Path("done".to_string()), None).await.err().unwrap();
+        assert_eq!(status, StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn terminates_by_client_and_target() {
+        let state = state();
+        let _a = running(&state, "a", "192.0.2.10", None, "example.com:443");
+        let _b = running(&state, "b", "192.0.2.10", None, "example.org:443");
+        let _c = running(&state, "c", "192.0.2.11", Some("alice"), "example.com:8443");
+        let _d = running(&state, "d", "192.0.2.12", None, "example.com:443");
+
+        let response = terminate_connections(State(state.clone()), query(Some("192.0.2.10"), Some("example.com")), None).await.ok().unwrap();
+        assert_eq!(ids(&response), ["a"]);
+
+        // A user name matches as a client, a bare host matches every port
+        let response = terminate_connections(State(state.clone()), query(Some("alice"), None), None).await.ok().unwrap();
+        assert_eq!(ids(&response), ["c"]);
+
+        let response = terminate_connections(State(state.clone()), query(None, Some("example.com:443")), None).await.ok().unwrap();
+        assert_eq!(ids(&response), ["d"]);
+        assert_eq!(response["terminated"], 1);
+
+        let response = terminate_connections(State(state.clone()), query(None, Some("example.org")), None).await.ok().unwrap();
+        assert_eq!(ids(&response), ["b"]);
+        assert!(state.tunnels.is_empty());
+    }
+
+    #[tokio::test]
+    async fn terminating_needs_a_client_or_target() {
+        let state = state();
+        let _a = running(&state, "a", "192.0.2.10", None, "example.com:443");
+
+        let (status, _) = terminate_connections(State(state.clone()), query(None, None), None).await.err().unwrap();
+
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        assert!(state.tunnels.contains_key("a"));
+    }
+}
@@ -1,2 +1,8 @@
 pub mod users;
-pub mod connections;
\ No newline at end of file
+pub mod connections;
+pub mod pools;
+pub mod cache;
+pub mod pages;
+pub mod pac;
+pub mod auth;
+pub mod policy;
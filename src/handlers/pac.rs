@@ -0,0 +1,62 @@
+use serde::Deserialize;
+use axum::body::Body;
+use axum::extract::{Extension, Query, State};
+use axum::http::{header, HeaderMap, Response, StatusCode};
+use local_ip_address::local_ip;
+use std::sync::Arc;
+
+use crate::config::Config;
+use crate::groups::client_groups;
+use crate::handlers::auth::authenticated_caller;
+use crate::pac;
+use crate::routing::split_host_port;
+use crate::ClientIdentity;
+
+pub type PacState = Arc<Config>;
+
+#[derive(Deserialize)]
+pub struct PacQuery {
+    group: Option<String>, // Generate the script for this client group, for its members or API callers
+}
+
+// Served as both `/proxy.pac` and `/wpad.dat`
+pub async fn get_pac(
+    State(config): State<PacState>,
+    Query(query): Query<PacQuery>,
+    client: Option<Extension<ClientIdentity>>,
+    headers: HeaderMap,
+) -> Response<Body> {
+    let own_groups = client.map(|Extension(client)| client_groups(&client)).unwrap_or_default();
+    // Another group's routes and rules are only revealed to an API caller
+    let groups = match query.group {
+        Some(group) if own_groups.contains(&group) => vec![group],
+        Some(group) => match authenticated_caller(&headers) {
+            Some(caller) => {
+                tracing::info!("🧭 {} fetched the proxy auto-config for group {}", caller.name, group);
+                vec![group]
+            }
+            None => {
+                return Response::builder()
+                    .status(StatusCode::FORBIDDEN)
+                    .body(Body::from(format!("Not a member of group {}; other groups need API credentials", group)))
+                    .unwrap();
+            }
+        },
+        None => own_groups,
+    };
+
+    // Without a configured address, point clients at the host they fetched the script from
+    let proxy_addr = config.pac_proxy_addr.clone().unwrap_or_else(|| {
+        let host = headers.get(header::HOST)
+            .and_then(|host| host.to_str().ok())
+            .map(|host| split_host_port(host).0.to_string())
+            .or_else(|| local_ip().ok().map(|ip| ip.to_string()))
+            .unwrap_or_else(|| "127.0.0.1".to_string());
+        format!("{}:{}", host, config.proxy_port)
+    });
+
+    Response::builder()
+        .header(header::CONTENT_TYPE, pac::CONTENT_TYPE)
+        .body(Body::from(pac::generate(&config, &groups, &proxy_addr)))
+        .unwrap()
+}
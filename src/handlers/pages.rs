@@ -0,0 +1,28 @@
+use serde_json::{Value, json};
+use axum::body::Body;
+use axum::http::{HeaderMap, Response, StatusCode};
+use axum::response::Json;
+
+use crate::pages::{self, Page, PageDetails};
+
+// Render a block or error page with sample values, to check templates and translations
+// without having to trigger the condition
+pub async fn preview_page(
+    axum::extract::Path(name): axum::extract::Path<String>,
+    headers: HeaderMap,
+) -> Result<Response<Body>, (StatusCode, Json<Value>)> {
+    let page: Page = name.parse()
+        .map_err(|_| (StatusCode::NOT_FOUND, Json(json!({ "error": format!("Unknown page: {}", name) }))))?;
+
+    let details = PageDetails {
+        host: Some("www.example.com:443".to_string()),
+        url: Some("https://www.example.com/".to_string()),
+        client: Some("192.0.2.10".to_string()),
+        user: Some("alice".to_string()),
+        rule: Some("blocked_sites.txt:1".to_string()),
+        reason: Some("preview".to_string()),
+        connection_id: Some("192.0.2.10_0_0".to_string()),
+    };
+
+    Ok(pages::render(page, details, &headers))
+}
@@ -0,0 +1,268 @@
+use serde::Deserialize;
+use serde_json::{Value, json};
+use axum::extract::{Path, Query, State};
+use axum::http::StatusCode;
+use axum::response::Json;
+use axum::Extension;
+use std::sync::Arc;
+
+use crate::config::Config;
+use crate::handlers::auth::{caller_name, ApiCaller};
+use crate::policy::{self, Access, PolicyError, PolicyStore, PolicyVersion};
+
+pub type PolicyState = Arc<PolicyStore>;
+
+type ApiResult = Result<Json<Value>, (StatusCode, Json<Value>)>;
+
+// Every change may name the version it was based on, to be refused if the rules changed since
+#[derive(Deserialize)]
+pub struct AddRule {
+    rule: String,
+    position: Option<usize>, // 1-based; appended when omitted
+    version: Option<u64>,
+    comment: Option<String>,
+}
+
+#[derive(Deserialize)]
+pub struct MoveRule {
+    to: usize, // 1-based position the rule ends up at
+    version: Option<u64>,
+    comment: Option<String>,
+}
+
+#[derive(Deserialize, Default)]
+pub struct ChangeOptions {
+    version: Option<u64>,
+    comment: Option<String>,
+}
+
+#[derive(Deserialize)]
+pub struct ReplaceRules {
+    rules: Vec<String>,
+    version: Option<u64>,
+    comment: Option<String>,
+}
+
+#[derive(Deserialize)]
+pub struct LoadShadow {
+    rules: Vec<String>,
+    comment: Option<String>,
+}
+
+#[derive(Deserialize)]
+pub struct EvaluateQuery {
+    client: String,
+    target: String, // host:port, as in a CONNECT request
+    user: Option<String>,
+    #[serde(default)]
+    access: Access, // "connect" (default), "forward" or "udp"
+}
+
+#[derive(Deserialize)]
+pub struct ValidateRules {
+    rules: Vec<String>,
+}
+
+pub async fn get_policy(State(store): State<PolicyState>) -> ApiResult {
+    let current = store.current().map_err(error_response)?;
+    Ok(Json(policy_json(&current)))
+}
+
+pub async fn replace_policy(
+    State(store): State<PolicyState>,
+    caller: Option<Extension<ApiCaller>>,
+    Json(body): Json<ReplaceRules>,
+) -> ApiResult {
+    let updated = store
+        .update(body.version, &caller_name(caller), body.comment, |_| Ok(body.rules))
+        .map_err(error_response)?;
+    Ok(Json(policy_json(&updated)))
+}
+
+pub async fn validate_policy(Json(body): Json<ValidateRules>) -> Json<Value> {
+    let errors = policy::validate(&body.rules);
+    Json(json!({
+        "valid": errors.is_empty(),
+        "errors": errors
+    }))
+}
+
+pub async fn add_rule(
+    State(store): State<PolicyState>,
+    caller: Option<Extension<ApiCaller>>,
+    Json(body): Json<AddRule>,
+) -> ApiResult {
+    let updated = store
+        .update(body.version, &caller_name(caller), body.comment, |mut rules| {
+            let index = match body.position {
+                Some(position) => rule_index(position, rules.len() + 1)?,
+                None => rules.len(),
+            };
+            rules.insert(index, body.rule.trim().to_string());
+            Ok(rules)
+        })
+        .map_err(error_response)?;
+    Ok(Json(policy_json(&updated)))
+}
+
+pub async fn delete_rule(
+    State(store): State<PolicyState>,
+    Path(position): Path<usize>,
+    caller: Option<Extension<ApiCaller>>,
+    options: Option<Json<ChangeOptions>>,
+) -> ApiResult {
+    let Json(options) = options.unwrap_or_default();
+    let updated = store
+        .update(options.version, &caller_name(caller), options.comment, |mut rules| {
+            let index = rule_index(position, rules.len())?;
+            rules.remove(index);
+            Ok(rules)
+        })
+        .map_err(error_response)?;
+    Ok(Json(policy_json(&updated)))
+}
+
+pub async fn move_rule(
+    State(store): State<PolicyState>,
+    Path(position): Path<usize>,
+    caller: Option<Extension<ApiCaller>>,
+    Json(body): Json<MoveRule>,
+) -> ApiResult {
+    let updated = store
+        .update(body.version, &caller_name(caller), body.comment, |mut rules| {
+            let from = rule_index(position, rules.len())?;
+            let to = rule_index(body.to, rules.len())?;
+            let rule = rules.remove(from);
+            rules.insert(to, rule);
+            Ok(rules)
+        })
+        .map_err(error_response)?;
+    Ok(Json(policy_json(&updated)))
+}
+
+pub async fn list_versions(State(store): State<PolicyState>) -> ApiResult {
+    let versions = store.versions().map_err(error_response)?;
+    let versions: Vec<Value> = versions.iter()
+        .rev()
+        .map(|version| json!({
+            "version": version.version,
+            "created_at": version.created_at,
+            "author": version.author,
+            "comment": version.comment,
+            "rule_count": version.rules.len()
+        }))
+        .collect();
+
+    Ok(Json(json!({
+        "total_versions": versions.len(),
+        "versions": versions
+    })))
+}
+
+pub async fn get_version(State(store): State<PolicyState>, Path(version): Path<u64>) -> ApiResult {
+    let version = store.version(version).map_err(error_response)?;
+    Ok(Json(policy_json(&version)))
+}
+
+// Applies an earlier rule set as a new version, so the rollback itself can be undone
+pub async fn rollback(
+    State(store): State<PolicyState>,
+    Path(version): Path<u64>,
+    caller: Option<Extension<ApiCaller>>,
+    options: Option<Json<ChangeOptions>>,
+) -> ApiResult {
+    let Json(options) = options.unwrap_or_default();
+    let target = store.version(version).map_err(error_response)?;
+    let comment = options.comment.or_else(|| Some(format!("Rollback to version {}", version)));
+
+    let updated = store
+        .update(options.version, &caller_name(caller), comment, |_| Ok(target.rules))
+        .map_err(error_response)?;
+    Ok(Json(policy_json(&updated)))
+}
+
+// Why a client would be allowed or refused a connection to a target, without connecting
+pub async fn evaluate_policy(State(config): State<Arc<Config>>, Query(query): Query<EvaluateQuery>) -> ApiResult {
+    let client = policy::evaluation_client(&query.client, &query.target, query.user)
+        .map_err(|error| (StatusCode::BAD_REQUEST, Json(json!({ "error": error }))))?;
+    Ok(Json(json!(policy::evaluate(&client, &query.target, query.access, &config))))
+}
+
+// The impact of the shadow rule set so far, to review before promoting it
+pub async fn get_shadow(State(store): State<PolicyState>) -> ApiResult {
+    store.with_shadow(|shadow| Json(json!(shadow))).ok_or_else(no_shadow)
+}
+
+pub async fn load_shadow(
+    State(store): State<PolicyState>,
+    caller: Option<Extension<ApiCaller>>,
+    Json(body): Json<LoadShadow>,
+) -> ApiResult {
+    store.load_shadow(body.rules, &caller_name(caller), body.comment).map_err(error_response)?;
+    store.with_shadow(|shadow| Json(json!(shadow))).ok_or_else(no_shadow)
+}
+
+pub async fn discard_shadow(State(store): State<PolicyState>) -> ApiResult {
+    if !store.discard_shadow() {
+        return Err(no_shadow());
+    }
+    tracing::info!("🌓 Shadow policy discarded");
+    Ok(Json(json!({ "discarded": true })))
+}
+
+pub async fn promote_shadow(
+    State(store): State<PolicyState>,
+    caller: Option<Extension<ApiCaller>>,
+    options: Option<Json<ChangeOptions>>,
+) -> ApiResult {
+    let Json(options) = options.unwrap_or_default();
+    let promoted = store
+        .promote_shadow(options.version, &caller_name(caller), options.comment)
+        .map_err(error_response)?;
+    Ok(Json(policy_json(&promoted)))
+}
+
+fn no_shadow() -> (StatusCode, Json<Value>) {
+    (StatusCode::NOT_FOUND, Json(json!({ "error": "No shadow policy is loaded" })))
+}
+
+fn policy_json(version: &PolicyVersion) -> Value {
+    let rules: Vec<Value> = version.rules.iter()
+        .enumerate()
+        .map(|(index, rule)| json!({ "position": index + 1, "rule": rule }))
+        .collect();
+
+    json!({
+        "version": version.version,
+        "created_at": version.created_at,
+        "author": version.author,
+        "comment": version.comment,
+        "rules": rules
+    })
+}
+
+// Index of a 1-based rule position, out of `len` positions
+fn rule_index(position: usize, len: usize) -> Result<usize, PolicyError> {
+    if position == 0 || position > len {
+        return Err(PolicyError::NotFound(format!("No rule at position {}", position)));
+    }
+    Ok(position - 1)
+}
+
+fn error_response(e: PolicyError) -> (StatusCode, Json<Value>) {
+    match e {
+        PolicyError::Invalid(errors) => (
+            StatusCode::UNPROCESSABLE_ENTITY,
+            Json(json!({ "error": "Invalid rule set", "errors": errors })),
+        ),
+        PolicyError::Conflict(version) => (
+            StatusCode::CONFLICT,
+            Json(json!({ "error": "The policy has changed since the given version", "version": version })),
+        ),
+        PolicyError::NotFound(message) => (StatusCode::NOT_FOUND, Json(json!({ "error": message }))),
+        PolicyError::Io(e) => {
+            tracing::error!("❌ Failed to update the policy: {}", e);
+            (StatusCode::INTERNAL_SERVER_ERROR, Json(json!({ "error": format!("Failed to update the policy: {}", e) })))
+        }
+    }
+}
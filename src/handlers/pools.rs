@@ -0,0 +1,43 @@
+use serde_json::{Value, json};
+use axum::response::Json;
+
+use crate::pool::PoolHealthState;
+use crate::routing::{read_routing, UpstreamKind};
+
+pub async fn get_pools(
+    axum::extract::State(state): axum::extract::State<PoolHealthState>
+) -> Json<Value> {
+    let pools: Vec<Value> = read_routing().pools.iter().map(|pool| {
+        let members: Vec<Value> = pool.members.iter().map(|member| {
+            let health = state.health(&member.name);
+            json!({
+                "name": member.name,
+                "address": member.addr,
+                "protocol": match member.kind {
+                    UpstreamKind::HttpConnect => "http",
+                    UpstreamKind::Socks5 => "socks5",
+                },
+                "healthy": health.is_healthy(),
+                "consecutive_failures": health.consecutive_failures,
+                "active_connections": health.active_connections,
+                "total_connections": health.total_connections,
+                "ejected_until": health.ejected_until,
+                "last_checked": health.last_checked,
+                "last_error": health.last_error
+            })
+        }).collect();
+
+        json!({
+            "name": pool.name,
+            "strategy": pool.strategy.as_str(),
+            "probe": pool.probe,
+            "healthy_members": members.iter().filter(|member| member["healthy"] == true).count(),
+            "members": members
+        })
+    }).collect();
+
+    Json(json!({
+        "total_pools": pools.len(),
+        "pools": pools
+    }))
+}
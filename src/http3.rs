@@ -0,0 +1,406 @@
+use axum::body::Body;
+use axum::http::{Method, Response, StatusCode};
+use bytes::{Buf, Bytes};
+use chrono::Utc;
+use h3::server::{RequestResolver, RequestStream};
+use quinn::crypto::rustls::QuicServerConfig;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+use tokio_rustls::rustls::{pki_types::CertificateDer, ServerConfig};
+
+use crate::mitm;
+use crate::pages::{self, Page, PageDetails};
+use crate::policy::{Access, Verdict};
+use crate::protocol::{Direction, Sniffer};
+use crate::sni::{self, Parsed};
+use crate::tls::{self, SharedTlsConfig};
+use crate::{
+    AppState,
+    ClientIdentity,
+    CONNECTION_TIMEOUT_SECS,
+    TUNNEL_TIMEOUT_SECS,
+    apply_client_hello,
+    check_policy,
+    dial,
+    record_allowed,
+    record_blocked,
+    record_tunnel_result,
+    terminable,
+    transferred,
+};
+
+const H3_IDLE_TIMEOUT_SECS: u64 = 60; // Close QUIC connections without any activity
+const H3_MAX_CONCURRENT_STREAMS: u32 = 100; // Tunnels multiplexed over one QUIC connection
+const H3_CERT_CHECK_INTERVAL_SECS: u64 = 30; // Pick up certificates reloaded by the TLS watcher
+const RELAY_BUFFER_SIZE: usize = 16 * 1024; // Largest DATA frame sent back to the client
+
+type H3Connection = h3::server::Connection<h3_quinn::Connection, Bytes>;
+
+// Accept loop for the QUIC listener. It shares the TLS certificate (and client CA)
+// with the TCP TLS listener and follows its hot reloads.
+pub async fn serve_http3(
+    addr: SocketAddr,
+    tls_config: SharedTlsConfig,
+    app_state: AppState,
+) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    let current = tls_config.read().unwrap().clone();
+    let endpoint = quinn::Endpoint::server(quic_server_config(&current)?, addr)?;
+
+    let reload_endpoint = endpoint.clone();
+    tokio::spawn(async move {
+        follow_certificate_reloads(reload_endpoint, tls_config, current).await;
+    });
+
+    while let Some(incoming) = endpoint.accept().await {
+        let client_ip = incoming.remote_address().ip();
+
+        let permit = match app_state.connection_semaphore.clone().try_acquire_owned() {
+            Ok(permit) => permit,
+            Err(_) => {
+                tracing::warn!("🚫 Connection limit reached, rejecting connection from {}", client_ip);
+                incoming.refuse();
+                continue;
+            }
+        };
+
+        let app_state = app_state.clone();
+
+        tokio::spawn(async move {
+            let _permit = permit;
+
+            if let Err(e) = handle_quic_connection(incoming, app_state).await {
+                tracing::warn!("❌ HTTP/3 connection error from {}: {}", client_ip, e);
+            }
+        });
+    }
+
+    Ok(())
+}
+
+fn quic_server_config(tls_config: &ServerConfig) -> Result<quinn::ServerConfig, Box<dyn std::error::Error + Send + Sync>> {
+    let mut crypto = tls_config.clone();
+    crypto.alpn_protocols = vec![b"h3".to_vec()];
+    crypto.max_early_data_size = 0;
+
+    let mut server_config = quinn::ServerConfig::with_crypto(Arc::new(QuicServerConfig::try_from(crypto)?));
+
+    let mut transport = quinn::TransportConfig::default();
+    transport.max_idle_timeout(Some(Duration::from_secs(H3_IDLE_TIMEOUT_SECS).try_into()?));
+    transport.max_concurrent_bidi_streams(H3_MAX_CONCURRENT_STREAMS.into());
+    server_config.transport_config(Arc::new(transport));
+
+    Ok(server_config)
+}
+
+async fn follow_certificate_reloads(
+    endpoint: quinn::Endpoint,
+    tls_config: SharedTlsConfig,
+    mut current: Arc<ServerConfig>,
+) {
+    let mut check_interval = tokio::time::interval(Duration::from_secs(H3_CERT_CHECK_INTERVAL_SECS));
+
+    loop {
+        check_interval.tick().await;
+
+        let latest = tls_config.read().unwrap().clone();
+        if Arc::ptr_eq(&latest, &current) {
+            continue;
+        }
+
+        match quic_server_config(&latest) {
+            Ok(server_config) => {
+                endpoint.set_server_config(Some(server_config));
+                tracing::info!("🔐 HTTP/3 listener picked up the reloaded TLS certificate");
+            }
+            Err(e) => {
+                tracing::error!("❌ Failed to apply reloaded TLS certificate to HTTP/3: {}", e);
+            }
+        }
+        current = latest;
+    }
+}
+
+async fn handle_quic_connection(
+    incoming: quinn::Incoming,
+    app_state: AppState,
+) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    let connection = tokio::time::timeout(
+        Duration::from_secs(CONNECTION_TIMEOUT_SECS),
+        incoming.accept()?
+    ).await??;
+
+    let client_ip = connection.remote_address().ip().to_canonical().to_string();
+
+    // With mutual TLS the client certificate names the user, as on the TCP TLS listener
+    let peer_certs = connection.peer_identity()
+        .and_then(|identity| identity.downcast::<Vec<CertificateDer<'static>>>().ok());
+    let user = tls::client_identity(peer_certs.as_deref().map(|certs| certs.as_slice()), &app_state.config);
+
+    let mut h3_conn: H3Connection = h3::server::builder()
+        .build(h3_quinn::Connection::new(connection))
+        .await?;
+
+    loop {
+        match h3_conn.accept().await {
+            Ok(Some(resolver)) => {
+                let client = ClientIdentity::new(client_ip.clone(), user.clone());
+                let app_state = app_state.clone();
+
+                tokio::spawn(async move {
+                    if let Err(e) = handle_request(resolver, client.clone(), app_state).await {
+                        tracing::warn!("❌ HTTP/3 stream error from {}: {}", client, e);
+                    }
+                });
+            }
+            Ok(None) => break,
+            Err(e) if e.is_h3_no_error() => break,
+            Err(e) => return Err(e.into()),
+        }
+    }
+
+    Ok(())
+}
+
+// Each request stream is one CONNECT tunnel, checked, recorded and accounted
+// exactly like CONNECT on the HTTP/1 and HTTP/2 listeners
+async fn handle_request(
+    resolver: RequestResolver<h3_quinn::Connection, Bytes>,
+    client: ClientIdentity,
+    app_state: AppState,
+) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    let (req, mut stream) = resolver.resolve_request().await?;
+
+    if req.method() != Method::CONNECT {
+        return send_status(&mut stream, StatusCode::METHOD_NOT_ALLOWED).await;
+    }
+    if req.extensions().get::<h3::ext::Protocol>().is_some() {
+        return send_status(&mut stream, StatusCode::NOT_IMPLEMENTED).await;
+    }
+
+    let Some(host_addr) = req.uri().authority().map(|auth| auth.to_string()) else {
+        tracing::warn!("⚠️ Invalid CONNECT request from {}: {:?}", client, req.uri());
+        return send_status(&mut stream, StatusCode::BAD_REQUEST).await;
+    };
+
+    let user_agent = req.headers().get("user-agent")
+        .and_then(|v| v.to_str().ok())
+        .map(|s| s.to_string());
+
+    let evaluation = check_policy(&app_state, &client, &host_addr, Access::Connect);
+    if evaluation.verdict != Verdict::Allowed {
+        let conn_key = record_blocked(&app_state, &client, &host_addr, user_agent, "tcp", evaluation.verdict.as_str()).await;
+        let page = pages::render(Page::Blocked, PageDetails {
+            rule: evaluation.rule,
+            reason: evaluation.verdict.reason().map(str::to_string),
+            ..PageDetails::for_connection(&client, &host_addr, &conn_key)
+        }, req.headers());
+        return send_page(&mut stream, page).await;
+    }
+
+    let conn_key = record_allowed(&app_state, &client, &host_addr, user_agent, "tcp").await;
+
+    terminable(&app_state, &conn_key, &client, &host_addr, connect_tunnel(stream, &app_state, &conn_key, &client, &host_addr))
+        .await
+        .unwrap_or(Ok(()))
+}
+
+// Accept the CONNECT, connect to the target and relay until either side is done. As with
+// CONNECT on the TCP listeners, the target is only dialed once the ClientHello has passed
+// inspection, which the client sends after the CONNECT is accepted.
+async fn connect_tunnel(
+    mut stream: RequestStream<h3_quinn::BidiStream<Bytes>, Bytes>,
+    app_state: &AppState,
+    conn_key: &str,
+    client: &ClientIdentity,
+    host_addr: &str,
+) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    let start_time = Utc::now();
+    if let Err(e) = stream.send_response(Response::builder().status(StatusCode::OK).body(()).unwrap()).await {
+        let error = std::io::Error::other(e);
+        record_tunnel_result(app_state, conn_key, client, host_addr, start_time, Ok(Err(error))).await;
+        return Ok(());
+    }
+
+    let tunnel_result = tokio::time::timeout(
+        Duration::from_secs(TUNNEL_TIMEOUT_SECS),
+        async {
+            // Intercepted tunnels check the ClientHello while terminating TLS themselves
+            if mitm::should_intercept(app_state, host_addr) {
+                let server = dial(app_state, conn_key, client, host_addr).await?;
+                return intercept_stream(stream, server, app_state, conn_key, client, host_addr).await;
+            }
+            let preface = inspect_client_hello(&mut stream, app_state, conn_key, client, host_addr).await?;
+            let server = dial(app_state, conn_key, client, host_addr).await?;
+            relay_stream(app_state, conn_key, stream, server, &preface).await
+        }
+    ).await;
+
+    record_tunnel_result(app_state, conn_key, client, host_addr, start_time, tunnel_result).await;
+
+    Ok(())
+}
+
+// Terminate TLS for inspection as on the TCP listeners: the interceptor serves one end of
+// an in-memory pipe, and the other end is copied to and from the stream's DATA frames.
+// The tunnel ends once the interceptor is done with the client.
+async fn intercept_stream(
+    stream: RequestStream<h3_quinn::BidiStream<Bytes>, Bytes>,
+    server: TcpStream,
+    app_state: &AppState,
+    conn_key: &str,
+    client: &ClientIdentity,
+    host_addr: &str,
+) -> std::io::Result<(u64, u64)> {
+    let (client_io, pipe) = tokio::io::duplex(RELAY_BUFFER_SIZE);
+    let (mut pipe_read, mut pipe_write) = tokio::io::split(pipe);
+    let (mut send, mut recv) = stream.split();
+
+    let from_client = async {
+        while let Some(mut chunk) = recv.recv_data().await.map_err(std::io::Error::other)? {
+            pipe_write.write_all(&chunk.copy_to_bytes(chunk.remaining())).await?;
+        }
+        pipe_write.shutdown().await?;
+        std::future::pending::<std::io::Result<()>>().await
+    };
+
+    let to_client = async {
+        let mut buf = vec![0u8; RELAY_BUFFER_SIZE];
+        loop {
+            let n = pipe_read.read(&mut buf).await?;
+            if n == 0 {
+                break;
+            }
+            send.send_data(Bytes::copy_from_slice(&buf[..n])).await.map_err(std::io::Error::other)?;
+        }
+        send.finish().await.map_err(std::io::Error::other)
+    };
+
+    let pump = async {
+        tokio::select! {
+            result = from_client => result,
+            result = to_client => result,
+        }
+    };
+
+    let (intercepted, pumped) = tokio::join!(mitm::intercept(client_io, server, app_state, conn_key, client, host_addr), pump);
+    if let Err(e) = pumped {
+        tracing::debug!("HTTP/3 stream of an intercepted tunnel closed: {}", e);
+    }
+    intercepted
+}
+
+// Answer with one of the proxy's pages, as the TCP listeners do
+async fn send_page(
+    stream: &mut RequestStream<h3_quinn::BidiStream<Bytes>, Bytes>,
+    page: Response<Body>,
+) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    let (parts, body) = page.into_parts();
+    let body = axum::body::to_bytes(body, usize::MAX).await?;
+
+    stream.send_response(Response::from_parts(parts, ())).await?;
+    stream.send_data(body).await?;
+    stream.finish().await?;
+    Ok(())
+}
+
+async fn send_status(
+    stream: &mut RequestStream<h3_quinn::BidiStream<Bytes>, Bytes>,
+    status: StatusCode,
+) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    stream.send_response(Response::builder().status(status).body(()).unwrap()).await?;
+    stream.finish().await?;
+    Ok(())
+}
+
+// The HTTP/3 counterpart of the TCP listeners' ClientHello inspection, reading the
+// ClientHello from the stream's DATA frames
+async fn inspect_client_hello(
+    stream: &mut RequestStream<h3_quinn::BidiStream<Bytes>, Bytes>,
+    app_state: &AppState,
+    conn_key: &str,
+    client: &ClientIdentity,
+    host_addr: &str,
+) -> std::io::Result<Vec<u8>> {
+    if !app_state.config.sni_inspection {
+        return Ok(Vec::new());
+    }
+
+    let mut preface = Vec::new();
+    let read = async {
+        while let Some(mut chunk) = stream.recv_data().await.map_err(std::io::Error::other)? {
+            preface.extend_from_slice(&chunk.copy_to_bytes(chunk.remaining()));
+            match sni::parse_client_hello(&preface) {
+                Parsed::Complete(hello) => return Ok(Some(hello)),
+                Parsed::Incomplete if preface.len() < sni::MAX_CLIENT_HELLO_BYTES => {}
+                _ => return Ok(None),
+            }
+        }
+        Ok::<_, std::io::Error>(None)
+    };
+
+    let hello = match tokio::time::timeout(Duration::from_secs(sni::CLIENT_HELLO_TIMEOUT_SECS), read).await {
+        Ok(result) => result?,
+        Err(_) => None,
+    };
+    if let Some(hello) = hello {
+        apply_client_hello(app_state, conn_key, client, host_addr, &hello).await?;
+    }
+
+    Ok(preface)
+}
+
+// Copy DATA frames to the target and target bytes back as DATA frames, after
+// the bytes already read for inspection.
+// QUIC flow control applies per stream, so one slow tunnel does not stall the others.
+async fn relay_stream(
+    app_state: &AppState,
+    conn_key: &str,
+    stream: RequestStream<h3_quinn::BidiStream<Bytes>, Bytes>,
+    mut server: TcpStream,
+    preface: &[u8],
+) -> std::io::Result<(u64, u64)> {
+    let transferred = transferred(app_state, conn_key);
+    let mut client_sniffer = Sniffer::new(app_state, conn_key, Direction::Client);
+    let mut server_sniffer = Sniffer::new(app_state, conn_key, Direction::Server);
+    client_sniffer.observe(preface)?;
+    server.write_all(preface).await?;
+    transferred.add_sent(preface.len() as u64);
+
+    let (mut send, mut recv) = stream.split();
+    let (mut server_read, mut server_write) = server.into_split();
+
+    let client_to_server = async {
+        let mut total = preface.len() as u64;
+        while let Some(mut chunk) = recv.recv_data().await.map_err(std::io::Error::other)? {
+            let data = chunk.copy_to_bytes(chunk.remaining());
+            client_sniffer.observe(&data)?;
+            server_write.write_all(&data).await?;
+            transferred.add_sent(data.len() as u64);
+            total += data.len() as u64;
+        }
+        server_write.shutdown().await?;
+        Ok::<u64, std::io::Error>(total)
+    };
+
+    let server_to_client = async {
+        let mut total = 0u64;
+        let mut buf = vec![0u8; RELAY_BUFFER_SIZE];
+        loop {
+            let n = server_read.read(&mut buf).await?;
+            if n == 0 {
+                break;
+            }
+            server_sniffer.observe(&buf[..n])?;
+            send.send_data(Bytes::copy_from_slice(&buf[..n])).await.map_err(std::io::Error::other)?;
+            transferred.add_received(n as u64);
+            total += n as u64;
+        }
+        send.finish().await.map_err(std::io::Error::other)?;
+        Ok::<u64, std::io::Error>(total)
+    };
+
+    tokio::try_join!(client_to_server, server_to_client)
+}
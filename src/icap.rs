@@ -0,0 +1,711 @@
+use axum::body::Body;
+use axum::http::{header, request, response, HeaderMap, Request, Response, Uri};
+use bytes::{Bytes, BytesMut};
+use http_body::Body as _;
+use serde::{Deserialize, Serialize};
+use std::collections::VecDeque;
+use std::io;
+use std::pin::Pin;
+use std::time::Duration;
+use tokio::io::{AsyncBufRead, AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+use tokio::net::TcpStream;
+
+use crate::config::Config;
+use crate::filter::{BoxError, Prefetched};
+use crate::routing::split_host_port;
+use crate::AppState;
+
+const ICAP_DEFAULT_PORT: u16 = 1344;
+const ICAP_TIMEOUT_SECS: u64 = 30;
+const MAX_SCAN_BYTES: usize = 10 * 1024 * 1024; // Larger bodies are passed through unscanned
+const MAX_RECORDED_SCANS: usize = 100; // Verdicts kept on one connection record
+const MAX_HEADER_LINE_BYTES: usize = 64 * 1024;
+
+// ICAP headers naming what a scanner found (c-icap, Symantec and others)
+const INFECTION_HEADERS: &[&str] = &["x-infection-found", "x-virus-id", "x-violations-found"];
+
+// What to do with traffic when the ICAP server cannot be reached or returns an error
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum IcapFailure {
+    Open,
+    Closed,
+}
+
+impl std::str::FromStr for IcapFailure {
+    type Err = ();
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value.to_ascii_lowercase().as_str() {
+            "open" => Ok(IcapFailure::Open),
+            "closed" => Ok(IcapFailure::Closed),
+            _ => Err(()),
+        }
+    }
+}
+
+// Outcome of one REQMOD or RESPMOD exchange, recorded on the connection
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ScanVerdict {
+    pub mode: String, // "reqmod" or "respmod"
+    pub url: String,
+    pub verdict: String, // "clean", "modified", "blocked", "skipped" or "error"
+    pub detail: Option<String>, // Threat name, or why the scan did not happen
+}
+
+// How scanned traffic continues
+pub enum Scanned<T> {
+    Allow(T), // Forward this (possibly modified) message
+    Respond(Response<Body>), // Answer the client with this response instead
+    Deny(String), // The scan failed and ICAP_FAILURE is closed
+}
+
+// An ICAP service, from a URL such as `icap://127.0.0.1:1344/respmod`
+struct Service {
+    url: String,
+    host: String,
+    addr: String,
+}
+
+impl Service {
+    fn parse(url: &str) -> Option<Self> {
+        let rest = url.strip_prefix("icap://")?;
+        let authority = rest.split('/').next().unwrap_or_default();
+        let (host, port) = split_host_port(authority);
+        if host.is_empty() {
+            return None;
+        }
+        let port = match port {
+            Some(port) => port.parse::<u16>().ok()?,
+            None => ICAP_DEFAULT_PORT,
+        };
+
+        Some(Service {
+            url: url.to_string(),
+            host: host.to_string(),
+            addr: format!("{}:{}", host, port),
+        })
+    }
+}
+
+// RFC 3507 client sending intercepted requests (REQMOD) and responses (RESPMOD)
+// to a scanning server such as c-icap with ClamAV
+pub struct IcapClient {
+    reqmod: Option<Service>,
+    respmod: Option<Service>,
+    preview_bytes: usize,
+    failure: IcapFailure,
+}
+
+impl IcapClient {
+    // Scanning is only enabled when ICAP_REQMOD_URL or ICAP_RESPMOD_URL is set
+    pub fn from_config(config: &Config) -> Result<Option<Self>, String> {
+        let parse = |url: &Option<String>| match url {
+            Some(url) => Service::parse(url).map(Some).ok_or_else(|| format!("invalid ICAP URL {:?}", url)),
+            None => Ok(None),
+        };
+        let reqmod = parse(&config.icap_reqmod_url)?;
+        let respmod = parse(&config.icap_respmod_url)?;
+
+        if reqmod.is_none() && respmod.is_none() {
+            return Ok(None);
+        }
+
+        Ok(Some(IcapClient {
+            reqmod,
+            respmod,
+            preview_bytes: config.icap_preview_bytes,
+            failure: config.icap_failure,
+        }))
+    }
+
+    pub fn scans_responses(&self) -> bool {
+        self.respmod.is_some()
+    }
+
+    pub async fn scan_request(
+        &self,
+        app_state: &AppState,
+        conn_key: &str,
+        req: Request<Body>,
+        url: &str,
+    ) -> Scanned<Request<Body>> {
+        let Some(service) = &self.reqmod else {
+            return Scanned::Allow(req);
+        };
+        let (parts, body) = req.into_parts();
+
+        let body = match collect(body, &parts.headers).await {
+            Ok(Collected::Complete(body)) => body,
+            Ok(Collected::TooLarge(body)) => {
+                return self.oversized(app_state, conn_key, "reqmod", url, || Scanned::Allow(Request::from_parts(parts, body)));
+            }
+            Err(e) => return Scanned::Deny(format!("request body could not be read: {}", e)),
+        };
+
+        let req_head = request_head(&parts, url);
+        let result = self.exchange(service, "REQMOD", &req_head, None, (!body.is_empty()).then_some(&body[..])).await;
+
+        let mut original = Some(Request::from_parts(parts, Body::from(body)));
+        let outcome = result.and_then(|response| match response.status {
+            204 => Ok(("clean", None, Scanned::Allow(original.take().unwrap()))),
+            200 => match (response.section("res-hdr"), response.section("req-hdr")) {
+                // A response in place of the request means the request was refused
+                (Some(head), _) => Ok(("blocked", response.threat(), Scanned::Respond(build_response(head, response.body.clone())?))),
+                (None, Some(head)) => Ok(("modified", response.threat(), Scanned::Allow(build_request(head, response.body.clone())?))),
+                (None, None) => Err(io::Error::new(io::ErrorKind::InvalidData, "ICAP 200 without an HTTP message")),
+            },
+            status => Err(io::Error::other(format!("ICAP server returned {}", status))),
+        });
+
+        self.conclude(app_state, conn_key, "reqmod", url, outcome, || {
+            Scanned::Allow(original.take().expect("original request is only taken on success"))
+        })
+    }
+
+    pub async fn scan_response(
+        &self,
+        app_state: &AppState,
+        conn_key: &str,
+        req_head: &[u8],
+        res: Response<Body>,
+        url: &str,
+    ) -> Scanned<Response<Body>> {
+        let Some(service) = &self.respmod else {
+            return Scanned::Allow(res);
+        };
+        let (parts, body) = res.into_parts();
+
+        let body = match collect(body, &parts.headers).await {
+            Ok(Collected::Complete(body)) => body,
+            Ok(Collected::TooLarge(body)) => {
+                return self.oversized(app_state, conn_key, "respmod", url, || Scanned::Allow(Response::from_parts(parts, body)));
+            }
+            Err(e) => return Scanned::Deny(format!("response body could not be read: {}", e)),
+        };
+
+        let res_head = response_head(&parts);
+        let result = self.exchange(service, "RESPMOD", req_head, Some(&res_head), (!body.is_empty()).then_some(&body[..])).await;
+
+        let mut original = Some(Response::from_parts(parts, Body::from(body)));
+        let outcome = result.and_then(|response| match response.status {
+            204 => Ok(("clean", None, Scanned::Allow(original.take().unwrap()))),
+            200 => {
+                let head = response.section("res-hdr")
+                    .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "ICAP 200 without an HTTP response"))?;
+                let modified = build_response(head, response.body.clone())?;
+                let threat = response.threat();
+                let verdict = if threat.is_some() || modified.status().is_client_error() { "blocked" } else { "modified" };
+                Ok((verdict, threat, Scanned::Allow(modified)))
+            }
+            status => Err(io::Error::other(format!("ICAP server returned {}", status))),
+        });
+
+        self.conclude(app_state, conn_key, "respmod", url, outcome, || {
+            Scanned::Allow(original.take().expect("original response is only taken on success"))
+        })
+    }
+
+    // A body over MAX_SCAN_BYTES cannot be scanned. With ICAP_FAILURE=closed it is refused
+    // like any other unscanned message, so size alone cannot get content past the scanner.
+    fn oversized<T>(
+        &self,
+        app_state: &AppState,
+        conn_key: &str,
+        mode: &str,
+        url: &str,
+        pass: impl FnOnce() -> Scanned<T>,
+    ) -> Scanned<T> {
+        let detail = format!("body larger than {} MiB", MAX_SCAN_BYTES / (1024 * 1024));
+        match self.failure {
+            IcapFailure::Open => {
+                record(app_state, conn_key, mode, url, "skipped", Some(detail));
+                pass()
+            }
+            IcapFailure::Closed => {
+                tracing::warn!("🛡️ ICAP {} refused {}: {}", mode, url, detail);
+                record(app_state, conn_key, mode, url, "blocked", Some(detail.clone()));
+                Scanned::Deny(format!("{} could not be scanned", detail))
+            }
+        }
+    }
+
+    // Record the verdict, applying ICAP_FAILURE when the exchange failed
+    fn conclude<T>(
+        &self,
+        app_state: &AppState,
+        conn_key: &str,
+        mode: &str,
+        url: &str,
+        outcome: io::Result<(&str, Option<String>, Scanned<T>)>,
+        fail_open: impl FnOnce() -> Scanned<T>,
+    ) -> Scanned<T> {
+        match outcome {
+            Ok((verdict, detail, scanned)) => {
+                match verdict {
+                    "blocked" => tracing::warn!("🛡️ ICAP {} blocked {} ({})", mode, url, detail.as_deref().unwrap_or("no detail")),
+                    _ => tracing::debug!("🛡️ ICAP {} {}: {}", mode, url, verdict),
+                }
+                record(app_state, conn_key, mode, url, verdict, detail);
+                scanned
+            }
+            Err(e) => {
+                tracing::error!("❌ ICAP {} failed for {}: {}", mode, url, e);
+                record(app_state, conn_key, mode, url, "error", Some(e.to_string()));
+                match self.failure {
+                    IcapFailure::Open => fail_open(),
+                    IcapFailure::Closed => Scanned::Deny("content scanning is unavailable".to_string()),
+                }
+            }
+        }
+    }
+
+    // One ICAP transaction: the encapsulated HTTP headers, then the body, sending only a
+    // preview first when the server may decide without the rest
+    async fn exchange(
+        &self,
+        service: &Service,
+        method: &str,
+        req_head: &[u8],
+        res_head: Option<&[u8]>,
+        body: Option<&[u8]>,
+    ) -> io::Result<IcapResponse> {
+        tokio::time::timeout(
+            Duration::from_secs(ICAP_TIMEOUT_SECS),
+            self.exchange_inner(service, method, req_head, res_head, body)
+        ).await
+        .map_err(|_| io::Error::new(io::ErrorKind::TimedOut, "ICAP timeout"))?
+    }
+
+    async fn exchange_inner(
+        &self,
+        service: &Service,
+        method: &str,
+        req_head: &[u8],
+        res_head: Option<&[u8]>,
+        body: Option<&[u8]>,
+    ) -> io::Result<IcapResponse> {
+        let mut stream = BufReader::new(TcpStream::connect(&service.addr).await?);
+
+        let mut sections = vec!["req-hdr=0".to_string()];
+        let mut offset = req_head.len();
+        if let Some(res_head) = res_head {
+            sections.push(format!("res-hdr={}", offset));
+            offset += res_head.len();
+        }
+        match (body, res_head) {
+            (Some(_), Some(_)) => sections.push(format!("res-body={}", offset)),
+            (Some(_), None) => sections.push(format!("req-body={}", offset)),
+            (None, _) => sections.push(format!("null-body={}", offset)),
+        }
+
+        let preview = body.filter(|_| self.preview_bytes > 0).map(|body| body.len().min(self.preview_bytes));
+
+        let mut message = format!(
+            "{} {} ICAP/1.0\r\nHost: {}\r\nAllow: 204\r\nEncapsulated: {}\r\n",
+            method, service.url, service.host, sections.join(", ")
+        );
+        if let Some(preview) = preview {
+            message.push_str(&format!("Preview: {}\r\n", preview));
+        }
+        message.push_str("\r\n");
+
+        let mut out = BytesMut::from(message.as_bytes());
+        out.extend_from_slice(req_head);
+        if let Some(res_head) = res_head {
+            out.extend_from_slice(res_head);
+        }
+
+        match (body, preview) {
+            (Some(body), Some(preview)) => {
+                write_chunk(&mut out, &body[..preview]);
+                if preview == body.len() {
+                    out.extend_from_slice(b"0; ieof\r\n\r\n");
+                } else {
+                    out.extend_from_slice(b"0\r\n\r\n");
+                    stream.get_mut().write_all(&out).await?;
+
+                    let response = read_response(&mut stream).await?;
+                    if response.status != 100 {
+                        return Ok(response);
+                    }
+                    out.clear();
+                    write_chunk(&mut out, &body[preview..]);
+                    out.extend_from_slice(b"0\r\n\r\n");
+                }
+            }
+            (Some(body), None) => {
+                write_chunk(&mut out, body);
+                out.extend_from_slice(b"0\r\n\r\n");
+            }
+            (None, _) => {}
+        }
+        stream.get_mut().write_all(&out).await?;
+
+        read_response(&mut stream).await
+    }
+}
+
+struct IcapResponse {
+    status: u16,
+    headers: Vec<(String, String)>,
+    sections: Vec<(String, usize)>, // Encapsulated entities and their offsets
+    head: Vec<u8>, // Encapsulated HTTP headers
+    body: Option<Vec<u8>>,
+}
+
+impl IcapResponse {
+    fn header(&self, name: &str) -> Option<&str> {
+        self.headers.iter()
+            .find(|(header, _)| header.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_str())
+    }
+
+    // The bytes of one encapsulated header section, e.g. `res-hdr`
+    fn section(&self, name: &str) -> Option<&[u8]> {
+        let index = self.sections.iter().position(|(section, _)| section == name)?;
+        let start = self.sections[index].1;
+        let end = self.sections.get(index + 1).map_or(self.head.len(), |(_, offset)| *offset);
+        self.head.get(start..end)
+    }
+
+    // What the scanner reported finding, e.g. the threat name from X-Infection-Found
+    fn threat(&self) -> Option<String> {
+        let value = INFECTION_HEADERS.iter().find_map(|name| self.header(name))?;
+        let threat = value.split(';')
+            .find_map(|field| field.trim().strip_prefix("Threat="))
+            .unwrap_or(value);
+        Some(threat.trim().to_string())
+    }
+}
+
+async fn read_response<R: AsyncBufRead + Unpin>(reader: &mut R) -> io::Result<IcapResponse> {
+    let status_line = read_line(reader).await?;
+    let status = status_line.split_whitespace()
+        .nth(1)
+        .and_then(|status| status.parse().ok())
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, format!("invalid ICAP status line {:?}", status_line)))?;
+
+    let mut headers = Vec::new();
+    loop {
+        let line = read_line(reader).await?;
+        if line.is_empty() {
+            break;
+        }
+        if let Some((name, value)) = line.split_once(':') {
+            headers.push((name.trim().to_string(), value.trim().to_string()));
+        }
+    }
+
+    let mut sections: Vec<(String, usize)> = headers.iter()
+        .find(|(name, _)| name.eq_ignore_ascii_case("encapsulated"))
+        .map(|(_, value)| {
+            value.split(',')
+                .filter_map(|entry| {
+                    let (name, offset) = entry.trim().split_once('=')?;
+                    Some((name.to_string(), offset.parse().ok()?))
+                })
+                .collect()
+        })
+        .unwrap_or_default();
+    sections.sort_by_key(|(_, offset)| *offset);
+
+    // Everything before the body entity is HTTP headers
+    let (head_len, has_body) = match sections.iter().find(|(name, _)| name.ends_with("-body")) {
+        Some((name, offset)) => (*offset, name != "null-body"),
+        None => (0, false),
+    };
+    if head_len > MAX_HEADER_LINE_BYTES {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, "ICAP headers too large"));
+    }
+    let mut head = vec![0; head_len];
+    reader.read_exact(&mut head).await?;
+
+    let body = match has_body {
+        true => Some(read_chunked(reader).await?),
+        false => None,
+    };
+
+    Ok(IcapResponse { status, headers, sections, head, body })
+}
+
+async fn read_line<R: AsyncBufRead + Unpin>(reader: &mut R) -> io::Result<String> {
+    let mut line = String::new();
+    let n = reader.take(MAX_HEADER_LINE_BYTES as u64).read_line(&mut line).await?;
+    if n == 0 {
+        return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "ICAP server closed the connection"));
+    }
+    Ok(line.trim_end_matches(['\r', '\n']).to_string())
+}
+
+async fn read_chunked<R: AsyncBufRead + Unpin>(reader: &mut R) -> io::Result<Vec<u8>> {
+    let mut body = Vec::new();
+
+    loop {
+        let line = read_line(reader).await?;
+        let size = line.split(';').next().unwrap_or_default().trim();
+        let size = usize::from_str_radix(size, 16)
+            .map_err(|_| io::Error::new(io::ErrorKind::InvalidData, format!("invalid chunk size {:?}", size)))?;
+
+        if size == 0 {
+            // Trailer lines, up to the blank line ending the message
+            while !read_line(reader).await?.is_empty() {}
+            return Ok(body);
+        }
+        if body.len() + size > MAX_SCAN_BYTES {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "ICAP body too large"));
+        }
+
+        let start = body.len();
+        body.resize(start + size, 0);
+        reader.read_exact(&mut body[start..]).await?;
+        read_line(reader).await?;
+    }
+}
+
+fn write_chunk(out: &mut BytesMut, data: &[u8]) {
+    if data.is_empty() {
+        return;
+    }
+    out.extend_from_slice(format!("{:x}\r\n", data.len()).as_bytes());
+    out.extend_from_slice(data);
+    out.extend_from_slice(b"\r\n");
+}
+
+fn record(app_state: &AppState, conn_key: &str, mode: &str, url: &str, verdict: &str, detail: Option<String>) {
+    if let Some(mut conn) = app_state.monitoring_state.get_mut(conn_key) {
+        if conn.scans.len() < MAX_RECORDED_SCANS {
+            conn.scans.push(ScanVerdict {
+                mode: mode.to_string(),
+                url: url.to_string(),
+                verdict: verdict.to_string(),
+                detail,
+            });
+        }
+    }
+}
+
+enum Collected {
+    Complete(Bytes),
+    TooLarge(Body), // The body as received, for passing through unscanned
+}
+
+// Read a body for scanning, unless it is larger than MAX_SCAN_BYTES
+async fn collect(body: Body, headers: &HeaderMap) -> Result<Collected, BoxError> {
+    let content_length = headers.get(header::CONTENT_LENGTH)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.parse::<usize>().ok());
+    if content_length.is_some_and(|length| length > MAX_SCAN_BYTES) {
+        return Ok(Collected::TooLarge(body));
+    }
+
+    let mut body = body;
+    let mut frames = VecDeque::new();
+    let mut size = 0;
+
+    while let Some(frame) = std::future::poll_fn(|cx| Pin::new(&mut body).poll_frame(cx)).await {
+        let frame = frame?;
+        size += frame.data_ref().map_or(0, Bytes::len);
+        frames.push_back(frame);
+
+        if size > MAX_SCAN_BYTES {
+            return Ok(Collected::TooLarge(Body::new(Prefetched::new(frames, body))));
+        }
+    }
+
+    let mut collected = BytesMut::with_capacity(size);
+    for frame in frames {
+        if let Ok(data) = frame.into_data() {
+            collected.extend_from_slice(&data);
+        }
+    }
+    Ok(Collected::Complete(collected.freeze()))
+}
+
+// HTTP/1.1 request headers as encapsulated for ICAP, with the absolute URL
+pub fn request_head(parts: &request::Parts, url: &str) -> Vec<u8> {
+    let mut head = format!("{} {} HTTP/1.1\r\n", parts.method, url).into_bytes();
+    write_headers(&mut head, &parts.headers);
+    head
+}
+
+fn response_head(parts: &response::Parts) -> Vec<u8> {
+    let mut head = format!(
+        "HTTP/1.1 {} {}\r\n",
+        parts.status.as_u16(),
+        parts.status.canonical_reason().unwrap_or_default()
+    ).into_bytes();
+    write_headers(&mut head, &parts.headers);
+    head
+}
+
+fn write_headers(head: &mut Vec<u8>, headers: &HeaderMap) {
+    for (name, value) in headers {
+        head.extend_from_slice(name.as_str().as_bytes());
+        head.extend_from_slice(b": ");
+        head.extend_from_slice(value.as_bytes());
+        head.extend_from_slice(b"\r\n");
+    }
+    head.extend_from_slice(b"\r\n");
+}
+
+// Framing headers are replaced, since the body is sent with a known length
+fn is_framing_header(name: &str) -> bool {
+    name.eq_ignore_ascii_case("content-length") || name.eq_ignore_ascii_case("transfer-encoding")
+}
+
+fn build_response(head: &[u8], body: Option<Vec<u8>>) -> io::Result<Response<Body>> {
+    let mut headers = [httparse::EMPTY_HEADER; 128];
+    let mut parsed = httparse::Response::new(&mut headers);
+    parsed.parse(head).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+
+    let mut builder = Response::builder().status(parsed.code.unwrap_or(200));
+    for header in parsed.headers.iter().filter(|header| !is_framing_header(header.name)) {
+        builder = builder.header(header.name, header.value);
+    }
+    let body = body.unwrap_or_default();
+
+    builder.header(header::CONTENT_LENGTH, body.len())
+        .body(Body::from(body))
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+}
+
+fn build_request(head: &[u8], body: Option<Vec<u8>>) -> io::Result<Request<Body>> {
+    let mut headers = [httparse::EMPTY_HEADER; 128];
+    let mut parsed = httparse::Request::new(&mut headers);
+    parsed.parse(head).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+
+    // The request goes to the intercepted server, so only the path is kept
+    let uri: Uri = parsed.path.unwrap_or("/").parse().map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+    let path = uri.path_and_query().map(|path| path.as_str()).unwrap_or("/").to_string();
+
+    let mut builder = Request::builder()
+        .method(parsed.method.unwrap_or("GET"))
+        .uri(path);
+    for header in parsed.headers.iter().filter(|header| !is_framing_header(header.name)) {
+        builder = builder.header(header.name, header.value);
+    }
+    let body = body.unwrap_or_default();
+    if !body.is_empty() {
+        builder = builder.header(header::CONTENT_LENGTH, body.len());
+    }
+
+    builder.body(Body::from(body))
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    async fn parse(raw: &[u8]) -> io::Result<IcapResponse> {
+        read_response(&mut BufReader::new(raw)).await
+    }
+
+    #[tokio::test]
+    async fn reads_a_no_modification_response() {
+        let response = parse(b"ICAP/1.0 204 No Content\r\nISTag: \"abc\"\r\nEncapsulated: null-body=0\r\n\r\n").await.unwrap();
+
+        assert_eq!(response.status, 204);
+        assert_eq!(response.header("istag"), Some("\"abc\""));
+        assert!(response.head.is_empty());
+        assert_eq!(response.body, None);
+    }
+
+    #[tokio::test]
+    async fn splits_encapsulated_sections_and_body() {
+        let req_hdr = "GET / HTTP/1.1\r\nHost: example.com\r\n\r\n";
+        let res_hdr = "HTTP/1.1 403 Forbidden\r\nContent-Type: text/plain\r\n\r\n";
+        let raw = format!(
+            "ICAP/1.0 200 OK\r\nEncapsulated: res-hdr={}, req-hdr=0, res-body={}\r\n\r\n{}{}7\r\nblocked\r\n0\r\n\r\n",
+            req_hdr.len(),
+            req_hdr.len() + res_hdr.len(),
+            req_hdr,
+            res_hdr,
+        );
+
+        let response = parse(raw.as_bytes()).await.unwrap();
+
+        assert_eq!(response.status, 200);
+        assert_eq!(response.section("req-hdr"), Some(req_hdr.as_bytes()));
+        assert_eq!(response.section("res-hdr"), Some(res_hdr.as_bytes()));
+        assert_eq!(response.body.as_deref(), Some(&b"blocked"[..]));
+    }
+
+    #[tokio::test]
+    async fn names_the_threat_a_scanner_found() {
+        let response = parse(
+            b"ICAP/1.0 200 OK\r\nX-Infection-Found: Type=0; Resolution=2; Threat=Eicar-Signature;\r\nEncapsulated: null-body=0\r\n\r\n"
+        ).await.unwrap();
+        assert_eq!(response.threat().as_deref(), Some("Eicar-Signature"));
+
+        let response = parse(b"ICAP/1.0 200 OK\r\nX-Virus-ID: Win.Test.EICAR\r\nEncapsulated: null-body=0\r\n\r\n").await.unwrap();
+        assert_eq!(response.threat().as_deref(), Some("Win.Test.EICAR"));
+
+        let response = parse(b"ICAP/1.0 204 No Content\r\n\r\n").await.unwrap();
+        assert_eq!(response.threat(), None);
+    }
+
+    #[tokio::test]
+    async fn rejects_malformed_responses() {
+        let error = parse(b"garbage\r\n\r\n").await.err().unwrap();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+
+        let error = parse(b"ICAP/1.0 200 OK\r\nEncapsulated: res-hdr=0, res-body=999999\r\n\r\n").await.err().unwrap();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+
+        let error = parse(b"ICAP/1.0 200 OK\r\nEncapsulated: res-body=0\r\n\r\n5\r\nab").await.err().unwrap();
+        assert_eq!(error.kind(), io::ErrorKind::UnexpectedEof);
+    }
+
+    #[tokio::test]
+    async fn reads_chunks_with_extensions_and_trailers() {
+        let raw = b"4;ieof\r\nWiki\r\nA\r\npedia in c\r\n0\r\nX-Trailer: 1\r\n\r\nnext";
+        let mut reader = BufReader::new(&raw[..]);
+
+        assert_eq!(read_chunked(&mut reader).await.unwrap(), b"Wikipedia in c");
+
+        let mut rest = Vec::new();
+        reader.read_to_end(&mut rest).await.unwrap();
+        assert_eq!(rest, b"next");
+
+        let error = read_chunked(&mut BufReader::new(&b"zz\r\n"[..])).await.unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[tokio::test]
+    async fn written_chunks_read_back() {
+        let mut out = BytesMut::new();
+        write_chunk(&mut out, b"hello ");
+        write_chunk(&mut out, b"");
+        write_chunk(&mut out, &[b'x'; 300]);
+        out.extend_from_slice(b"0\r\n\r\n");
+
+        assert!(out.starts_with(b"6\r\nhello \r\n12c\r\n"));
+        let body = read_chunked(&mut BufReader::new(&out[..])).await.unwrap();
+        assert_eq!(body, [&b"hello "[..], &[b'x'; 300]].concat());
+    }
+
+    #[test]
+    fn parses_service_urls() {
+        let service = Service::parse("icap://scanner.local/respmod").unwrap();
+        assert_eq!((service.host.as_str(), service.addr.as_str()), ("scanner.local", "scanner.local:1344"));
+
+        let service = Service::parse("icap://127.0.0.1:11344/avscan").unwrap();
+        assert_eq!(service.addr, "127.0.0.1:11344");
+
+        assert!(Service::parse("http://scanner.local/respmod").is_none());
+        assert!(Service::parse("icap://scanner.local:notaport/").is_none());
+        assert!(Service::parse("icap:///respmod").is_none());
+    }
+
+    #[test]
+    fn rebuilds_responses_with_their_new_length() {
+        let head = b"HTTP/1.1 200 OK\r\nContent-Type: text/plain\r\nTransfer-Encoding: chunked\r\nContent-Length: 99\r\n\r\n";
+
+        let response = build_response(head, Some(b"clean".to_vec())).unwrap();
+
+        assert_eq!(response.headers()[header::CONTENT_LENGTH], "5");
+        assert_eq!(response.headers()[header::CONTENT_TYPE], "text/plain");
+        assert!(!response.headers().contains_key(header::TRANSFER_ENCODING));
+    }
+}
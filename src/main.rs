@@ -7,14 +7,15 @@ use axum::{
     response::{
         IntoResponse, Response
     },
-    routing::{get},
+    routing::{delete, get, post},
     Router,
 };
 
 mod serve;
 use serve::{
     index_page,
-    notfound_page
+    notfound_page,
+    not_proxy_request
 };
 
 mod handlers;
@@ -25,38 +26,116 @@ use handlers::{
     },
     connections::{
         ConnectionInfo,
+        TerminateState,
+        Transferred,
+        TunnelHandle,
+        TunnelHandles,
         get_connections,
         get_active_connections,
+        is_terminal,
+        terminate_connection,
+        terminate_connections,
+    },
+    pools::get_pools,
+    cache::{get_cache, purge_cache},
+    pages::preview_page,
+    pac::get_pac,
+    auth::{read_api_credentials, require_api_auth},
+    policy::{
+        add_rule, delete_rule, discard_shadow, evaluate_policy, get_policy, get_shadow, get_version, list_versions,
+        load_shadow, move_rule, promote_shadow, replace_policy, rollback, validate_policy,
     },
 };
 
 use hyper::body::Incoming;
-use hyper::server::conn::http1;
-use hyper::upgrade::Upgraded;
+use hyper::server::conn::{http1, http2};
 use std::net::{SocketAddr, IpAddr};
 use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
 use std::time::Duration;
 use dashmap::DashMap;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
 use tokio::net::{TcpListener, TcpStream};
-use tokio::sync::{RwLock, Semaphore};
+use tokio::sync::{oneshot, RwLock, Semaphore};
 use tower::Service;
 use tower::ServiceExt;
 use tower_http::{
     trace::{self, TraceLayer}
 };
-use hyper_util::rt::TokioIo;
+use hyper_util::rt::{TokioExecutor, TokioIo, TokioTimer};
 use tracing_subscriber::{fmt, layer::SubscriberExt, util::SubscriberInitExt};
 use tracing::Level;
 use chrono::{Utc, Duration as ChronoDuration};
 
 mod read_txt;
-use read_txt::check_address_block;
+
+mod socks;
+use socks::{handle_socks5, SOCKS_VERSION};
+
+mod udp;
+use udp::{connect_udp, is_connect_udp};
+
+mod config;
+use config::Config;
+
+mod tls;
+use tls::SharedTlsConfig;
+
+mod http3;
+
+mod groups;
+use groups::ip_matches;
+
+mod routing;
+use routing::Route;
+
+mod pool;
+use pool::{PoolHealthState, PoolState};
+
+mod dns;
+use dns::Resolver;
+
+mod ports;
+
+mod sni;
+
+mod mitm;
+use mitm::Interceptor;
+
+mod filter;
+
+mod icap;
+use icap::IcapClient;
+
+mod protocol;
+use protocol::{CountingIo, Direction, Rewind, Sniffed, Sniffer};
+
+mod forward;
+
+mod cache;
+use cache::Cache;
+
+mod pages;
+use pages::{Page, PageDetails};
+
+mod pac;
+
+mod admin;
+
+mod policy;
+use policy::{Access, Evaluation, PolicyStore, Verdict};
+
+mod cli;
 use local_ip_address::local_ip;
 
 // Configuration constants
 const MAX_CONCURRENT_CONNECTIONS: usize = 1000;
 const CONNECTION_TIMEOUT_SECS: u64 = 30;
 const TUNNEL_TIMEOUT_SECS: u64 = 300;
+const UDP_IDLE_TIMEOUT_SECS: u64 = 60; // Close UDP flows after a minute without traffic
+const H2_MAX_CONCURRENT_STREAMS: u32 = 100; // Tunnels multiplexed over one HTTP/2 connection
+const H2_KEEP_ALIVE_SECS: u64 = 30; // Ping idle HTTP/2 clients to detect dead connections
+const H2_PREFACE: &[u8] = b"PRI * HTTP/2.0\r\n\r\nSM\r\n\r\n";
 const CLEANUP_INTERVAL_SECS: u64 = 300; // Clean up every 5 minutes
 const MAX_CONNECTION_AGE_HOURS: i64 = 24; // Keep connections for 24 hours
 const MAX_CONNECTIONS_TO_KEEP: usize = 10000; // Maximum connections to keep in memory
@@ -73,16 +152,57 @@ struct UserStats {
     last_seen: chrono::DateTime<chrono::Utc>,
 }
 
+// The client on the other end of a proxied connection
+#[derive(Clone, Debug)]
+struct ClientIdentity {
+    ip: String,
+    user: Option<String>, // Authenticated user (SOCKS5 username or client certificate)
+}
+
+impl ClientIdentity {
+    fn new(ip: String, user: Option<String>) -> Self {
+        ClientIdentity { ip, user }
+    }
+
+    // User stats are tracked per authenticated user, falling back to the client IP
+    fn stats_key(&self) -> &str {
+        self.user.as_deref().unwrap_or(&self.ip)
+    }
+}
+
+impl std::fmt::Display for ClientIdentity {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match &self.user {
+            Some(user) => write!(f, "{}@{}", user, self.ip),
+            None => write!(f, "{}", self.ip),
+        }
+    }
+}
+
 #[derive(Clone)]
 struct AppState {
     monitoring_state: OptimizedMonitoringState,
     user_stats_state: OptimizedUserStatsState,
     connection_semaphore: Arc<Semaphore>,
     router: Router,
+    config: Arc<Config>,
+    pool_state: PoolHealthState,
+    resolver: Arc<Resolver>,
+    interceptor: Option<Arc<Interceptor>>,
+    icap: Option<Arc<IcapClient>>,
+    cache: Option<Arc<Cache>>,
+    policy: Arc<PolicyStore>,
+    tunnels: TunnelHandles,
 }
 
 #[tokio::main]
 async fn main() {
+    // Subcommands such as `proxy policy test` run instead of the server
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    if let Some(code) = cli::run(&args) {
+        std::process::exit(code);
+    }
+
     // Initialize tracing with less verbose output to reduce CPU overhead
     tracing_subscriber::registry()
         .with(
@@ -95,52 +215,132 @@ async fn main() {
         )
         .init();
 
+    let config = Config::from_env();
+
     // Initialize optimized state with DashMap
     let monitoring_state: OptimizedMonitoringState = Arc::new(DashMap::new());
     let user_stats_state: OptimizedUserStatsState = Arc::new(DashMap::new());
     let connection_semaphore = Arc::new(Semaphore::new(MAX_CONCURRENT_CONNECTIONS));
+    let pool_state: PoolHealthState = Arc::new(PoolState::default());
+    let resolver = Arc::new(
+        Resolver::from_config(&config).unwrap_or_else(|e| panic!("Failed to configure DNS resolver: {}", e))
+    );
+    let interceptor = Interceptor::from_config(&config)
+        .unwrap_or_else(|e| panic!("Failed to load the TLS interception CA: {}", e))
+        .map(Arc::new);
+    let icap = IcapClient::from_config(&config)
+        .unwrap_or_else(|e| panic!("Failed to configure ICAP: {}", e))
+        .map(Arc::new);
+    let cache = Cache::from_config(&config)
+        .unwrap_or_else(|e| panic!("Failed to open the HTTP cache: {}", e))
+        .map(Arc::new);
 
     // Convert to legacy state types for handlers (if needed)
     let legacy_user_stats_state: UserStatsState = Arc::new(RwLock::new(std::collections::HashMap::new()));
 
+    let tunnels: TunnelHandles = Arc::new(DashMap::new());
     let monitoring_api = Router::new()
         .route("/connections", get(get_connections))
         .route("/active", get(get_active_connections))
-        .with_state(monitoring_state.clone());
+        .with_state(monitoring_state.clone())
+        .merge(Router::new()
+            .route("/connections", delete(terminate_connections))
+            .route("/connections/:id", delete(terminate_connection))
+            .with_state(TerminateState { monitoring_state: monitoring_state.clone(), tunnels: tunnels.clone() }));
 
     let stats_api = Router::new()
         .route("/stats", get(get_user_stats))
         .with_state(legacy_user_stats_state.clone());
 
+    let pools_api = Router::new()
+        .route("/pools", get(get_pools))
+        .with_state(pool_state.clone());
+
+    let cache_api = Router::new()
+        .route("/cache", get(get_cache).delete(purge_cache))
+        .with_state(cache.clone());
+
+    let policy = Arc::new(PolicyStore::new());
+    let policy_api = Router::new()
+        .route("/policy", get(get_policy).put(replace_policy))
+        .route("/policy/validate", post(validate_policy))
+        .route("/policy/rules", post(add_rule))
+        .route("/policy/rules/:position", delete(delete_rule))
+        .route("/policy/rules/:position/move", post(move_rule))
+        .route("/policy/versions", get(list_versions))
+        .route("/policy/versions/:version", get(get_version))
+        .route("/policy/versions/:version/rollback", post(rollback))
+        .route("/policy/shadow", get(get_shadow).put(load_shadow).delete(discard_shadow))
+        .route("/policy/shadow/promote", post(promote_shadow))
+        .with_state(policy.clone())
+        .merge(Router::new()
+            .route("/policy/evaluate", get(evaluate_policy))
+            .with_state(Arc::new(config.clone())));
+
     let api_routes = Router::new()
         .merge(monitoring_api)
-        .merge(stats_api);
-
-    let page_routes = Router::new()
-        .route("/", get(index_page));
+        .merge(stats_api)
+        .merge(pools_api)
+        .merge(cache_api)
+        .merge(policy_api)
+        .route("/pages/:page", get(preview_page))
+        .layer(axum::middleware::from_fn(require_api_auth));
+
+    let pac_routes = Router::new()
+        .route("/proxy.pac", get(get_pac))
+        .route("/wpad.dat", get(get_pac))
+        .with_state(Arc::new(config.clone()));
+
+    // Pages for proxy clients, served on the proxy port as well as the admin listener
+    let client_routes = Router::new()
+        .route("/", get(index_page))
+        .merge(pac_routes);
+
+    let trace_layer = TraceLayer::new_for_http()
+        .make_span_with(
+            trace::DefaultMakeSpan::new()
+                .level(Level::WARN)
+        )
+        .on_response(
+            trace::DefaultOnResponse::new().level(Level::WARN)
+        );
 
-    let router = Router::new()
-        .merge(page_routes)
+    let admin_router = Router::new()
+        .merge(client_routes.clone())
         .nest("/api", api_routes)
         .fallback(notfound_page)
-        .layer(
-            TraceLayer::new_for_http()
-                .make_span_with(
-                    trace::DefaultMakeSpan::new()
-                        .level(Level::WARN)
-                )
-                .on_response(
-                    trace::DefaultOnResponse::new().level(Level::WARN)
-                ),
-        );
+        .layer(trace_layer.clone());
+
+    // Anything else reaching the proxy port is not a proxy request
+    let router = client_routes
+        .fallback(not_proxy_request)
+        .layer(trace_layer);
 
     let app_state = AppState {
         monitoring_state: monitoring_state.clone(),
         user_stats_state: user_stats_state.clone(),
         connection_semaphore,
         router,
+        config: Arc::new(config.clone()),
+        pool_state: pool_state.clone(),
+        resolver: resolver.clone(),
+        interceptor,
+        icap,
+        cache,
+        policy,
+        tunnels,
     };
 
+    let admin_addr = config.admin_addr.clone();
+    if !admin_addr.is_local() && read_api_credentials().is_empty() {
+        panic!("Refusing to serve the admin API on {} without credentials; add them to api_access.txt or bind ADMIN_ADDR to a loopback address", admin_addr);
+    }
+    tokio::spawn(async move {
+        if let Err(e) = admin::serve_admin(admin_addr.clone(), admin_router).await {
+            tracing::error!("❌ Admin listener on {} failed: {}", admin_addr, e);
+        }
+    });
+
     // Start the cleanup task
     let cleanup_monitoring_state = monitoring_state.clone();
     let cleanup_user_stats_state = user_stats_state.clone();
@@ -148,19 +348,69 @@ async fn main() {
         cleanup_old_connections(cleanup_monitoring_state, cleanup_user_stats_state).await;
     });
 
+    // Start active health checks for upstream pools with a probe target
+    tokio::spawn(pool::run_health_checks(pool_state, resolver));
+
     // Bind to all interfaces (0.0.0.0) for flexibility
-    let port = 8080;
+    let port = config.proxy_port;
     let addr = SocketAddr::from(([0, 0, 0, 0], port));
     let local_ip = local_ip().unwrap();
     tracing::info!("🚀 Proxy server listening on {}, \nNetwork: {:?}:{}", addr, local_ip, port);
+    tracing::info!("🛠️ Admin API listening on {}", config.admin_addr);
     tracing::info!("📊 Monitor endpoints:");
     tracing::info!("  - GET /api/connections - All connections");
+    tracing::info!("  - DELETE /api/connections/:id - Terminate a tunnel (or ?client= / ?target= for several)");
     tracing::info!("  - GET /api/stats - Statistics");
     tracing::info!("  - GET /api/active - Active connections");
+    tracing::info!("  - GET /api/pools - Upstream pool health");
+    tracing::info!("  - GET/DELETE /api/cache - HTTP cache statistics and purging");
+    tracing::info!("  - GET /api/pages/:page - Block and error page preview");
+    tracing::info!("  - GET/PUT /api/policy - Blocked sites rules, with /rules, /validate, /evaluate, /versions and /shadow");
+    tracing::info!("🧭 Proxy auto-config at /proxy.pac and /wpad.dat");
+    if read_api_credentials().is_empty() {
+        tracing::warn!("⚠️ The /api endpoints on {} are open to every local user; add credentials to api_access.txt", config.admin_addr);
+    }
+    tracing::info!("🧦 SOCKS5 clients accepted on the same port (CONNECT and UDP ASSOCIATE)");
+    tracing::info!("📦 CONNECT-UDP (RFC 9298) at /.well-known/masque/udp/{{host}}/{{port}}/");
     tracing::info!("⚙️  Max concurrent connections: {}", MAX_CONCURRENT_CONNECTIONS);
     tracing::info!("🧹 Connection cleanup: every {} seconds, max age {} hours",
         CLEANUP_INTERVAL_SECS, MAX_CONNECTION_AGE_HOURS);
-    tracing::info!("🔒 Running behind Nginx proxy");
+
+    if config.tls_enabled() {
+        let server_config = tls::load_server_config(&config)
+            .unwrap_or_else(|e| panic!("Failed to load TLS certificate: {}", e));
+        let tls_config: SharedTlsConfig = Arc::new(std::sync::RwLock::new(Arc::new(server_config)));
+
+        let watch_tls_config = tls_config.clone();
+        let watch_config = config.clone();
+        tokio::spawn(async move {
+            tls::watch_certificates(watch_tls_config, watch_config).await;
+        });
+
+        if let Some(h3_port) = config.h3_port {
+            let h3_addr = SocketAddr::from(([0, 0, 0, 0], h3_port));
+            let h3_tls_config = tls_config.clone();
+            let h3_app_state = app_state.clone();
+            tracing::info!("⚡ HTTP/3 (QUIC) proxy listening on udp/{}", h3_addr);
+
+            tokio::spawn(async move {
+                if let Err(e) = http3::serve_http3(h3_addr, h3_tls_config, h3_app_state).await {
+                    tracing::error!("❌ HTTP/3 listener failed: {}", e);
+                }
+            });
+        }
+
+        let tls_addr = SocketAddr::from(([0, 0, 0, 0], config.tls_port));
+        let tls_listener = TcpListener::bind(tls_addr).await.unwrap();
+        tracing::info!("🔒 HTTPS proxy listening on {}", tls_addr);
+
+        let tls_app_state = app_state.clone();
+        tokio::spawn(async move {
+            serve_tls(tls_listener, tls_config, tls_app_state).await;
+        });
+    } else {
+        tracing::info!("🔓 TLS listener disabled (set TLS_CERT_FILE and TLS_KEY_FILE to enable)");
+    }
 
     let listener = TcpListener::bind(addr).await.unwrap();
 
@@ -189,7 +439,64 @@ async fn main() {
         tokio::spawn(async move {
             let _permit = permit;
 
-            if let Err(e) = handle_connection(stream, client_ip, app_state).await {
+            if let Err(e) = handle_stream(stream, client_ip, app_state).await {
+                tracing::warn!("❌ Connection error from {}: {:?}", client_ip, e);
+            }
+        });
+    }
+}
+
+// Accept loop for the TLS listener; clients use an https:// proxy URL
+async fn serve_tls(listener: TcpListener, tls_config: SharedTlsConfig, app_state: AppState) {
+    loop {
+        let (stream, client_addr) = match listener.accept().await {
+            Ok(pair) => pair,
+            Err(e) => {
+                tracing::error!("Failed to accept TLS connection: {:?}", e);
+                continue;
+            }
+        };
+
+        let permit = match app_state.connection_semaphore.clone().try_acquire_owned() {
+            Ok(permit) => permit,
+            Err(_) => {
+                tracing::warn!("🚫 Connection limit reached, rejecting connection from {}", client_addr.ip());
+                drop(stream);
+                continue;
+            }
+        };
+
+        let acceptor = tls::acceptor(&tls_config);
+        let app_state = app_state.clone();
+        let client_ip = client_addr.ip();
+
+        tokio::spawn(async move {
+            let _permit = permit;
+
+            let tls_stream = match tokio::time::timeout(
+                Duration::from_secs(CONNECTION_TIMEOUT_SECS),
+                acceptor.accept(stream)
+            ).await {
+                Ok(Ok(tls_stream)) => tls_stream,
+                Ok(Err(e)) => {
+                    tracing::warn!("❌ TLS handshake failed from {}: {}", client_ip, e);
+                    return;
+                }
+                Err(_) => {
+                    tracing::warn!("⏱️ TLS handshake timeout from {}", client_ip);
+                    return;
+                }
+            };
+
+            // With mutual TLS the client certificate names the user
+            let user = tls::client_identity(tls_stream.get_ref().1.peer_certificates(), &app_state.config);
+            if let Some(user) = &user {
+                tracing::debug!("TLS client {} authenticated by certificate as {}", client_ip, user);
+            }
+
+            let http2 = tls_stream.get_ref().1.alpn_protocol() == Some(b"h2");
+
+            if let Err(e) = handle_connection(tls_stream, client_ip, user, http2, app_state).await {
                 tracing::warn!("❌ Connection error from {}: {:?}", client_ip, e);
             }
         });
@@ -217,10 +524,9 @@ async fn cleanup_old_connections(
         for entry in monitoring_state.iter() {
             let conn_info = entry.value();
 
-            // Remove connections older than cutoff_time, or if we have too many connections
+            // Remove connections older than cutoff_time, or finished ones if we have too many connections
             if conn_info.timestamp < cutoff_time ||
-               (monitoring_state.len() > MAX_CONNECTIONS_TO_KEEP &&
-                (conn_info.status == "completed" || conn_info.status == "failed" || conn_info.status == "blocked")) {
+               (monitoring_state.len() > MAX_CONNECTIONS_TO_KEEP && is_terminal(&conn_info.status)) {
                 keys_to_remove.push(entry.key().clone());
             }
         }
@@ -263,57 +569,173 @@ async fn cleanup_old_connections(
     }
 }
 
-// Extract real client IP from proxy headers
-fn get_real_client_ip(headers: &HeaderMap) -> Option<IpAddr> {
+// The client's IP: the TCP peer, or the address a trusted reverse proxy (TRUSTED_PROXIES)
+// reports in X-Real-IP or X-Forwarded-For. Any other peer could claim another client's
+// address, and with it that client's groups, ports and routes.
+fn real_client_ip(headers: &HeaderMap, peer: IpAddr, trusted_proxies: &[String]) -> IpAddr {
+    let trusted = |ip: IpAddr| trusted_proxies.iter().any(|proxy| ip_matches(proxy, ip));
+    if !trusted(peer) {
+        return peer;
+    }
+
     // Try X-Real-IP first (set by Nginx)
-    if let Some(real_ip) = headers.get("x-real-ip") {
-        if let Ok(ip_str) = real_ip.to_str() {
-            if let Ok(ip) = ip_str.parse::<IpAddr>() {
-                return Some(ip);
-            }
-        }
+    if let Some(ip) = headers.get("x-real-ip")
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.trim().parse::<IpAddr>().ok())
+    {
+        return ip;
     }
 
-    // Try X-Forwarded-For as fallback
-    if let Some(forwarded_for) = headers.get("x-forwarded-for") {
-        if let Ok(forwarded_str) = forwarded_for.to_str() {
-            // Take the first IP in the list (original client)
-            if let Some(first_ip) = forwarded_str.split(',').next() {
-                if let Ok(ip) = first_ip.trim().parse::<IpAddr>() {
-                    return Some(ip);
-                }
-            }
+    // X-Forwarded-For as fallback: the nearest address not added by a trusted proxy, as
+    // entries further left were written by the client itself
+    let forwarded_for: Vec<&str> = headers.get_all("x-forwarded-for")
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .collect();
+    let mut client_ip = peer;
+    for entry in forwarded_for.iter().rev() {
+        let Ok(ip) = entry.trim().parse::<IpAddr>() else {
+            break;
+        };
+        client_ip = ip;
+        if !trusted(ip) {
+            break;
         }
     }
-
-    None
+    client_ip
 }
 
-async fn handle_connection(
+// Sniff the first bytes to tell SOCKS5 and HTTP/2 prior-knowledge (h2c) clients
+// apart from HTTP/1 on the shared listener
+async fn handle_stream(
     stream: TcpStream,
     client_ip: IpAddr,
     app_state: AppState,
 ) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
-    let client_ip_str = client_ip.to_string();
+    let mut first_byte = [0u8; 1];
+    let peeked = tokio::time::timeout(
+        Duration::from_secs(CONNECTION_TIMEOUT_SECS),
+        stream.peek(&mut first_byte)
+    ).await??;
+
+    if peeked == 1 && first_byte[0] == SOCKS_VERSION {
+        return handle_socks5(stream, client_ip, app_state).await;
+    }
+
+    let mut stream = stream;
+    let preface = if peeked == 1 && first_byte[0] == H2_PREFACE[0] {
+        tokio::time::timeout(
+            Duration::from_secs(CONNECTION_TIMEOUT_SECS),
+            read_h2_preface(&mut stream)
+        ).await??
+    } else {
+        Vec::new()
+    };
+
+    let http2 = preface == H2_PREFACE;
+    handle_connection(Rewind::new(stream, preface), client_ip, None, http2, app_state).await
+}
+
+// Read the client's first bytes for as long as they match the HTTP/2 preface. Returns
+// the bytes read, which still have to reach the HTTP server.
+async fn read_h2_preface<T: AsyncRead + Unpin>(stream: &mut T) -> std::io::Result<Vec<u8>> {
+    let mut preface = Vec::with_capacity(H2_PREFACE.len());
+    let mut buf = [0u8; H2_PREFACE.len()];
+
+    while preface.len() < H2_PREFACE.len() {
+        // Never past the preface, so HTTP/1 bytes are not consumed needlessly
+        let read = stream.read(&mut buf[..H2_PREFACE.len() - preface.len()]).await?;
+        if read == 0 {
+            break;
+        }
+        preface.extend_from_slice(&buf[..read]);
+        if !H2_PREFACE.starts_with(&preface) {
+            break;
+        }
+    }
+
+    Ok(preface)
+}
+
+// Requests in flight on an HTTP/2 connection, and when the last one started or finished
+struct H2Activity {
+    opened: tokio::time::Instant,
+    in_flight: AtomicU64,
+    last_ms: AtomicU64, // Since `opened`
+}
+
+impl H2Activity {
+    fn new() -> Self {
+        H2Activity { opened: tokio::time::Instant::now(), in_flight: AtomicU64::new(0), last_ms: AtomicU64::new(0) }
+    }
+
+    fn begin(self: &Arc<Self>) -> InFlight {
+        self.in_flight.fetch_add(1, Ordering::Relaxed);
+        self.touch();
+        InFlight(self.clone())
+    }
 
+    fn touch(&self) {
+        self.last_ms.store(self.opened.elapsed().as_millis() as u64, Ordering::Relaxed);
+    }
+
+    // When the connection will have been idle for `idle`, unless a request comes in first
+    fn idle_at(&self, idle: Duration) -> tokio::time::Instant {
+        if self.in_flight.load(Ordering::Relaxed) > 0 {
+            return tokio::time::Instant::now() + idle;
+        }
+        self.opened + Duration::from_millis(self.last_ms.load(Ordering::Relaxed)) + idle
+    }
+}
+
+struct InFlight(Arc<H2Activity>);
+
+impl Drop for InFlight {
+    fn drop(&mut self) {
+        self.0.in_flight.fetch_sub(1, Ordering::Relaxed);
+        self.0.touch();
+    }
+}
+
+async fn handle_connection<I>(
+    stream: I,
+    client_ip: IpAddr,
+    user: Option<String>,
+    http2: bool,
+    app_state: AppState,
+) -> Result<(), Box<dyn std::error::Error + Send + Sync>>
+where
+    I: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
     let tower_service = tower::service_fn(move |req: Request<_>| {
         let app_state = app_state.clone();
-        let client_ip_str = client_ip_str.clone();
+        let user = user.clone();
         let req = req.map(Body::new);
 
         async move {
-            if req.method() == Method::CONNECT {
-                // Get real client IP from proxy headers
-                let real_client_ip = get_real_client_ip(req.headers())
-                    .map(|ip| ip.to_string())
-                    .unwrap_or_else(|| client_ip_str.clone());
-
-                proxy(req, app_state, real_client_ip).await
+            let client = ClientIdentity::new(real_client_ip(req.headers(), client_ip, &app_state.config.trusted_proxies).to_string(), user);
+
+            if is_connect_udp(&req) {
+                connect_udp(req, app_state, client).await
+            } else if req.extensions().get::<hyper::ext::Protocol>().is_some() {
+                // Extended CONNECT (RFC 8441) for anything other than connect-udp
+                Ok((
+                    StatusCode::NOT_IMPLEMENTED,
+                    "Unsupported extended CONNECT protocol",
+                ).into_response())
+            } else if req.method() == Method::CONNECT {
+                proxy(req, app_state, client).await
+            } else if forward::is_forward_request(&req) {
+                forward::forward_http(req, app_state, client).await
             } else {
                 // Check if this is an HTTP request that should be redirected to HTTPS
                 if let Some(proto) = req.headers().get("x-forwarded-proto") {
-                    if proto == "http" && !req.uri().path().starts_with("/api") && !req.uri().path().starts_with("/.well-known") {
-                        // Redirect HTTP to HTTPS for web requests (not API)
+                    // PAC and WPAD fetchers often do not follow redirects
+                    let path = req.uri().path();
+                    let redirected = !path.starts_with("/.well-known") && path != "/proxy.pac" && path != "/wpad.dat";
+                    if proto == "http" && redirected {
+                        // Redirect HTTP to HTTPS for web requests
                         let host = req.headers().get("host")
                             .and_then(|h| h.to_str().ok())
                             .unwrap_or("prxy.villebiz.com");
@@ -328,6 +750,10 @@ async fn handle_connection(
                     }
                 }
 
+                // Pages such as the PAC script are tailored to the client
+                let mut req = req;
+                req.extensions_mut().insert(client);
+
                 app_state.router.clone().oneshot(req)
                     .await
                     .map_err(|err| {
@@ -344,16 +770,60 @@ async fn handle_connection(
 
     let io = TokioIo::new(stream);
 
-    let serve_future = http1::Builder::new()
+    if http2 {
+        let activity = Arc::new(H2Activity::new());
+        let counted_activity = activity.clone();
+        let counted_service = hyper::service::service_fn(move |request: Request<Incoming>| {
+            let in_flight = counted_activity.begin();
+            let response = hyper::service::Service::call(&hyper_service, request);
+            async move {
+                let response = response.await;
+                drop(in_flight);
+                response
+            }
+        });
+
+        // Keep-alive pings drop peers that have gone away. hyper handles per-stream flow
+        // control, so a slow tunnel only stalls its own stream.
+        let connection = http2::Builder::new(TokioExecutor::new())
+            .timer(TokioTimer::new())
+            .enable_connect_protocol()
+            .max_concurrent_streams(H2_MAX_CONCURRENT_STREAMS)
+            .keep_alive_interval(Duration::from_secs(H2_KEEP_ALIVE_SECS))
+            .keep_alive_timeout(Duration::from_secs(H2_KEEP_ALIVE_SECS))
+            .serve_connection(io, counted_service);
+        tokio::pin!(connection);
+
+        // Without requests for a while, stop accepting streams. CONNECT tunnels and response
+        // bodies that are still running finish first; they are limited on their own.
+        let idle = Duration::from_secs(CONNECTION_TIMEOUT_SECS);
+        loop {
+            let idle_at = activity.idle_at(idle);
+            tokio::select! {
+                served = connection.as_mut() => return Ok(served?),
+                _ = tokio::time::sleep_until(idle_at) => {
+                    if activity.idle_at(idle) <= tokio::time::Instant::now() {
+                        connection.as_mut().graceful_shutdown();
+                        break;
+                    }
+                }
+            }
+        }
+        connection.await?;
+
+        return Ok(());
+    }
+
+    // Only waiting for a request head is limited, which also closes idle keep-alive
+    // connections; forwarded responses may stream for as long as they take
+    http1::Builder::new()
+        .timer(TokioTimer::new())
+        .header_read_timeout(Duration::from_secs(CONNECTION_TIMEOUT_SECS))
         .preserve_header_case(true)
         .title_case_headers(true)
         .serve_connection(io, hyper_service)
-        .with_upgrades();
-
-    tokio::time::timeout(
-        Duration::from_secs(CONNECTION_TIMEOUT_SECS),
-        serve_future
-    ).await??;
+        .with_upgrades()
+        .await?;
 
     Ok(())
 }
@@ -361,7 +831,7 @@ async fn handle_connection(
 async fn proxy(
     req: Request,
     app_state: AppState,
-    client_ip: String
+    client: ClientIdentity,
 ) -> Result<Response, hyper::Error> {
     let headers = req.headers().clone();
     let user_agent = headers.get("user-agent")
@@ -376,118 +846,56 @@ async fn proxy(
     );
 
     if let Some(host_addr) = req.uri().authority().map(|auth| auth.to_string()) {
-        let timestamp = Utc::now();
-
-        // Check if address should be blocked
-        if check_address_block(&host_addr) {
-            tracing::warn!("🚫 BLOCKED: {} attempting to connect to {}", client_ip, host_addr);
-
-            update_user_stats_optimized(&app_state.user_stats_state, &client_ip, true).await;
-
-            let conn_info = ConnectionInfo {
-                client_ip: client_ip.clone(),
-                target_host: host_addr.clone(),
-                timestamp,
-                user_agent: user_agent.clone(),
-                bytes_sent: 0,
-                bytes_received: 0,
-                status: "blocked".to_string(),
-                duration_ms: Some(0),
-            };
-
-            let key = format!("{}_{}", client_ip, timestamp.timestamp_millis());
-            app_state.monitoring_state.insert(key, conn_info);
-
-            return Ok(Response::builder()
-                .status(StatusCode::FORBIDDEN)
-                .header("Content-Type", "text/html")
-                .body(Body::from(format!(
-                    r#"<!DOCTYPE html>
-                    <html><head><title>Access Denied</title></head>
-                    <body><h1>🚫 Access Denied</h1>
-                    <p>Connection to <strong>{}</strong> blocked by policy.</p>
-                    <p>Your IP: {}</p><p>Timestamp: {}</p></body></html>"#,
-                    host_addr, client_ip, timestamp.format("%Y-%m-%d %H:%M:%S UTC")
-                )))
-                .unwrap());
+        // Blocked sites first, then only allowlisted ports may be tunnelled, so the proxy
+        // is not an open TCP relay
+        let evaluation = check_policy(&app_state, &client, &host_addr, Access::Connect);
+
+        match evaluation.verdict {
+            Verdict::Blocked => {
+                let conn_key = record_blocked(&app_state, &client, &host_addr, user_agent, "tcp", "blocked").await;
+                return Ok(pages::render(Page::Blocked, PageDetails {
+                    rule: evaluation.rule,
+                    ..PageDetails::for_connection(&client, &host_addr, &conn_key)
+                }, &headers));
+            }
+            Verdict::PortDenied | Verdict::DestinationDenied => {
+                let outcome = evaluation.verdict.as_str();
+                let conn_key = record_blocked(&app_state, &client, &host_addr, user_agent, "tcp", outcome).await;
+                return Ok(pages::render(Page::Blocked, PageDetails {
+                    rule: evaluation.rule,
+                    reason: evaluation.verdict.reason().map(str::to_string),
+                    ..PageDetails::for_connection(&client, &host_addr, &conn_key)
+                }, &headers));
+            }
+            Verdict::Allowed => {}
         }
 
-        tracing::info!("✅ ALLOWED: {} → {}", client_ip, host_addr);
-
-        let conn_key = format!("{}_{}", client_ip, timestamp.timestamp_millis());
-        let conn_info = ConnectionInfo {
-            client_ip: client_ip.clone(),
-            target_host: host_addr.clone(),
-            timestamp,
-            user_agent,
-            bytes_sent: 0,
-            bytes_received: 0,
-            status: "active".to_string(),
-            duration_ms: None,
-        };
+        let conn_key = record_allowed(&app_state, &client, &host_addr, user_agent, "tcp").await;
 
-        app_state.monitoring_state.insert(conn_key.clone(), conn_info);
-        update_user_stats_optimized(&app_state.user_stats_state, &client_ip, false).await;
+        tokio::task::spawn(async move {
+            terminable(&app_state, &conn_key, &client, &host_addr, async {
+                match hyper::upgrade::on(req).await {
+                    Ok(upgraded) => {
+                        let start_time = Utc::now();
 
-        let monitoring_state = app_state.monitoring_state.clone();
-        let user_stats_state = app_state.user_stats_state.clone();
+                        let tunnel_result = tokio::time::timeout(
+                            Duration::from_secs(TUNNEL_TIMEOUT_SECS),
+                            tunnel(TokioIo::new(upgraded), &app_state, &conn_key, &client, &host_addr)
+                        ).await;
 
-        tokio::task::spawn(async move {
-            match hyper::upgrade::on(req).await {
-                Ok(upgraded) => {
-                    let start_time = Utc::now();
-
-                    let tunnel_result = tokio::time::timeout(
-                        Duration::from_secs(TUNNEL_TIMEOUT_SECS),
-                        tunnel(upgraded, host_addr.clone())
-                    ).await;
-
-                    match tunnel_result {
-                        Ok(Ok((bytes_sent, bytes_received))) => {
-                            let duration = Utc::now().signed_duration_since(start_time);
-                            let duration_ms = duration.num_milliseconds().max(0) as u64;
-
-                            tracing::info!("✅ Tunnel completed: {} → {} | ⬆️ {} bytes ⬇️ {} bytes | ⏱️ {}ms",
-                                client_ip, host_addr, bytes_sent, bytes_received, duration_ms);
-
-                            if let Some(mut conn) = monitoring_state.get_mut(&conn_key) {
-                                conn.bytes_sent = bytes_sent;
-                                conn.bytes_received = bytes_received;
-                                conn.duration_ms = Some(duration_ms);
-                                conn.status = "completed".to_string();
-                            }
-
-                            update_user_stats_bytes(&user_stats_state, &client_ip, bytes_sent + bytes_received).await;
-                        }
-                        Ok(Err(e)) => {
-                            tracing::error!("❌ Tunnel error: {} → {} | Error: {}", client_ip, host_addr, e);
-                            if let Some(mut conn) = monitoring_state.get_mut(&conn_key) {
-                                conn.status = "failed".to_string();
-                                conn.duration_ms = Some(0);
-                            }
-                        }
-                        Err(_) => {
-                            tracing::warn!("⏱️ Tunnel timeout: {} → {}", client_ip, host_addr);
-                            if let Some(mut conn) = monitoring_state.get_mut(&conn_key) {
-                                conn.status = "timeout".to_string();
-                                conn.duration_ms = Some(TUNNEL_TIMEOUT_SECS * 1000);
-                            }
-                        }
+                        record_tunnel_result(&app_state, &conn_key, &client, &host_addr, start_time, tunnel_result).await;
                     }
-                }
-                Err(e) => {
-                    tracing::warn!("❌ Upgrade error: {} → {} | Error: {}", client_ip, host_addr, e);
-                    if let Some(mut conn) = monitoring_state.get_mut(&conn_key) {
-                        conn.status = "failed".to_string();
-                        conn.duration_ms = Some(0);
+                    Err(e) => {
+                        tracing::warn!("❌ Upgrade error: {} → {} | Error: {}", client, host_addr, e);
+                        mark_connection_failed(&app_state.monitoring_state, &conn_key);
                     }
                 }
-            }
+            }).await;
         });
 
         Ok(Response::new(Body::empty()))
     } else {
-        tracing::warn!("⚠️ Invalid CONNECT request from {}: {:?}", client_ip, req.uri());
+        tracing::warn!("⚠️ Invalid CONNECT request from {}: {:?}", client, req.uri());
         Ok((
             StatusCode::BAD_REQUEST,
             "CONNECT must be to a socket address",
@@ -495,17 +903,371 @@ async fn proxy(
     }
 }
 
-async fn tunnel(upgraded: Upgraded, addr: String) -> std::io::Result<(u64, u64)> {
-    let mut server = tokio::time::timeout(
+// Monitoring key for a new connection record; the sequence number keeps keys unique
+// when one client opens several connections (or UDP flows) within the same millisecond
+fn connection_key(client_ip: &str, timestamp: chrono::DateTime<Utc>) -> String {
+    static SEQUENCE: AtomicU64 = AtomicU64::new(0);
+    let seq = SEQUENCE.fetch_add(1, Ordering::Relaxed);
+    format!("{}_{}_{}", client_ip, timestamp.timestamp_millis(), seq)
+}
+
+// The live policy decision for a connection, compared with the shadow rule set when one
+// is loaded. Every front end decides through here.
+fn check_policy(app_state: &AppState, client: &ClientIdentity, host_addr: &str, access: Access) -> Evaluation {
+    let evaluation = policy::evaluate(client, host_addr, access, &app_state.config);
+    app_state.policy.shadow_check(client, &evaluation);
+    evaluation
+}
+
+// Record a connection attempt refused by policy and return its monitoring key; `outcome`
+// is the recorded status ("blocked" for the site list, "port_denied" for the CONNECT port allowlist)
+async fn record_blocked(
+    app_state: &AppState,
+    client: &ClientIdentity,
+    host_addr: &str,
+    user_agent: Option<String>,
+    transport: &str,
+    outcome: &str,
+) -> String {
+    tracing::warn!("🚫 BLOCKED ({}): {} attempting to connect to {}", outcome, client, host_addr);
+
+    update_user_stats_optimized(&app_state.user_stats_state, client.stats_key(), true).await;
+
+    let timestamp = Utc::now();
+    let conn_info = ConnectionInfo::new(
+        client.ip.clone(),
+        client.user.clone(),
+        host_addr.to_string(),
+        timestamp,
+        user_agent,
+        transport,
+        outcome,
+    );
+
+    let conn_key = connection_key(&client.ip, timestamp);
+    app_state.monitoring_state.insert(conn_key.clone(), conn_info);
+    conn_key
+}
+
+// Record an allowed connection as active and return its monitoring key
+async fn record_allowed(
+    app_state: &AppState,
+    client: &ClientIdentity,
+    host_addr: &str,
+    user_agent: Option<String>,
+    transport: &str,
+) -> String {
+    tracing::info!("✅ ALLOWED: {} → {}", client, host_addr);
+
+    let timestamp = Utc::now();
+    let conn_key = connection_key(&client.ip, timestamp);
+    let conn_info = ConnectionInfo::new(
+        client.ip.clone(),
+        client.user.clone(),
+        host_addr.to_string(),
+        timestamp,
+        user_agent,
+        transport,
+        "active",
+    );
+
+    app_state.monitoring_state.insert(conn_key.clone(), conn_info);
+    update_user_stats_optimized(&app_state.user_stats_state, client.stats_key(), false).await;
+
+    conn_key
+}
+
+// Mark an active connection as failed, keeping a more specific outcome already recorded
+fn mark_connection_failed(monitoring_state: &OptimizedMonitoringState, conn_key: &str) {
+    if let Some(mut conn) = monitoring_state.get_mut(conn_key) {
+        if conn.status == "active" {
+            conn.status = "failed".to_string();
+            conn.duration_ms = Some(0);
+        }
+    }
+}
+
+// Update the connection record and user stats once a tunnel has finished
+async fn record_tunnel_result(
+    app_state: &AppState,
+    conn_key: &str,
+    client: &ClientIdentity,
+    host_addr: &str,
+    start_time: chrono::DateTime<Utc>,
+    tunnel_result: Result<std::io::Result<(u64, u64)>, tokio::time::error::Elapsed>,
+) {
+    release_upstream(app_state, conn_key);
+
+    match tunnel_result {
+        Ok(Ok((bytes_sent, bytes_received))) => {
+            let duration = Utc::now().signed_duration_since(start_time);
+            let duration_ms = duration.num_milliseconds().max(0) as u64;
+
+            tracing::info!("✅ Tunnel completed: {} → {} | ⬆️ {} bytes ⬇️ {} bytes | ⏱️ {}ms",
+                client, host_addr, bytes_sent, bytes_received, duration_ms);
+
+            if let Some(mut conn) = app_state.monitoring_state.get_mut(conn_key) {
+                conn.bytes_sent = bytes_sent;
+                conn.bytes_received = bytes_received;
+                conn.duration_ms = Some(duration_ms);
+                conn.status = "completed".to_string();
+            }
+
+            update_user_stats_bytes(&app_state.user_stats_state, client.stats_key(), bytes_sent + bytes_received).await;
+        }
+        Ok(Err(e)) => {
+            tracing::error!("❌ Tunnel error: {} → {} | Error: {}", client, host_addr, e);
+            mark_connection_failed(&app_state.monitoring_state, conn_key);
+        }
+        Err(_) => {
+            tracing::warn!("⏱️ Tunnel timeout: {} → {}", client, host_addr);
+            if let Some(mut conn) = app_state.monitoring_state.get_mut(conn_key) {
+                conn.status = "timeout".to_string();
+                conn.duration_ms = Some(TUNNEL_TIMEOUT_SECS * 1000);
+            }
+        }
+    }
+}
+
+// Run a tunnel that the admin API can close early, whatever front end it came through.
+// Returns None when it was closed, after recording the connection as `admin_terminated`.
+async fn terminable<F: std::future::Future>(
+    app_state: &AppState,
+    conn_key: &str,
+    client: &ClientIdentity,
+    host_addr: &str,
+    tunnel: F,
+) -> Option<F::Output> {
+    // Kept until the tunnel ends
+    let (cancel, cancelled) = oneshot::channel();
+    let transferred = Transferred::default();
+    app_state.tunnels.insert(conn_key.to_string(), TunnelHandle { cancel, transferred: transferred.clone() });
+    let start_time = Utc::now();
+
+    let output = tokio::select! {
+        Ok(()) = cancelled => {
+            record_admin_terminated(app_state, conn_key, client, host_addr, start_time, transferred.totals()).await;
+            None
+        }
+        output = tunnel => Some(output),
+    };
+
+    app_state.tunnels.remove(conn_key);
+    output
+}
+
+// The byte counters of a running tunnel, shared with its handle; a connection the
+// admin API cannot close gets counters of its own
+fn transferred(app_state: &AppState, conn_key: &str) -> Transferred {
+    app_state.tunnels.get(conn_key)
+        .map(|handle| handle.transferred.clone())
+        .unwrap_or_default()
+}
+
+// Record a tunnel closed through the admin API, with the bytes it relayed until then
+async fn record_admin_terminated(
+    app_state: &AppState,
+    conn_key: &str,
+    client: &ClientIdentity,
+    host_addr: &str,
+    start_time: chrono::DateTime<Utc>,
+    (bytes_sent, bytes_received): (u64, u64),
+) {
+    release_upstream(app_state, conn_key);
+
+    let duration_ms = Utc::now().signed_duration_since(start_time).num_milliseconds().max(0) as u64;
+    tracing::info!("🛑 Tunnel terminated by the admin API: {} → {} | ⬆️ {} bytes ⬇️ {} bytes | ⏱️ {}ms",
+        client, host_addr, bytes_sent, bytes_received, duration_ms);
+
+    if let Some(mut conn) = app_state.monitoring_state.get_mut(conn_key) {
+        conn.status = "admin_terminated".to_string();
+        conn.bytes_sent = bytes_sent;
+        conn.bytes_received = bytes_received;
+        conn.duration_ms = Some(duration_ms);
+    }
+
+    update_user_stats_bytes(&app_state.user_stats_state, client.stats_key(), bytes_sent + bytes_received).await;
+}
+
+// Give back the tunnel's slot on the upstream it was chained through, if any
+fn release_upstream(app_state: &AppState, conn_key: &str) {
+    let route = app_state.monitoring_state.get(conn_key).and_then(|conn| conn.route.clone());
+    if let Some(route) = route {
+        let upstream = route.rsplit('/').next().unwrap_or(&route);
+        app_state.pool_state.release(upstream);
+    }
+}
+
+async fn tunnel<T>(
+    mut client_io: T,
+    app_state: &AppState,
+    conn_key: &str,
+    client: &ClientIdentity,
+    addr: &str,
+) -> std::io::Result<(u64, u64)>
+where
+    T: AsyncRead + AsyncWrite + Unpin,
+{
+    // Intercepted tunnels check the ClientHello while terminating TLS themselves
+    if mitm::should_intercept(app_state, addr) {
+        let server = dial(app_state, conn_key, client, addr).await?;
+        return mitm::intercept(client_io, server, app_state, conn_key, client, addr).await;
+    }
+
+    // Inspect before dialing, so a rejected ClientHello never reaches the target
+    let preface = inspect_client_hello(&mut client_io, app_state, conn_key, client, addr).await?;
+    let server = dial(app_state, conn_key, client, addr).await?;
+    relay_with_preface(app_state, conn_key, client_io, server, &preface).await
+}
+
+// With SNI_INSPECTION on, read the client's TLS ClientHello, record its SNI and ALPN
+// and re-check the policy. Returns the bytes read, which still have to reach the target.
+async fn inspect_client_hello<T>(
+    client_io: &mut T,
+    app_state: &AppState,
+    conn_key: &str,
+    client: &ClientIdentity,
+    addr: &str,
+) -> std::io::Result<Vec<u8>>
+where
+    T: AsyncRead + Unpin,
+{
+    if !app_state.config.sni_inspection {
+        return Ok(Vec::new());
+    }
+
+    let (preface, hello) = sni::read_client_hello(client_io).await?;
+    if let Some(hello) = hello {
+        apply_client_hello(app_state, conn_key, client, addr, &hello).await?;
+    }
+
+    Ok(preface)
+}
+
+async fn apply_client_hello(
+    app_state: &AppState,
+    conn_key: &str,
+    client: &ClientIdentity,
+    addr: &str,
+    hello: &sni::ClientHello,
+) -> std::io::Result<()> {
+    if let Some(mut conn) = app_state.monitoring_state.get_mut(conn_key) {
+        conn.sni = hello.sni.clone();
+        conn.alpn = (!hello.alpn.is_empty()).then(|| hello.alpn.clone());
+    }
+
+    if let Err(outcome) = sni::check_client_hello(app_state, client, addr, hello).await {
+        if let Some(mut conn) = app_state.monitoring_state.get_mut(conn_key) {
+            conn.status = outcome.to_string();
+            conn.duration_ms = Some(0);
+        }
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::PermissionDenied,
+            format!("Rejected by TLS inspection ({})", outcome),
+        ));
+    }
+
+    Ok(())
+}
+
+// Connect to the target directly or through the upstream (or pool member) picked
+// by the routing rules, failing over to the next pool member when a dial fails,
+// and note the route taken on the connection record
+async fn dial(
+    app_state: &AppState,
+    conn_key: &str,
+    client: &ClientIdentity,
+    addr: &str,
+) -> std::io::Result<TcpStream> {
+    let route = routing::select_route(client, addr);
+    let upstreams = match &route {
+        Route::Direct => {
+            let connection = connect_with_timeout(&app_state.resolver, None, addr).await?;
+            if let Some(mut conn) = app_state.monitoring_state.get_mut(conn_key) {
+                conn.route = Some(route.label(None));
+                conn.dns_ms = connection.dns_ms;
+                conn.remote_addr = Some(connection.peer_addr.to_string());
+            }
+            return Ok(connection.stream);
+        }
+        Route::Upstream(upstream) => vec![upstream.clone()],
+        Route::Pool(pool) => app_state.pool_state.candidates(pool, client),
+    };
+
+    let mut last_error = None;
+    for upstream in &upstreams {
+        tracing::debug!("↪️ {} → {} via upstream {}", client, addr, upstream.name);
+
+        match connect_with_timeout(&app_state.resolver, Some(upstream), addr).await {
+            Ok(connection) => {
+                app_state.pool_state.record_success(&upstream.name);
+                if let Some(mut conn) = app_state.monitoring_state.get_mut(conn_key) {
+                    conn.route = Some(route.label(Some(upstream)));
+                    conn.dns_ms = connection.dns_ms;
+                    conn.remote_addr = Some(connection.peer_addr.to_string());
+                }
+                return Ok(connection.stream);
+            }
+            // The upstream answered but refused the target, which says nothing about its health
+            Err(e) if routing::is_target_refusal(&e) => {
+                tracing::warn!("⚠️ Upstream {} refused {} → {}: {}", upstream.name, client, addr, e);
+                last_error = Some(e);
+            }
+            Err(e) => {
+                tracing::warn!("⚠️ Upstream {} failed for {} → {}: {}", upstream.name, client, addr, e);
+                app_state.pool_state.record_failure(&upstream.name, &e);
+                last_error = Some(e);
+            }
+        }
+    }
+
+    Err(last_error.unwrap_or_else(|| std::io::Error::other("No upstream available")))
+}
+
+async fn connect_with_timeout(
+    resolver: &Resolver,
+    upstream: Option<&routing::Upstream>,
+    addr: &str,
+) -> std::io::Result<routing::Connection> {
+    tokio::time::timeout(
         Duration::from_secs(10),
-        TcpStream::connect(&addr)
+        routing::connect(resolver, upstream, addr)
     ).await
-    .map_err(|_| std::io::Error::new(std::io::ErrorKind::TimedOut, "Connection timeout"))??;
+    .map_err(|_| std::io::Error::new(std::io::ErrorKind::TimedOut, "Connection timeout"))?
+}
 
-    let mut upgraded = TokioIo::new(upgraded);
+// Send the bytes already read from the client, then relay in both directions,
+// classifying the protocol from the first bytes each side sends
+async fn relay_with_preface<T>(
+    app_state: &AppState,
+    conn_key: &str,
+    client: T,
+    mut server: TcpStream,
+    preface: &[u8],
+) -> std::io::Result<(u64, u64)>
+where
+    T: AsyncRead + AsyncWrite + Unpin,
+{
+    let transferred = transferred(app_state, conn_key);
+    let mut client_sniffer = Sniffer::new(app_state, conn_key, Direction::Client);
+    client_sniffer.observe(preface)?;
+    server.write_all(preface).await?;
+    transferred.add_sent(preface.len() as u64);
+
+    let client = Sniffed::new(CountingIo::new(client, transferred), client_sniffer);
+    let server = Sniffed::new(server, Sniffer::new(app_state, conn_key, Direction::Server));
+    let (from_client, from_server) = relay(client, server).await?;
+
+    Ok((from_client + preface.len() as u64, from_server))
+}
 
+async fn relay<T, S>(mut client: T, mut server: S) -> std::io::Result<(u64, u64)>
+where
+    T: AsyncRead + AsyncWrite + Unpin,
+    S: AsyncRead + AsyncWrite + Unpin,
+{
     let (from_client, from_server) =
-        tokio::io::copy_bidirectional(&mut upgraded, &mut server).await?;
+        tokio::io::copy_bidirectional(&mut client, &mut server).await?;
 
     Ok((from_client, from_server))
 }
@@ -542,3 +1304,79 @@ async fn update_user_stats_bytes(
         stats.total_bytes += bytes;
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::http::HeaderValue;
+
+    fn headers(pairs: &[(&'static str, &'static str)]) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        for (name, value) in pairs {
+            headers.append(*name, HeaderValue::from_static(value));
+        }
+        headers
+    }
+
+    fn ip(value: &str) -> IpAddr {
+        value.parse().unwrap()
+    }
+
+    #[test]
+    fn ignores_forwarding_headers_from_untrusted_peers() {
+        let spoofed = headers(&[("x-real-ip", "10.0.0.5"), ("x-forwarded-for", "10.0.0.5")]);
+
+        assert_eq!(real_client_ip(&spoofed, ip("198.51.100.7"), &[]), ip("198.51.100.7"));
+        assert_eq!(real_client_ip(&spoofed, ip("198.51.100.7"), &["192.0.2.0/24".to_string()]), ip("198.51.100.7"));
+    }
+
+    #[test]
+    fn believes_a_trusted_proxy() {
+        let trusted = ["192.0.2.0/24".to_string()];
+
+        let real_ip = headers(&[("x-real-ip", "203.0.113.9"), ("x-forwarded-for", "10.0.0.5")]);
+        assert_eq!(real_client_ip(&real_ip, ip("192.0.2.1"), &trusted), ip("203.0.113.9"));
+
+        let forwarded = headers(&[("x-forwarded-for", "203.0.113.9")]);
+        assert_eq!(real_client_ip(&forwarded, ip("192.0.2.1"), &trusted), ip("203.0.113.9"));
+
+        assert_eq!(real_client_ip(&headers(&[]), ip("192.0.2.1"), &trusted), ip("192.0.2.1"));
+    }
+
+    #[test]
+    fn takes_the_nearest_untrusted_forwarded_address() {
+        let trusted = ["192.0.2.0/24".to_string()];
+        // The client wrote 10.0.0.5 itself; the trusted proxies appended the rest
+        let forwarded = headers(&[("x-forwarded-for", "10.0.0.5, 203.0.113.9"), ("x-forwarded-for", "192.0.2.20")]);
+
+        assert_eq!(real_client_ip(&forwarded, ip("192.0.2.1"), &trusted), ip("203.0.113.9"));
+
+        let garbled = headers(&[("x-forwarded-for", "10.0.0.5, unknown, 192.0.2.20")]);
+        assert_eq!(real_client_ip(&garbled, ip("192.0.2.1"), &trusted), ip("192.0.2.20"));
+    }
+
+    #[tokio::test]
+    async fn reads_no_further_than_the_h2_preface() {
+        let mut h2 = &b"PRI * HTTP/2.0\r\n\r\nSM\r\n\r\n\x00\x00\x00\x04"[..];
+        assert_eq!(read_h2_preface(&mut h2).await.unwrap(), H2_PREFACE);
+        assert_eq!(h2, b"\x00\x00\x00\x04");
+
+        // Stops at the first read that departs from the preface
+        let mut http1 = &b"POST /upload HTTP/1.1\r\nHost: example.com\r\n"[..];
+        assert_eq!(read_h2_preface(&mut http1).await.unwrap(), b"POST /upload HTTP/1.1\r\nH");
+        assert_eq!(http1, b"ost: example.com\r\n");
+
+        let mut truncated = &b"PRI * HTTP"[..];
+        assert_eq!(read_h2_preface(&mut truncated).await.unwrap(), b"PRI * HTTP");
+    }
+
+    #[tokio::test]
+    async fn replays_the_bytes_read_ahead() {
+        let mut stream = Rewind::new(&b"ST / HTTP/1.1\r\n"[..], b"PO".to_vec());
+
+        let mut request = String::new();
+        stream.read_to_string(&mut request).await.unwrap();
+
+        assert_eq!(request, "POST / HTTP/1.1\r\n");
+    }
+}
@@ -0,0 +1,274 @@
+use axum::body::Body;
+use axum::http::Request;
+use dashmap::DashMap;
+use hyper::body::Incoming;
+use hyper::client::conn::http1::SendRequest;
+use hyper_util::rt::TokioIo;
+use rcgen::{CertificateParams, DistinguishedName, DnType, KeyPair};
+use std::fs;
+use std::io;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::net::TcpStream;
+use tokio::sync::Mutex;
+use tokio_rustls::rustls::{
+    self,
+    pki_types::{pem::PemObject, CertificateDer, PrivateKeyDer, ServerName},
+    server::Acceptor,
+    ClientConfig,
+    RootCertStore,
+    ServerConfig,
+};
+use tokio_rustls::{LazyConfigAcceptor, TlsConnector};
+
+use crate::config::Config;
+use crate::forward::{self, Upstream};
+use crate::routing::{destination_matches, split_host_port};
+use crate::protocol::{tls_version_name, CountingIo, Direction, Sniffed, Sniffer};
+use crate::sni::ClientHello;
+use crate::{apply_client_hello, transferred, AppState, ClientIdentity, CONNECTION_TIMEOUT_SECS};
+
+const MAX_CACHED_CERTIFICATES: usize = 1000; // Minted certificates kept before the cache is cleared
+const CERTIFICATE_VALIDITY_DAYS: i64 = 30;
+
+// Terminates client TLS with certificates minted from the interception CA and
+// re-originates verified TLS to the real server
+pub struct Interceptor {
+    ca_cert: rcgen::Certificate,
+    ca_key: KeyPair,
+    server_configs: DashMap<String, Arc<ServerConfig>>,
+    client_config: Arc<ClientConfig>,
+}
+
+impl Interceptor {
+    // Interception is only available when MITM_CA_CERT_FILE and MITM_CA_KEY_FILE are set
+    pub fn from_config(config: &Config) -> Result<Option<Self>, Box<dyn std::error::Error + Send + Sync>> {
+        let (Some(cert_file), Some(key_file)) = (&config.mitm_ca_cert_file, &config.mitm_ca_key_file) else {
+            return Ok(None);
+        };
+
+        let ca_key = KeyPair::from_pem(&fs::read_to_string(key_file)?)?;
+        // Re-signing the parsed parameters with the same key keeps the subject and key
+        // identifier, so minted certificates chain to the CA file clients trust
+        let ca_cert = CertificateParams::from_ca_cert_pem(&fs::read_to_string(cert_file)?)?
+            .self_signed(&ca_key)?;
+
+        let mut roots = RootCertStore { roots: webpki_roots::TLS_SERVER_ROOTS.to_vec() };
+        if let Some(upstream_ca_file) = &config.mitm_upstream_ca_file {
+            for cert in CertificateDer::pem_file_iter(upstream_ca_file)? {
+                roots.add(cert?)?;
+            }
+        }
+
+        let mut client_config = ClientConfig::builder_with_provider(Arc::new(rustls::crypto::ring::default_provider()))
+            .with_safe_default_protocol_versions()?
+            .with_root_certificates(roots)
+            .with_no_client_auth();
+        client_config.alpn_protocols = vec![b"http/1.1".to_vec()];
+
+        Ok(Some(Interceptor {
+            ca_cert,
+            ca_key,
+            server_configs: DashMap::new(),
+            client_config: Arc::new(client_config),
+        }))
+    }
+
+    // Server config presenting a certificate for `host`, minted on first use
+    fn server_config(&self, host: &str) -> Result<Arc<ServerConfig>, Box<dyn std::error::Error + Send + Sync>> {
+        if let Some(server_config) = self.server_configs.get(host) {
+            return Ok(server_config.clone());
+        }
+
+        let key = KeyPair::generate()?;
+        let mut params = CertificateParams::new(vec![host.to_string()])?;
+        let mut distinguished_name = DistinguishedName::new();
+        distinguished_name.push(DnType::CommonName, host);
+        params.distinguished_name = distinguished_name;
+        let now = time::OffsetDateTime::now_utc();
+        params.not_before = now - time::Duration::hours(1);
+        params.not_after = now + time::Duration::days(CERTIFICATE_VALIDITY_DAYS);
+        let cert = params.signed_by(&key, &self.ca_cert, &self.ca_key)?;
+
+        let chain = vec![cert.der().clone(), self.ca_cert.der().clone()];
+        let key_der = PrivateKeyDer::try_from(key.serialize_der())?;
+        let mut server_config = ServerConfig::builder_with_provider(Arc::new(rustls::crypto::ring::default_provider()))
+            .with_safe_default_protocol_versions()?
+            .with_no_client_auth()
+            .with_single_cert(chain, key_der)?;
+        server_config.alpn_protocols = vec![b"http/1.1".to_vec()];
+        let server_config = Arc::new(server_config);
+
+        if self.server_configs.len() >= MAX_CACHED_CERTIFICATES {
+            self.server_configs.clear();
+        }
+        self.server_configs.insert(host.to_string(), server_config.clone());
+
+        Ok(server_config)
+    }
+}
+
+// Destinations to intercept, from `intercept.txt`:
+//
+//   intercept <destination-pattern>
+//   bypass <destination-pattern>
+//
+// Patterns are the same as in `routes.txt`. A bypass always wins, so pinned apps
+// and banking sites can be excluded from broader intercept rules.
+pub fn should_intercept(app_state: &AppState, host_addr: &str) -> bool {
+    if app_state.interceptor.is_none() {
+        return false;
+    }
+
+    let contents = fs::read_to_string("./intercept.txt").unwrap_or_default();
+    let mut intercept = false;
+
+    for line in contents.lines() {
+        match line.split_whitespace().collect::<Vec<_>>().as_slice() {
+            ["bypass", pattern] if destination_matches(pattern, host_addr) => return false,
+            ["intercept", pattern] if destination_matches(pattern, host_addr) => intercept = true,
+            _ => {}
+        }
+    }
+
+    intercept
+}
+
+// The `intercept` patterns from `intercept.txt`, when interception is configured
+pub fn intercepted_destinations(config: &Config) -> Vec<String> {
+    if config.mitm_ca_cert_file.is_none() || config.mitm_ca_key_file.is_none() {
+        return Vec::new();
+    }
+
+    fs::read_to_string("./intercept.txt")
+        .unwrap_or_default()
+        .lines()
+        .filter_map(|line| match line.split_whitespace().collect::<Vec<_>>().as_slice() {
+            ["intercept", pattern] => Some(pattern.to_string()),
+            _ => None,
+        })
+        .collect()
+}
+
+// Terminate the client's TLS, then serve its HTTP/1.1 requests by forwarding them over
+// a verified TLS connection to the server through the same pipeline as plain HTTP.
+// Returns the bytes exchanged with the client, as for a plain tunnel.
+pub async fn intercept<T>(
+    client_io: T,
+    server: TcpStream,
+    app_state: &AppState,
+    conn_key: &str,
+    client: &ClientIdentity,
+    host_addr: &str,
+) -> io::Result<(u64, u64)>
+where
+    T: AsyncRead + AsyncWrite + Unpin,
+{
+    let Some(interceptor) = &app_state.interceptor else {
+        return Err(io::Error::other("TLS interception is not configured"));
+    };
+
+    // Counted before TLS, as the bytes that crossed the client's connection
+    let transferred = transferred(app_state, conn_key);
+    let client_io = CountingIo::new(client_io, transferred.clone());
+    // Only the client's side is classified: the proxy itself is the server's TLS client
+    let client_io = Sniffed::new(client_io, Sniffer::new(app_state, conn_key, Direction::Client));
+
+    let start = tokio::time::timeout(
+        Duration::from_secs(CONNECTION_TIMEOUT_SECS),
+        LazyConfigAcceptor::new(Acceptor::default(), client_io)
+    ).await
+    .map_err(|_| io::Error::new(io::ErrorKind::TimedOut, "TLS handshake timeout"))??;
+
+    let client_hello = start.client_hello();
+    let hello = ClientHello {
+        sni: client_hello.server_name().map(|name| name.to_ascii_lowercase()),
+        alpn: client_hello.alpn()
+            .map(|protocols| protocols.map(|protocol| String::from_utf8_lossy(protocol).into_owned()).collect())
+            .unwrap_or_default(),
+        ..ClientHello::default()
+    };
+    apply_client_hello(app_state, conn_key, client, host_addr, &hello).await?;
+
+    let (connect_host, port) = split_host_port(host_addr);
+    let host = hello.sni.clone()
+        .unwrap_or_else(|| connect_host.trim_start_matches('[').trim_end_matches(']').to_string());
+    let authority = match port {
+        Some("443") | None => host.clone(),
+        Some(port) => format!("{}:{}", host, port),
+    };
+
+    let server_config = interceptor.server_config(&host).map_err(io::Error::other)?;
+    let client_tls = start.into_stream(server_config).await?;
+
+    if let Some(mut conn) = app_state.monitoring_state.get_mut(conn_key) {
+        conn.intercepted = true;
+        conn.tls_version = client_tls.get_ref().1.protocol_version().map(|version| tls_version_name(u16::from(version)));
+    }
+
+    // A failed upstream handshake (e.g. an invalid server certificate) is reported to
+    // the client as a 502 rather than a dropped connection
+    let upstream = match connect_upstream(interceptor, server, &host).await {
+        Ok(sender) => Ok(Arc::new(Mutex::new(sender))),
+        Err(e) => {
+            tracing::error!("❌ Interception upstream error: {} → {} | Error: {}", client, host_addr, e);
+            Err(e.to_string())
+        }
+    };
+
+    let service = hyper::service::service_fn({
+        let app_state = app_state.clone();
+        let conn_key = conn_key.to_string();
+        let client = client.clone();
+        let host_addr = host_addr.to_string();
+
+        move |req: Request<Incoming>| {
+            let app_state = app_state.clone();
+            let conn_key = conn_key.clone();
+            let client = client.clone();
+            let host_addr = host_addr.clone();
+            let authority = authority.clone();
+            let upstream = upstream.clone();
+
+            async move {
+                let path = req.uri().path_and_query().map(|path| path.as_str()).unwrap_or("/");
+                let url = format!("https://{}{}", authority, path);
+                let upstream = Upstream::Intercepted(upstream);
+
+                Ok::<_, hyper::Error>(
+                    forward::forward_request(req.map(Body::new), &app_state, &conn_key, &client, &host_addr, &url, upstream).await
+                )
+            }
+        }
+    });
+
+    let served = hyper::server::conn::http1::Builder::new()
+        .serve_connection(TokioIo::new(client_tls), service)
+        .await;
+    if let Err(e) = served {
+        tracing::debug!("Intercepted connection from {} closed: {}", client, e);
+    }
+
+    Ok(transferred.totals())
+}
+
+async fn connect_upstream(
+    interceptor: &Interceptor,
+    server: TcpStream,
+    host: &str,
+) -> Result<SendRequest<Body>, Box<dyn std::error::Error + Send + Sync>> {
+    let server_name = ServerName::try_from(host.to_string())?;
+    let server_tls = TlsConnector::from(interceptor.client_config.clone())
+        .connect(server_name, server)
+        .await?;
+
+    let (sender, connection) = hyper::client::conn::http1::handshake(TokioIo::new(server_tls)).await?;
+    tokio::spawn(async move {
+        if let Err(e) = connection.await {
+            tracing::debug!("Interception upstream connection closed: {}", e);
+        }
+    });
+
+    Ok(sender)
+}
@@ -0,0 +1,268 @@
+use chrono::Utc;
+use serde::Serialize;
+use std::fs;
+use std::net::Ipv4Addr;
+
+use crate::config::Config;
+use crate::forward::read_blocked_urls;
+use crate::mitm::intercepted_destinations;
+use crate::policy::read_rules;
+use crate::routing::{read_routing, split_host_port};
+
+pub const CONTENT_TYPE: &str = "application/x-ns-proxy-autoconfig";
+
+// Used when there is no `pac.txt`: short host names and private networks go direct
+const DEFAULT_PAC_RULES: &[&str] = &[
+    "direct <local>",
+    "direct 10.0.0.0/8",
+    "direct 172.16.0.0/12",
+    "direct 192.168.0.0/16",
+];
+
+// Evaluated by the client: destinations the proxy enforces policy on always use the
+// proxy, then the first matching route decides (an upstream means the proxy), then
+// the first matching `pac.txt` rule, and otherwise the proxy with a direct fallback
+const SCRIPT: &str = r#"
+var DEFAULT_PORTS = { "http": "80", "https": "443", "ws": "80", "wss": "443", "ftp": "21" };
+
+function portOf(url) {
+    var match = /^([a-z][a-z0-9+.-]*):\/\/(?:[^\/?#@]*@)?(?:\[[^\]]*\]|[^\/?#:]*)(?::(\d+))?/i.exec(url);
+    if (!match) return "";
+    return match[2] || DEFAULT_PORTS[match[1].toLowerCase()] || "";
+}
+
+function matches(rule, host, port) {
+    if (rule.port !== null && rule.port != port) return false;
+    switch (rule.kind) {
+        case "any": return true;
+        case "local": return isPlainHostName(host);
+        case "suffix": return dnsDomainIs(host, rule.value);
+        // Only address literals, so no rule makes the browser wait on DNS
+        case "net": return /^\d+\.\d+\.\d+\.\d+$/.test(host) && isInNet(host, rule.value, rule.mask);
+        default: return host == rule.value;
+    }
+}
+
+function FindProxyForURL(url, host) {
+    host = host.toLowerCase();
+    var port = portOf(url);
+    var i;
+
+    for (i = 0; i < ENFORCED.length; i++) {
+        if (matches(ENFORCED[i], host, port)) return PROXY;
+    }
+    for (i = 0; i < ROUTES.length; i++) {
+        if (matches(ROUTES[i], host, port)) {
+            if (ROUTES[i].proxy) return PROXY;
+            break;
+        }
+    }
+    for (i = 0; i < RULES.length; i++) {
+        if (matches(RULES[i], host, port)) return RULES[i].proxy ? PROXY : "DIRECT";
+    }
+    return PROXY + "; DIRECT";
+}
+"#;
+
+// A destination pattern in the form the script matches
+#[derive(Serialize)]
+struct PacRule {
+    kind: &'static str,
+    value: String,
+    mask: Option<String>,
+    port: Option<String>,
+    proxy: bool,
+}
+
+impl PacRule {
+    // Same patterns as `routes.txt`, plus `<local>` for host names without a dot.
+    // IPv6 ranges cannot be checked without DNS lookups in PAC, so are left out.
+    fn parse(pattern: &str, proxy: bool) -> Option<Self> {
+        let (host, port) = split_host_port(pattern);
+        let host = host.trim_start_matches('[').trim_end_matches(']').to_ascii_lowercase();
+        let port = port.filter(|port| *port != "*").map(|port| port.to_string());
+
+        let (kind, value, mask) = if host == "*" {
+            ("any", host, None)
+        } else if host == "<local>" {
+            ("local", host, None)
+        } else if let Some(suffix) = host.strip_prefix("*.") {
+            ("suffix", format!(".{}", suffix), None)
+        } else if let Some((network, prefix_len)) = host.split_once('/') {
+            let network = network.parse::<Ipv4Addr>().ok()?;
+            let prefix_len = prefix_len.parse::<u32>().ok()?.min(32);
+            let mask = Ipv4Addr::from(u32::MAX.checked_shl(32 - prefix_len).unwrap_or(0));
+            ("net", network.to_string(), Some(mask.to_string()))
+        } else {
+            ("host", host, None)
+        };
+
+        Some(PacRule { kind, value, mask, port, proxy })
+    }
+}
+
+// Client rules from `pac.txt`, first match wins:
+//
+//   direct <destination-pattern> [group=<client-group>]
+//   proxy <destination-pattern> [group=<client-group>]
+fn read_pac_rules(groups: &[String]) -> Vec<PacRule> {
+    let contents = fs::read_to_string("./pac.txt")
+        .unwrap_or_else(|_| DEFAULT_PAC_RULES.join("\n"));
+
+    parse_pac_rules(&contents, groups)
+}
+
+// The rules that apply to a client in `groups`
+fn parse_pac_rules(contents: &str, groups: &[String]) -> Vec<PacRule> {
+    contents.lines()
+        .filter_map(|line| {
+            let fields: Vec<&str> = line.split_whitespace().collect();
+            let (proxy, pattern, options) = match fields.as_slice() {
+                ["direct", pattern, options @ ..] => (false, pattern, options),
+                ["proxy", pattern, options @ ..] => (true, pattern, options),
+                [] => return None,
+                [comment, ..] if comment.starts_with('#') => return None,
+                _ => {
+                    tracing::warn!("⚠️ Ignoring invalid line in pac.txt: {}", line);
+                    return None;
+                }
+            };
+
+            let group = options.iter().find_map(|option| option.strip_prefix("group="));
+            if group.is_some_and(|group| !groups.iter().any(|member_of| member_of == group)) {
+                return None;
+            }
+            PacRule::parse(pattern, proxy)
+        })
+        .collect()
+}
+
+// Proxy auto-config script for a client in `groups`, pointing at `proxy_addr`
+pub fn generate(config: &Config, groups: &[String], proxy_addr: &str) -> String {
+    // Blocked and intercepted destinations only see policy applied if they use the proxy
+    let enforced: Vec<String> = read_rules()
+        .into_iter()
+        .chain(read_blocked_urls().into_iter().map(|(_, host_pattern, _)| host_pattern))
+        .chain(intercepted_destinations(config))
+        .collect();
+
+    script(&enforced, read_routing().client_routes(groups), read_pac_rules(groups), groups, proxy_addr)
+}
+
+fn script(
+    enforced: &[String],
+    routes: Vec<(String, bool)>,
+    rules: Vec<PacRule>,
+    groups: &[String],
+    proxy_addr: &str,
+) -> String {
+    let enforced: Vec<PacRule> = enforced.iter()
+        .filter_map(|pattern| PacRule::parse(pattern, true))
+        .collect();
+
+    let routes: Vec<PacRule> = routes.into_iter()
+        .filter_map(|(pattern, upstream)| PacRule::parse(&pattern, upstream))
+        .collect();
+
+    let groups = match groups {
+        [] => "none".to_string(),
+        groups => groups.join(", "),
+    };
+
+    format!(
+        "// Proxy auto-config generated {} for client groups: {}\n\
+         var PROXY = {};\n\
+         var ENFORCED = {};\n\
+         var ROUTES = {};\n\
+         var RULES = {};\n{}",
+        Utc::now().format("%Y-%m-%d %H:%M:%S UTC"),
+        groups,
+        serde_json::to_string(&format!("PROXY {}", proxy_addr)).unwrap_or_default(),
+        serde_json::to_string(&enforced).unwrap_or_default(),
+        serde_json::to_string(&routes).unwrap_or_default(),
+        serde_json::to_string(&rules).unwrap_or_default(),
+        SCRIPT,
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parsed(pattern: &str) -> serde_json::Value {
+        serde_json::to_value(PacRule::parse(pattern, true).unwrap()).unwrap()
+    }
+
+    #[test]
+    fn translates_destination_patterns() {
+        assert_eq!(parsed("*"), serde_json::json!({"kind": "any", "value": "*", "mask": null, "port": null, "proxy": true}));
+        assert_eq!(parsed("<local>")["kind"], "local");
+        assert_eq!(parsed("*.Example.com:*"), serde_json::json!({"kind": "suffix", "value": ".example.com", "mask": null, "port": null, "proxy": true}));
+        assert_eq!(parsed("twitter.com:443"), serde_json::json!({"kind": "host", "value": "twitter.com", "mask": null, "port": "443", "proxy": true}));
+        assert_eq!(parsed("[2001:db8::1]:8443")["value"], "2001:db8::1");
+    }
+
+    #[test]
+    fn turns_ipv4_prefixes_into_masks() {
+        assert_eq!(parsed("172.16.0.0/12"), serde_json::json!({"kind": "net", "value": "172.16.0.0", "mask": "255.240.0.0", "port": null, "proxy": true}));
+        assert_eq!(parsed("192.0.2.7/32")["mask"], "255.255.255.255");
+        assert_eq!(parsed("0.0.0.0/0")["mask"], "0.0.0.0");
+        assert_eq!(parsed("10.0.0.0/99")["mask"], "255.255.255.255");
+
+        assert!(PacRule::parse("2001:db8::/32", true).is_none());
+        assert!(PacRule::parse("10.0.0.0/eight", true).is_none());
+    }
+
+    fn groups(names: &[&str]) -> Vec<String> {
+        names.iter().map(|name| name.to_string()).collect()
+    }
+
+    // The JSON array assigned to `var <name>` in a generated script
+    fn array(script: &str, name: &str) -> Vec<serde_json::Value> {
+        let prefix = format!("var {} = ", name);
+        let line = script.lines().find_map(|line| line.strip_prefix(prefix.as_str())).unwrap();
+        serde_json::from_str(line.trim_end_matches(';')).unwrap()
+    }
+
+    #[test]
+    fn generates_a_script_for_the_proxy_address() {
+        // A blocked site, a blocked_urls.txt host and an intercepted destination
+        let enforced = ["twitter.com:443".to_string(), "www.example.com".to_string(), "*.intercepted.example".to_string()];
+        let routes = vec![("*.corp.example".to_string(), false), ("10.0.0.0/8".to_string(), true)];
+        let script = script(&enforced, routes, Vec::new(), &groups(&["staff", "lab"]), "proxy.internal:8080");
+
+        assert!(script.starts_with("// Proxy auto-config generated "));
+        assert!(script.contains("for client groups: staff, lab\n"));
+        assert!(script.contains("var PROXY = \"PROXY proxy.internal:8080\";\n"));
+        assert!(script.contains("function FindProxyForURL(url, host)"));
+
+        let enforced = array(&script, "ENFORCED");
+        let values: Vec<_> = enforced.iter().map(|rule| (rule["kind"].as_str().unwrap(), rule["value"].as_str().unwrap())).collect();
+        assert_eq!(values, [("host", "twitter.com"), ("host", "www.example.com"), ("suffix", ".intercepted.example")]);
+        assert!(enforced.iter().all(|rule| rule["proxy"] == true));
+
+        let routes = array(&script, "ROUTES");
+        assert_eq!((routes[0]["kind"].as_str(), routes[0]["proxy"].as_bool()), (Some("suffix"), Some(false)));
+        assert_eq!((routes[1]["mask"].as_str(), routes[1]["proxy"].as_bool()), (Some("255.0.0.0"), Some(true)));
+    }
+
+    #[test]
+    fn names_clients_without_groups() {
+        let script = script(&[], Vec::new(), Vec::new(), &[], "192.0.2.1:8080");
+
+        assert!(script.contains("for client groups: none\n"));
+        assert!(array(&script, "ENFORCED").is_empty());
+    }
+
+    #[test]
+    fn keeps_pac_rules_for_the_client_groups() {
+        let contents = "# comment\n\ndirect <local>\nproxy *.lab.example group=lab\ndirect *.staff.example group=staff\nbogus line here\n";
+
+        let rules = parse_pac_rules(contents, &groups(&["lab"]));
+        let script = script(&[], Vec::new(), rules, &groups(&["lab"]), "192.0.2.1:8080");
+
+        let rules = array(&script, "RULES");
+        let values: Vec<_> = rules.iter().map(|rule| (rule["value"].as_str().unwrap(), rule["proxy"].as_bool().unwrap())).collect();
+        assert_eq!(values, [("<local>", false), (".lab.example", true)]);
+    }
+}
@@ -0,0 +1,254 @@
+use axum::body::Body;
+use axum::http::{header, HeaderMap, Response, StatusCode};
+use chrono::Utc;
+use minijinja::Environment;
+use serde::Serialize;
+use serde_json::json;
+use std::fs;
+use std::path::Path;
+
+use crate::ClientIdentity;
+
+const TEMPLATE_DIR: &str = "./templates"; // Overrides for the built-in templates, with `<lang>/` subdirectories
+const DEFAULT_LANG: &str = "en";
+
+// Used when TEMPLATE_DIR has no override, so the proxy works without the directory
+const BUILTIN_TEMPLATES: &[(&str, &str)] = &[
+    ("layout.html", include_str!("../templates/layout.html")),
+    ("details.html", include_str!("../templates/details.html")),
+    ("index.html", include_str!("../templates/index.html")),
+    ("not_found.html", include_str!("../templates/not_found.html")),
+    ("blocked.html", include_str!("../templates/blocked.html")),
+    ("auth_required.html", include_str!("../templates/auth_required.html")),
+    ("quota_exceeded.html", include_str!("../templates/quota_exceeded.html")),
+    ("rate_limited.html", include_str!("../templates/rate_limited.html")),
+    ("upstream_error.html", include_str!("../templates/upstream_error.html")),
+];
+
+// Pages the proxy answers with instead of the content that was asked for
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Page {
+    Blocked,
+    AuthRequired,
+    QuotaExceeded,
+    RateLimited,
+    UpstreamError,
+    NotFound,
+}
+
+impl Page {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Page::Blocked => "blocked",
+            Page::AuthRequired => "auth_required",
+            Page::QuotaExceeded => "quota_exceeded",
+            Page::RateLimited => "rate_limited",
+            Page::UpstreamError => "upstream_error",
+            Page::NotFound => "not_found",
+        }
+    }
+
+    pub fn status(&self) -> StatusCode {
+        match self {
+            Page::Blocked => StatusCode::FORBIDDEN,
+            Page::AuthRequired => StatusCode::PROXY_AUTHENTICATION_REQUIRED,
+            Page::QuotaExceeded => StatusCode::PAYMENT_REQUIRED,
+            Page::RateLimited => StatusCode::TOO_MANY_REQUESTS,
+            Page::UpstreamError => StatusCode::BAD_GATEWAY,
+            Page::NotFound => StatusCode::NOT_FOUND,
+        }
+    }
+}
+
+impl std::str::FromStr for Page {
+    type Err = ();
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value {
+            "blocked" => Ok(Page::Blocked),
+            "auth_required" => Ok(Page::AuthRequired),
+            "quota_exceeded" => Ok(Page::QuotaExceeded),
+            "rate_limited" => Ok(Page::RateLimited),
+            "upstream_error" => Ok(Page::UpstreamError),
+            "not_found" => Ok(Page::NotFound),
+            _ => Err(()),
+        }
+    }
+}
+
+// What a page reports; each field is available to templates under its own name,
+// along with `status`, `timestamp` and `lang`
+#[derive(Clone, Debug, Default, Serialize)]
+pub struct PageDetails {
+    pub host: Option<String>,
+    pub url: Option<String>,
+    pub client: Option<String>,
+    pub user: Option<String>,
+    pub rule: Option<String>, // The policy that applied, e.g. `blocked_sites.txt:3`
+    pub reason: Option<String>,
+    pub connection_id: Option<String>,
+}
+
+impl PageDetails {
+    // The client and destination of a recorded connection
+    pub fn for_connection(client: &ClientIdentity, host_addr: &str, conn_key: &str) -> Self {
+        PageDetails {
+            host: Some(host_addr.to_string()),
+            client: Some(client.ip.clone()),
+            user: client.user.clone(),
+            connection_id: Some(conn_key.to_string()),
+            ..PageDetails::default()
+        }
+    }
+}
+
+// Answer with `page`, as JSON when the client prefers it and otherwise as HTML in the
+// first language from Accept-Language that has a translation
+pub fn render(page: Page, details: PageDetails, request_headers: &HeaderMap) -> Response<Body> {
+    let status = page.status();
+    let timestamp = Utc::now().format("%Y-%m-%d %H:%M:%S UTC").to_string();
+
+    if prefers_json(request_headers) {
+        let body = json!({
+            "error": page.as_str(),
+            "status": status.as_u16(),
+            "host": details.host,
+            "url": details.url,
+            "client": details.client,
+            "user": details.user,
+            "rule": details.rule,
+            "reason": details.reason,
+            "connection_id": details.connection_id,
+            "timestamp": timestamp,
+        });
+
+        Response::builder()
+            .status(status)
+            .header(header::CONTENT_TYPE, "application/json")
+            .body(Body::from(body.to_string()))
+            .unwrap()
+    } else {
+        render_html(&format!("{}.html", page.as_str()), status, &details, &timestamp, request_headers)
+    }
+}
+
+pub fn render_html(
+    name: &str,
+    status: StatusCode,
+    details: &PageDetails,
+    timestamp: &str,
+    request_headers: &HeaderMap,
+) -> Response<Body> {
+    let languages = accepted_languages(request_headers);
+    let lang = languages.iter()
+        .find(|lang| Path::new(TEMPLATE_DIR).join(lang).join(name).is_file())
+        .cloned()
+        .unwrap_or_else(|| DEFAULT_LANG.to_string());
+
+    let context = minijinja::context! {
+        status => status.as_u16(),
+        timestamp => timestamp,
+        lang => lang,
+        ..minijinja::Value::from_serialize(details)
+    };
+
+    // A broken override falls back to the built-in template, so errors stay readable
+    let html = match build_environment(&lang, true).get_template(name).and_then(|template| template.render(&context)) {
+        Ok(html) => html,
+        Err(e) => {
+            tracing::warn!("⚠️ Failed to render template {}: {}", name, e);
+            build_environment(&lang, false).get_template(name)
+                .and_then(|template| template.render(&context))
+                .unwrap_or_else(|e| format!("{} ({})", status, e))
+        }
+    };
+
+    Response::builder()
+        .status(status)
+        .header(header::CONTENT_TYPE, "text/html; charset=utf-8")
+        .header(header::CONTENT_LANGUAGE, lang)
+        .body(Body::from(html))
+        .unwrap()
+}
+
+// Templates are re-read on every render, so edits apply without a restart
+fn build_environment(lang: &str, overrides: bool) -> Environment<'static> {
+    let mut environment = Environment::new();
+
+    for (name, builtin) in BUILTIN_TEMPLATES {
+        let source = overrides
+            .then(|| {
+                let dir = Path::new(TEMPLATE_DIR);
+                fs::read_to_string(dir.join(lang).join(name))
+                    .or_else(|_| fs::read_to_string(dir.join(name)))
+                    .ok()
+            })
+            .flatten()
+            .unwrap_or_else(|| builtin.to_string());
+
+        if let Err(e) = environment.add_template_owned(*name, source) {
+            tracing::warn!("⚠️ Invalid template {}: {}", name, e);
+            let _ = environment.add_template(name, builtin);
+        }
+    }
+
+    environment
+}
+
+// Language tags from Accept-Language, most preferred first. A regional tag is followed by
+// its primary language, so `fr-CA` can use a `fr/` translation.
+fn accepted_languages(headers: &HeaderMap) -> Vec<String> {
+    let mut weighted: Vec<(String, f32)> = headers.get(header::ACCEPT_LANGUAGE)
+        .and_then(|value| value.to_str().ok())
+        .unwrap_or_default()
+        .split(',')
+        .filter_map(|entry| {
+            let mut parts = entry.split(';');
+            let tag = parts.next()?.trim().to_ascii_lowercase();
+            let quality = parts
+                .find_map(|param| param.trim().strip_prefix("q="))
+                .and_then(|quality| quality.parse::<f32>().ok())
+                .unwrap_or(1.0);
+            // Tags become directory names, so nothing but letters, digits and hyphens
+            let valid = !tag.is_empty() && tag.chars().all(|c| c.is_ascii_alphanumeric() || c == '-');
+            (valid && quality > 0.0).then_some((tag, quality))
+        })
+        .collect();
+    weighted.sort_by(|a, b| b.1.total_cmp(&a.1));
+
+    let mut languages = Vec::new();
+    for (tag, _) in weighted {
+        let primary = tag.split('-').next().unwrap_or_default().to_string();
+        for candidate in [tag, primary] {
+            if !languages.contains(&candidate) {
+                languages.push(candidate);
+            }
+        }
+    }
+    languages
+}
+
+// Whether the client's Accept header ranks JSON above HTML
+fn prefers_json(headers: &HeaderMap) -> bool {
+    let Some(accept) = headers.get(header::ACCEPT).and_then(|value| value.to_str().ok()) else {
+        return false;
+    };
+
+    let quality = |media_type: &str| {
+        accept.split(',')
+            .filter_map(|entry| {
+                let mut parts = entry.split(';');
+                let candidate = parts.next()?.trim();
+                (candidate.eq_ignore_ascii_case(media_type)).then(|| {
+                    parts
+                        .find_map(|param| param.trim().strip_prefix("q="))
+                        .and_then(|quality| quality.parse::<f32>().ok())
+                        .unwrap_or(1.0)
+                })
+            })
+            .fold(0.0_f32, f32::max)
+    };
+
+    let json = quality("application/json");
+    json > 0.0 && json > quality("text/html")
+}
@@ -0,0 +1,652 @@
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::fs;
+use std::io;
+use std::net::{IpAddr, SocketAddr, UdpSocket};
+use std::path::Path;
+use std::sync::Mutex;
+
+use crate::admin::AdminAddr;
+use crate::config::Config;
+use crate::ports::{connect_port_rule, forward_port_rule};
+use crate::routing::split_host_port;
+use crate::ClientIdentity;
+
+const POLICY_FILE: &str = "./blocked_sites.txt";
+const HISTORY_DIR: &str = "./policy_history"; // One `<version>.json` snapshot per rule set
+const MAX_POLICY_VERSIONS: usize = 100; // Older snapshots are removed
+const MAX_SHADOW_SAMPLES: usize = 100; // Recent divergences kept for the shadow report
+const MAX_SHADOW_TRACKED: usize = 10_000; // Distinct clients and destinations counted as affected
+
+// A rule set as it was applied, kept so it can be rolled back to
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct PolicyVersion {
+    pub version: u64,
+    pub created_at: DateTime<Utc>,
+    pub author: String,
+    pub comment: Option<String>,
+    pub rules: Vec<String>,
+}
+
+#[derive(Debug)]
+pub enum PolicyError {
+    Invalid(Vec<String>),
+    Conflict(u64), // The rule set changed since the version the caller expected; holds the current version
+    NotFound(String),
+    Io(io::Error),
+}
+
+impl From<io::Error> for PolicyError {
+    fn from(e: io::Error) -> Self {
+        PolicyError::Io(e)
+    }
+}
+
+// The blocked sites rule set, changed through the API and versioned in HISTORY_DIR.
+// Changes are serialised, validated as a whole and written atomically, so the proxy,
+// which re-reads `blocked_sites.txt` for every connection, never sees a partial file.
+pub struct PolicyStore {
+    lock: Mutex<()>,
+    shadow: Mutex<Option<Shadow>>,
+}
+
+// A candidate rule set evaluated alongside the live one without being enforced
+#[derive(Serialize)]
+pub struct Shadow {
+    pub rules: Vec<String>,
+    pub base_version: u64, // Live version when the candidate was loaded, which its impact is measured against
+    pub loaded_at: DateTime<Utc>,
+    pub loaded_by: String,
+    pub comment: Option<String>,
+    pub evaluated: u64,
+    pub would_block: u64,
+    pub would_allow: u64,
+    pub would_block_by_rule: HashMap<String, u64>, // Candidate rules that would block allowed connections
+    pub would_allow_by_rule: HashMap<String, u64>, // Live rules whose blocks the candidate would lift
+    #[serde(serialize_with = "serialize_len")]
+    pub affected_clients: HashSet<String>,
+    #[serde(serialize_with = "serialize_len")]
+    pub affected_destinations: HashSet<String>,
+    pub recent: VecDeque<Divergence>,
+}
+
+// A decision the candidate rules would have made differently
+#[derive(Clone, Serialize)]
+pub struct Divergence {
+    pub timestamp: DateTime<Utc>,
+    pub client: String,
+    pub target: String,
+    pub live: String,   // "allowed", "blocked" or "port_denied"
+    pub shadow: String,
+    pub rule: Option<String>, // The rule that made the difference
+}
+
+fn serialize_len<S: serde::Serializer>(set: &HashSet<String>, serializer: S) -> Result<S::Ok, S::Error> {
+    serializer.serialize_u64(set.len() as u64)
+}
+
+impl PolicyStore {
+    pub fn new() -> Self {
+        PolicyStore { lock: Mutex::new(()), shadow: Mutex::new(None) }
+    }
+
+    // Load a candidate rule set in shadow mode, replacing any earlier one and its counts
+    pub fn load_shadow(&self, rules: Vec<String>, loaded_by: &str, comment: Option<String>) -> Result<(), PolicyError> {
+        let errors = validate(&rules);
+        if !errors.is_empty() {
+            return Err(PolicyError::Invalid(errors));
+        }
+        let base_version = self.current()?.version;
+
+        tracing::info!("🌓 Shadow policy loaded by {} ({} rules, against version {})", loaded_by, rules.len(), base_version);
+        *self.shadow.lock().unwrap_or_else(|e| e.into_inner()) = Some(Shadow {
+            rules,
+            base_version,
+            loaded_at: Utc::now(),
+            loaded_by: loaded_by.to_string(),
+            comment,
+            evaluated: 0,
+            would_block: 0,
+            would_allow: 0,
+            would_block_by_rule: HashMap::new(),
+            would_allow_by_rule: HashMap::new(),
+            affected_clients: HashSet::new(),
+            affected_destinations: HashSet::new(),
+            recent: VecDeque::new(),
+        });
+        Ok(())
+    }
+
+    pub fn discard_shadow(&self) -> bool {
+        self.shadow.lock().unwrap_or_else(|e| e.into_inner()).take().is_some()
+    }
+
+    // Run `report` on the shadow state, if a candidate is loaded
+    pub fn with_shadow<T>(&self, report: impl FnOnce(&Shadow) -> T) -> Option<T> {
+        self.shadow.lock().unwrap_or_else(|e| e.into_inner()).as_ref().map(report)
+    }
+
+    // Apply the candidate as the live rule set. Unless another version is given, this is
+    // refused if the live rules changed after the candidate was loaded, since its
+    // measured impact would no longer hold.
+    pub fn promote_shadow(&self, expected_version: Option<u64>, author: &str, comment: Option<String>) -> Result<PolicyVersion, PolicyError> {
+        let (rules, base_version, shadow_comment) = self
+            .with_shadow(|shadow| (shadow.rules.clone(), shadow.base_version, shadow.comment.clone()))
+            .ok_or_else(|| PolicyError::NotFound("No shadow policy is loaded".to_string()))?;
+
+        let promoted = self.update(
+            Some(expected_version.unwrap_or(base_version)),
+            author,
+            comment.or(shadow_comment),
+            |_| Ok(rules),
+        )?;
+        self.discard_shadow();
+        Ok(promoted)
+    }
+
+    // Compare the live decision for a connection with the candidate's. Both share the
+    // port check; only the blocked sites differ.
+    pub fn shadow_check(&self, client: &ClientIdentity, evaluation: &Evaluation) {
+        let mut guard = self.shadow.lock().unwrap_or_else(|e| e.into_inner());
+        let Some(shadow) = guard.as_mut() else {
+            return;
+        };
+        shadow.evaluated += 1;
+
+        let host_addr = evaluation.target.as_str();
+        let live_rule = evaluation.site_rule.as_deref();
+        let shadow_rule = shadow.rules.iter()
+            .position(|rule| rule == host_addr)
+            .map(|index| format!("shadow:{} ({})", index + 1, shadow.rules[index]));
+        let live = evaluation.verdict;
+        let candidate = if shadow_rule.is_some() { Verdict::Blocked } else { evaluation.unblocked };
+        // Only a different verdict counts: a site block and a port denial both refuse
+        if (live == Verdict::Allowed) == (candidate == Verdict::Allowed) {
+            return;
+        }
+
+        let rule = if candidate == Verdict::Blocked {
+            shadow.would_block += 1;
+            tracing::info!("🌓 SHADOW would block {} → {} ({})", client, host_addr, shadow_rule.as_deref().unwrap_or_default());
+            shadow_rule
+        } else {
+            shadow.would_allow += 1;
+            tracing::info!("🌓 SHADOW would allow {} → {} (live {})", client, host_addr, live_rule.unwrap_or_default());
+            live_rule.map(|rule| rule.to_string())
+        };
+        if let Some(rule) = &rule {
+            let counts = if candidate == Verdict::Blocked { &mut shadow.would_block_by_rule } else { &mut shadow.would_allow_by_rule };
+            *counts.entry(rule.clone()).or_insert(0) += 1;
+        }
+
+        if shadow.affected_clients.len() < MAX_SHADOW_TRACKED {
+            shadow.affected_clients.insert(client.stats_key().to_string());
+        }
+        if shadow.affected_destinations.len() < MAX_SHADOW_TRACKED {
+            shadow.affected_destinations.insert(host_addr.to_string());
+        }
+        if shadow.recent.len() >= MAX_SHADOW_SAMPLES {
+            shadow.recent.pop_front();
+        }
+        shadow.recent.push_back(Divergence {
+            timestamp: Utc::now(),
+            client: client.to_string(),
+            target: host_addr.to_string(),
+            live: live.as_str().to_string(),
+            shadow: candidate.as_str().to_string(),
+            rule,
+        });
+    }
+
+    // The live rule set, recorded as a new version first if the file was edited by hand
+    pub fn current(&self) -> Result<PolicyVersion, PolicyError> {
+        let _guard = self.lock.lock().unwrap_or_else(|e| e.into_inner());
+        sync_with_file()
+    }
+
+    // Apply `change` to the live rules and record the result as a new version. With
+    // `expected_version`, the change is refused if someone else changed the rules first.
+    pub fn update<F>(
+        &self,
+        expected_version: Option<u64>,
+        author: &str,
+        comment: Option<String>,
+        change: F,
+    ) -> Result<PolicyVersion, PolicyError>
+    where
+        F: FnOnce(Vec<String>) -> Result<Vec<String>, PolicyError>,
+    {
+        let _guard = self.lock.lock().unwrap_or_else(|e| e.into_inner());
+        let current = sync_with_file()?;
+        if expected_version.is_some_and(|expected| expected != current.version) {
+            return Err(PolicyError::Conflict(current.version));
+        }
+
+        let rules = change(current.rules)?;
+        let errors = validate(&rules);
+        if !errors.is_empty() {
+            return Err(PolicyError::Invalid(errors));
+        }
+
+        write_atomically(Path::new(POLICY_FILE), rules_file(&rules))?;
+        record_version(current.version + 1, author, comment, rules)
+    }
+
+    // Every recorded version, oldest first
+    pub fn versions(&self) -> Result<Vec<PolicyVersion>, PolicyError> {
+        let _guard = self.lock.lock().unwrap_or_else(|e| e.into_inner());
+        sync_with_file()?;
+        Ok(read_history())
+    }
+
+    pub fn version(&self, version: u64) -> Result<PolicyVersion, PolicyError> {
+        read_history()
+            .into_iter()
+            .find(|recorded| recorded.version == version)
+            .ok_or_else(|| PolicyError::NotFound(format!("Policy version {} not found", version)))
+    }
+}
+
+// How a client reaches the target, which decides the ports it may use
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Access {
+    #[default]
+    Connect, // A TCP tunnel: HTTP CONNECT, SOCKS5 CONNECT or CONNECT over HTTP/3
+    Forward, // A plain HTTP request forwarded in absolute form
+    Udp, // CONNECT-UDP or a SOCKS5 UDP ASSOCIATE flow, which have no port allowlist
+}
+
+impl std::str::FromStr for Access {
+    type Err = ();
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value {
+            "connect" => Ok(Access::Connect),
+            "forward" => Ok(Access::Forward),
+            "udp" => Ok(Access::Udp),
+            _ => Err(()),
+        }
+    }
+}
+
+// The decision for a connection, named after the status recorded for it
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Verdict {
+    Allowed,
+    Blocked,
+    PortDenied,
+    DestinationDenied, // A forwarded request to the proxy host itself
+}
+
+impl Verdict {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Verdict::Allowed => "allowed",
+            Verdict::Blocked => "blocked",
+            Verdict::PortDenied => "port_denied",
+            Verdict::DestinationDenied => "destination_denied",
+        }
+    }
+
+    // Explanation shown on block pages, where the rule alone does not say enough
+    pub fn reason(&self) -> Option<&'static str> {
+        match self {
+            Verdict::PortDenied => Some("the port is not permitted"),
+            Verdict::DestinationDenied => Some("requests to the proxy itself or to loopback addresses are not forwarded"),
+            Verdict::Allowed | Verdict::Blocked => None,
+        }
+    }
+}
+
+// Why a client may or may not connect to a target, for `/api/policy/evaluate` and
+// `proxy policy test` as well as the proxy itself
+#[derive(Serialize)]
+pub struct Evaluation {
+    pub client: String,
+    pub target: String,
+    pub access: Access,
+    pub verdict: Verdict,
+    pub rule: Option<String>, // The rule that decided the verdict, as shown on block pages
+    pub trace: Vec<TraceStep>,
+    #[serde(skip)]
+    pub site_rule: Option<String>,
+    #[serde(skip)]
+    pub unblocked: Verdict, // The verdict if no blocked site had matched, for shadow mode
+}
+
+// One rule considered on the way to a verdict
+#[derive(Serialize)]
+pub struct TraceStep {
+    pub rule: String,
+    pub effect: &'static str, // "none", "block", "allow" or "deny"
+}
+
+// The client an evaluation is made for, once the request names a client IP and a host:port target
+pub fn evaluation_client(client: &str, target: &str, user: Option<String>) -> Result<ClientIdentity, String> {
+    let ip = client.parse::<IpAddr>().map_err(|_| format!("Invalid client IP: {}", client))?;
+    if split_host_port(target).1.and_then(|port| port.parse::<u16>().ok()).is_none() {
+        return Err(format!("The target must be host:port: {}", target));
+    }
+    Ok(ClientIdentity::new(ip.to_string(), user.filter(|user| !user.is_empty())))
+}
+
+// The decision every front end makes: the first matching rule of `blocked_sites.txt`
+// blocks, forwarded requests may not reach the proxy host, then only allowlisted ports
+// may be used. The port is checked even for blocked sites, as shadow mode compares both.
+pub fn evaluate(client: &ClientIdentity, host_addr: &str, access: Access, config: &Config) -> Evaluation {
+    evaluate_rules(&read_rules(), client, host_addr, access, config)
+}
+
+// The same decision against a given list of blocked sites
+pub fn evaluate_rules(rules: &[String], client: &ClientIdentity, host_addr: &str, access: Access, config: &Config) -> Evaluation {
+    let mut trace = Vec::new();
+
+    let mut site_rule = None;
+    for (index, rule) in rules.iter().enumerate() {
+        let label = format!("blocked_sites.txt:{}", index + 1);
+        if rule == host_addr {
+            trace.push(TraceStep { rule: format!("{} ({})", label, rule), effect: "block" });
+            site_rule = Some(label);
+            break;
+        }
+        trace.push(TraceStep { rule: format!("{} ({})", label, rule), effect: "none" });
+    }
+
+    // Names are only checked here when they are loopback; the forwarder checks the
+    // addresses other names resolve to
+    let destination_rule = match access {
+        Access::Forward => {
+            let rule = named_local_destination(host_addr, config);
+            if let Some(rule) = &rule {
+                trace.push(TraceStep { rule: rule.clone(), effect: "deny" });
+            }
+            rule
+        }
+        Access::Connect | Access::Udp => None,
+    };
+
+    let port_rule = match access {
+        Access::Connect => connect_port_rule(client, host_addr, config),
+        Access::Forward => forward_port_rule(client, host_addr, config),
+        Access::Udp => Some("UDP, any port".to_string()),
+    };
+    trace.push(match &port_rule {
+        Some(rule) => TraceStep { rule: rule.clone(), effect: "allow" },
+        None => TraceStep { rule: "CONNECT_PORTS".to_string(), effect: "deny" },
+    });
+
+    // The blocked sites are checked before the destination and the port
+    let unblocked = match (&destination_rule, &port_rule) {
+        (Some(_), _) => Verdict::DestinationDenied,
+        (None, Some(_)) => Verdict::Allowed,
+        (None, None) => Verdict::PortDenied,
+    };
+    let verdict = if site_rule.is_some() { Verdict::Blocked } else { unblocked };
+    let rule = match verdict {
+        Verdict::Blocked => site_rule.clone(),
+        Verdict::DestinationDenied => destination_rule,
+        Verdict::PortDenied => Some("CONNECT_PORTS".to_string()),
+        Verdict::Allowed => port_rule,
+    };
+
+    Evaluation {
+        client: client.to_string(),
+        target: host_addr.to_string(),
+        access,
+        verdict,
+        rule,
+        trace,
+        site_rule,
+        unblocked,
+    }
+}
+
+// Forwarded requests may not reach the proxy host's loopback interface or the proxy's
+// own listeners, such as the admin API. Returns the rule that refuses `addr`.
+pub fn local_destination(addr: SocketAddr, config: &Config) -> Option<String> {
+    let ip = addr.ip().to_canonical();
+    if ip.is_loopback() || ip.is_unspecified() {
+        return Some("loopback".to_string());
+    }
+
+    let admin_port = match &config.admin_addr {
+        AdminAddr::Tcp(admin_addr) => Some(admin_addr.port()),
+        AdminAddr::Unix(_) => None,
+    };
+    let listener_ports = [Some(config.proxy_port), Some(config.tls_port), admin_port];
+    // Only an address of this host can be bound to
+    if listener_ports.contains(&Some(addr.port())) && UdpSocket::bind(SocketAddr::new(ip, 0)).is_ok() {
+        return Some("proxy listener".to_string());
+    }
+    None
+}
+
+// The same check for a target given as `localhost` or an IP address, before resolving
+fn named_local_destination(host_addr: &str, config: &Config) -> Option<String> {
+    let (host, port) = split_host_port(host_addr);
+    let port = port.and_then(|port| port.parse::<u16>().ok())?;
+    let host = host.trim_start_matches('[').trim_end_matches(']').trim_end_matches('.');
+
+    if host.eq_ignore_ascii_case("localhost") || host.to_ascii_lowercase().ends_with(".localhost") {
+        return Some("loopback".to_string());
+    }
+    let ip = host.parse::<IpAddr>().ok()?;
+    local_destination(SocketAddr::new(ip, port), config)
+}
+
+// Problems with a rule set, one message per invalid or repeated rule
+pub fn validate(rules: &[String]) -> Vec<String> {
+    let mut errors = Vec::new();
+
+    for (index, rule) in rules.iter().enumerate() {
+        let position = index + 1;
+        if let Err(e) = validate_rule(rule) {
+            errors.push(format!("Rule {} ({:?}): {}", position, rule, e));
+        } else if rules[..index].contains(rule) {
+            errors.push(format!("Rule {} ({:?}): duplicates an earlier rule", position, rule));
+        }
+    }
+
+    errors
+}
+
+// Blocked sites are matched exactly against the `host:port` a client connects to
+fn validate_rule(rule: &str) -> Result<(), &'static str> {
+    if rule.is_empty() || rule.chars().any(|c| c.is_whitespace() || c.is_control()) {
+        return Err("must be a host:port without spaces");
+    }
+    if rule.contains('/') || rule.starts_with('#') {
+        return Err("must be a host:port, not a URL or comment");
+    }
+
+    let (host, port) = split_host_port(rule);
+    let Some(port) = port else {
+        return Err("needs a port, e.g. example.com:443");
+    };
+    if port.parse::<u16>().map_or(true, |port| port == 0) {
+        return Err("has an invalid port");
+    }
+    if host.is_empty() || host == "[]" {
+        return Err("has no host");
+    }
+    if host.chars().any(|c| c.is_ascii_uppercase()) {
+        return Err("must be lowercase, as clients' hosts are compared exactly");
+    }
+
+    Ok(())
+}
+
+// The live blocked sites, one `host:port` per line. Blank lines are skipped, so rule
+// numbers count rules, and a missing file means nothing is blocked.
+pub fn read_rules() -> Vec<String> {
+    fs::read_to_string(POLICY_FILE)
+        .unwrap_or_default()
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty())
+        .map(|line| line.to_string())
+        .collect()
+}
+
+fn rules_file(rules: &[String]) -> String {
+    rules.iter().map(|rule| format!("{}\n", rule)).collect()
+}
+
+// The latest version, after recording the file's rules as a new one if they differ
+// from it (a first run, or an edit made outside the API)
+fn sync_with_file() -> Result<PolicyVersion, PolicyError> {
+    let rules = read_rules();
+    let latest = read_history().pop();
+
+    match latest {
+        Some(latest) if latest.rules == rules => Ok(latest),
+        Some(latest) => record_version(latest.version + 1, "blocked_sites.txt", Some("Edited on disk".to_string()), rules),
+        None => record_version(1, "blocked_sites.txt", Some("Initial rule set".to_string()), rules),
+    }
+}
+
+fn read_history() -> Vec<PolicyVersion> {
+    let Ok(entries) = fs::read_dir(HISTORY_DIR) else {
+        return Vec::new();
+    };
+
+    let mut versions: Vec<PolicyVersion> = entries
+        .filter_map(Result::ok)
+        .filter(|entry| entry.path().extension().is_some_and(|extension| extension == "json"))
+        .filter_map(|entry| {
+            let contents = fs::read(entry.path()).ok()?;
+            match serde_json::from_slice(&contents) {
+                Ok(version) => Some(version),
+                Err(e) => {
+                    tracing::warn!("⚠️ Ignoring unreadable policy version {}: {}", entry.path().display(), e);
+                    None
+                }
+            }
+        })
+        .collect();
+    versions.sort_by_key(|version: &PolicyVersion| version.version);
+    versions
+}
+
+fn record_version(version: u64, author: &str, comment: Option<String>, rules: Vec<String>) -> Result<PolicyVersion, PolicyError> {
+    let recorded = PolicyVersion {
+        version,
+        created_at: Utc::now(),
+        author: author.to_string(),
+        comment,
+        rules,
+    };
+
+    fs::create_dir_all(HISTORY_DIR)?;
+    let contents = serde_json::to_vec_pretty(&recorded).map_err(io::Error::other)?;
+    write_atomically(&Path::new(HISTORY_DIR).join(format!("{}.json", version)), &contents)?;
+    tracing::info!("📜 Policy version {} recorded by {} ({} rules)", version, author, recorded.rules.len());
+
+    let history = read_history();
+    if history.len() > MAX_POLICY_VERSIONS {
+        for old in &history[..history.len() - MAX_POLICY_VERSIONS] {
+            let _ = fs::remove_file(Path::new(HISTORY_DIR).join(format!("{}.json", old.version)));
+        }
+    }
+
+    Ok(recorded)
+}
+
+// Write to a temporary file beside `path` and rename it into place
+fn write_atomically(path: &Path, contents: impl AsRef<[u8]>) -> io::Result<()> {
+    let mut tmp = path.as_os_str().to_owned();
+    tmp.push(".tmp");
+    fs::write(&tmp, contents)?;
+    fs::rename(&tmp, path)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const RULES: &[&str] = &["blocked.example:443", "192.0.2.66:8005", "blocked.example:80"];
+
+    fn config() -> Config {
+        Config::from_vars(|name| match name {
+            "PROXY_PORT" => Some("8100".to_string()),
+            "TLS_PORT" => Some("8443".to_string()),
+            "ADMIN_ADDR" => Some("127.0.0.1:9090".to_string()),
+            "CONNECT_PORTS" => Some("8000-8010".to_string()),
+            _ => None,
+        })
+    }
+
+    fn evaluated(target: &str, access: Access) -> Evaluation {
+        let rules: Vec<String> = RULES.iter().map(|rule| rule.to_string()).collect();
+        let client = ClientIdentity::new("192.0.2.50".to_string(), None);
+        evaluate_rules(&rules, &client, target, access, &config())
+    }
+
+    #[test]
+    fn labels_the_matching_blocked_site_by_line() {
+        let evaluation = evaluated("192.0.2.66:8005", Access::Connect);
+
+        assert_eq!(evaluation.verdict, Verdict::Blocked);
+        assert_eq!(evaluation.rule.as_deref(), Some("blocked_sites.txt:2"));
+        let effects: Vec<_> = evaluation.trace.iter().map(|step| step.effect).collect();
+        assert_eq!(effects, ["none", "block", "allow"]);
+        assert_eq!(evaluation.trace[1].rule, "blocked_sites.txt:2 (192.0.2.66:8005)");
+        // What the port allowlist alone would have decided, for shadow mode
+        assert_eq!(evaluation.unblocked, Verdict::Allowed);
+    }
+
+    #[test]
+    fn labels_the_port_rule_that_allowed_a_connect() {
+        assert_eq!(evaluated("example.com:443", Access::Connect).rule.as_deref(), Some("default port 443"));
+        assert_eq!(evaluated("example.com:8005", Access::Connect).rule.as_deref(), Some("CONNECT_PORTS"));
+
+        let evaluation = evaluated("example.com:22", Access::Connect);
+        assert_eq!(evaluation.verdict, Verdict::PortDenied);
+        assert_eq!(evaluation.rule.as_deref(), Some("CONNECT_PORTS"));
+        assert_eq!(evaluation.trace.len(), RULES.len() + 1);
+        assert_eq!(evaluation.trace.last().map(|step| step.effect), Some("deny"));
+    }
+
+    #[test]
+    fn labels_forwarded_and_udp_access() {
+        assert_eq!(evaluated("example.com:80", Access::Forward).rule.as_deref(), Some("default port 80"));
+        assert_eq!(evaluated("example.com:80", Access::Connect).verdict, Verdict::PortDenied);
+        assert_eq!(evaluated("example.com:53", Access::Udp).rule.as_deref(), Some("UDP, any port"));
+    }
+
+    #[test]
+    fn labels_forwarded_requests_to_the_proxy_host() {
+        for target in ["localhost:80", "app.localhost:443", "127.0.0.1:8005", "[::1]:80", "0.0.0.0:80"] {
+            let evaluation = evaluated(target, Access::Forward);
+            assert_eq!(evaluation.verdict, Verdict::DestinationDenied, "{}", target);
+            assert_eq!(evaluation.rule.as_deref(), Some("loopback"), "{}", target);
+        }
+
+        // Only forwarded requests are checked, since tunnels to localhost are the client's own
+        assert_eq!(evaluated("localhost:443", Access::Connect).verdict, Verdict::Allowed);
+        assert_eq!(evaluated("192.0.2.1:80", Access::Forward).verdict, Verdict::Allowed);
+    }
+
+    #[test]
+    fn blocked_sites_win_over_every_other_rule() {
+        let evaluation = evaluated("blocked.example:80", Access::Forward);
+
+        assert_eq!(evaluation.verdict, Verdict::Blocked);
+        assert_eq!(evaluation.rule.as_deref(), Some("blocked_sites.txt:3"));
+        assert_eq!(evaluation.site_rule.as_deref(), Some("blocked_sites.txt:3"));
+        assert_eq!(evaluated("blocked.example:80", Access::Connect).unblocked, Verdict::PortDenied);
+    }
+
+    #[test]
+    fn refuses_the_proxy_listeners_on_local_addresses() {
+        let config = config();
+
+        assert_eq!(local_destination("127.0.0.2:443".parse().unwrap(), &config).as_deref(), Some("loopback"));
+        assert_eq!(local_destination("[::ffff:127.0.0.1]:443".parse().unwrap(), &config).as_deref(), Some("loopback"));
+        assert_eq!(local_destination("192.0.2.1:8100".parse().unwrap(), &config), None);
+        assert_eq!(local_destination("192.0.2.1:443".parse().unwrap(), &config), None);
+    }
+}
@@ -0,0 +1,160 @@
+use chrono::{DateTime, Duration as ChronoDuration, Utc};
+use dashmap::DashMap;
+use std::hash::{DefaultHasher, Hash, Hasher};
+use std::sync::Arc;
+use std::time::Duration;
+
+use crate::dns::Resolver;
+use crate::routing::{self, Pool, PoolStrategy, Upstream};
+use crate::ClientIdentity;
+
+const EJECT_AFTER_FAILURES: u32 = 3; // Consecutive dial failures before an upstream is ejected
+const EJECTION_SECS: i64 = 30; // How long a passively ejected upstream is skipped
+const HEALTH_CHECK_INTERVAL_SECS: u64 = 15; // How often pools with a probe target are checked
+const HEALTH_CHECK_TIMEOUT_SECS: u64 = 5;
+
+// Health of one upstream, updated by tunnel dials and active health checks
+#[derive(Debug, Clone, Default)]
+pub struct UpstreamHealth {
+    pub consecutive_failures: u32,
+    pub active_connections: u64,
+    pub total_connections: u64,
+    pub ejected_until: Option<DateTime<Utc>>,
+    pub last_checked: Option<DateTime<Utc>>,
+    pub last_error: Option<String>,
+}
+
+impl UpstreamHealth {
+    pub fn is_healthy(&self) -> bool {
+        self.ejected_until.is_none_or(|until| until <= Utc::now())
+    }
+}
+
+// Upstream health keyed by upstream name, plus the round-robin position of each pool
+#[derive(Default)]
+pub struct PoolState {
+    pub upstreams: DashMap<String, UpstreamHealth>,
+    cursors: DashMap<String, usize>,
+}
+
+pub type PoolHealthState = Arc<PoolState>;
+
+impl PoolState {
+    pub fn health(&self, upstream: &str) -> UpstreamHealth {
+        self.upstreams.get(upstream).map(|health| health.clone()).unwrap_or_default()
+    }
+
+    // A tunnel was established through the upstream
+    pub fn record_success(&self, upstream: &str) {
+        let mut health = self.upstreams.entry(upstream.to_string()).or_default();
+        health.consecutive_failures = 0;
+        health.ejected_until = None;
+        health.active_connections += 1;
+        health.total_connections += 1;
+    }
+
+    // A dial through the upstream failed; eject it after too many failures in a row
+    pub fn record_failure(&self, upstream: &str, error: &std::io::Error) {
+        let mut health = self.upstreams.entry(upstream.to_string()).or_default();
+        health.consecutive_failures += 1;
+        health.last_error = Some(error.to_string());
+
+        if health.consecutive_failures >= EJECT_AFTER_FAILURES && health.is_healthy() {
+            tracing::warn!("🩺 Ejecting upstream {} after {} consecutive failures", upstream, health.consecutive_failures);
+            health.ejected_until = Some(Utc::now() + ChronoDuration::seconds(EJECTION_SECS));
+        }
+    }
+
+    // A tunnel through the upstream has finished
+    pub fn release(&self, upstream: &str) {
+        if let Some(mut health) = self.upstreams.get_mut(upstream) {
+            health.active_connections = health.active_connections.saturating_sub(1);
+        }
+    }
+
+    fn record_check(&self, upstream: &str, result: std::io::Result<()>) {
+        let mut health = self.upstreams.entry(upstream.to_string()).or_default();
+        let now = Utc::now();
+        health.last_checked = Some(now);
+
+        match result {
+            Ok(()) => {
+                if !health.is_healthy() {
+                    tracing::info!("🩺 Upstream {} passed its health check", upstream);
+                }
+                health.consecutive_failures = 0;
+                health.ejected_until = None;
+            }
+            Err(e) => {
+                if health.is_healthy() {
+                    tracing::warn!("🩺 Upstream {} failed its health check: {}", upstream, e);
+                }
+                health.last_error = Some(e.to_string());
+                // Stays ejected until a later check succeeds
+                health.ejected_until = Some(now + ChronoDuration::seconds(2 * HEALTH_CHECK_INTERVAL_SECS as i64));
+            }
+        }
+    }
+
+    // Upstreams of the pool in the order they should be tried: the strategy's pick
+    // first, then the other healthy members for failover. When every member is
+    // ejected they are all tried rather than failing the tunnel outright.
+    pub fn candidates(&self, pool: &Pool, client: &ClientIdentity) -> Vec<Upstream> {
+        let mut members: Vec<(Upstream, UpstreamHealth)> = pool.members.iter()
+            .map(|member| (member.clone(), self.health(&member.name)))
+            .collect();
+        if members.iter().any(|(_, health)| health.is_healthy()) {
+            members.retain(|(_, health)| health.is_healthy());
+        }
+
+        match pool.strategy {
+            PoolStrategy::RoundRobin => {
+                let mut cursor = self.cursors.entry(pool.name.clone()).or_default();
+                let start = *cursor % members.len();
+                *cursor = cursor.wrapping_add(1);
+                members.rotate_left(start);
+            }
+            PoolStrategy::LeastConnections => {
+                members.sort_by_key(|(_, health)| health.active_connections);
+            }
+            PoolStrategy::ConsistentHash => {
+                // Rendezvous hashing: a client keeps its upstream while that upstream is
+                // healthy, and only its own clients move when membership changes
+                members.sort_by_key(|(member, _)| {
+                    let mut hasher = DefaultHasher::new();
+                    client.stats_key().hash(&mut hasher);
+                    member.name.hash(&mut hasher);
+                    std::cmp::Reverse(hasher.finish())
+                });
+            }
+        }
+
+        members.into_iter().map(|(member, _)| member).collect()
+    }
+}
+
+// Periodically connect through every member of pools that name a probe target
+pub async fn run_health_checks(pool_state: PoolHealthState, resolver: Arc<Resolver>) {
+    let mut check_interval = tokio::time::interval(Duration::from_secs(HEALTH_CHECK_INTERVAL_SECS));
+
+    loop {
+        check_interval.tick().await;
+
+        for pool in routing::read_routing().pools {
+            let Some(probe) = &pool.probe else {
+                continue;
+            };
+
+            for member in &pool.members {
+                let result = tokio::time::timeout(
+                    Duration::from_secs(HEALTH_CHECK_TIMEOUT_SECS),
+                    routing::connect(&resolver, Some(member), probe)
+                ).await
+                .map_err(|_| std::io::Error::new(std::io::ErrorKind::TimedOut, "Health check timeout"))
+                .and_then(|result| result.map(|_| ()));
+
+                pool_state.record_check(&member.name, result);
+            }
+        }
+    }
+}
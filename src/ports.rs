@@ -0,0 +1,141 @@
+use std::fs;
+
+use crate::config::Config;
+use crate::groups::client_groups;
+use crate::routing::split_host_port;
+use crate::ClientIdentity;
+
+pub const DEFAULT_CONNECT_PORT: u16 = 443;
+pub const DEFAULT_HTTP_PORT: u16 = 80;
+
+// A set of ports written as `443, 8443, 8000-8100`, or `*` for every port
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct PortSet {
+    ranges: Vec<(u16, u16)>,
+}
+
+impl PortSet {
+    pub fn contains(&self, port: u16) -> bool {
+        self.ranges.iter().any(|&(start, end)| (start..=end).contains(&port))
+    }
+}
+
+impl std::str::FromStr for PortSet {
+    type Err = ();
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        let mut ranges = Vec::new();
+
+        for entry in value.split(',').map(str::trim).filter(|entry| !entry.is_empty()) {
+            let range = if entry == "*" {
+                (u16::MIN, u16::MAX)
+            } else if let Some((start, end)) = entry.split_once('-') {
+                let (start, end) = (start.trim().parse().map_err(|_| ())?, end.trim().parse().map_err(|_| ())?);
+                // A reversed range such as `8000-80` is more likely a typo than an empty set
+                if start > end {
+                    return Err(());
+                }
+                (start, end)
+            } else {
+                let port = entry.parse().map_err(|_| ())?;
+                (port, port)
+            };
+            ranges.push(range);
+        }
+
+        Ok(PortSet { ranges })
+    }
+}
+
+// The setting that permits a CONNECT to `host_addr`, or None when the port is denied:
+// port 443, the extra ports from CONNECT_PORTS, or ports granted to one of the client's
+// groups in `connect_ports.txt`
+pub fn connect_port_rule(client: &ClientIdentity, host_addr: &str, config: &Config) -> Option<String> {
+    let port = split_host_port(host_addr).1.and_then(|port| port.parse::<u16>().ok())?;
+
+    if port == DEFAULT_CONNECT_PORT {
+        return Some(format!("default port {}", DEFAULT_CONNECT_PORT));
+    }
+    if config.connect_ports.contains(port) {
+        return Some("CONNECT_PORTS".to_string());
+    }
+
+    let group_ports = read_group_ports();
+    if group_ports.is_empty() {
+        return None;
+    }
+
+    let groups = client_groups(client);
+    group_ports.iter()
+        .find(|(group, ports)| groups.contains(group) && ports.contains(port))
+        .map(|(group, _)| format!("connect_ports.txt:{}", group))
+}
+
+// Plain HTTP may also be forwarded to port 80, besides the ports allowed for CONNECT
+pub fn forward_port_rule(client: &ClientIdentity, host_addr: &str, config: &Config) -> Option<String> {
+    let port = split_host_port(host_addr).1.and_then(|port| port.parse::<u16>().ok())?;
+
+    if port == DEFAULT_HTTP_PORT {
+        return Some(format!("default port {}", DEFAULT_HTTP_PORT));
+    }
+    connect_port_rule(client, host_addr, config)
+}
+
+// Per-group additions to the allowed ports, one group per line: `group-name: 22, 5432`
+fn read_group_ports() -> Vec<(String, PortSet)> {
+    fs::read_to_string("./connect_ports.txt")
+        .unwrap_or_default()
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .filter_map(|line| {
+            let (group, ports) = line.split_once(':')?;
+            match ports.parse::<PortSet>() {
+                Ok(ports) => Some((group.trim().to_string(), ports)),
+                Err(_) => {
+                    tracing::warn!("⚠️ Ignoring invalid line in connect_ports.txt: {}", line);
+                    None
+                }
+            }
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_ports_and_ranges() {
+        let ports: PortSet = " 22, 8000 - 8100,,5432 ".parse().unwrap();
+
+        assert!(ports.contains(22) && ports.contains(5432));
+        assert!(ports.contains(8000) && ports.contains(8050) && ports.contains(8100));
+        assert!(!ports.contains(443) && !ports.contains(7999) && !ports.contains(8101));
+
+        let single: PortSet = "8443-8443".parse().unwrap();
+        assert!(single.contains(8443) && !single.contains(8444));
+    }
+
+    #[test]
+    fn wildcard_covers_every_port() {
+        let ports: PortSet = "*".parse().unwrap();
+
+        assert!(ports.contains(0) && ports.contains(443) && ports.contains(u16::MAX));
+    }
+
+    #[test]
+    fn empty_setting_allows_nothing_extra() {
+        let ports: PortSet = "".parse().unwrap();
+
+        assert_eq!(ports, PortSet::default());
+        assert!(!ports.contains(443));
+    }
+
+    #[test]
+    fn rejects_invalid_entries() {
+        for value in ["ssh", "22, http", "70000", "8000-", "-8000", "1-2-3", "22;443", "8000-80", "443, 9000-8999"] {
+            assert!(value.parse::<PortSet>().is_err(), "{:?} should not parse", value);
+        }
+    }
+}
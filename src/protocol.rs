@@ -0,0 +1,436 @@
+use ring::digest::{digest, SHA256};
+use std::io;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+
+use crate::handlers::connections::Transferred;
+use crate::sni::{self, ClientHello, Parsed};
+use crate::{AppState, OptimizedMonitoringState};
+
+const MAX_SIGNATURE_BYTES: usize = 16; // Undecided after this many bytes means "unknown"
+
+// First bytes of each protocol, from either side of the tunnel
+const SIGNATURES: &[(&[u8], Protocol)] = &[
+    (b"SSH-", Protocol::Ssh),
+    (b"PRI * HTTP/2.0\r\n", Protocol::Http2),
+    (b"HTTP/1.", Protocol::Http),
+    (b"GET ", Protocol::Http),
+    (b"POST ", Protocol::Http),
+    (b"PUT ", Protocol::Http),
+    (b"HEAD ", Protocol::Http),
+    (b"DELETE ", Protocol::Http),
+    (b"OPTIONS ", Protocol::Http),
+    (b"PATCH ", Protocol::Http),
+    (b"TRACE ", Protocol::Http),
+];
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Protocol {
+    Tls,
+    Ssh,
+    Http,
+    Http2,
+    Unknown,
+}
+
+impl Protocol {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Protocol::Tls => "tls",
+            Protocol::Ssh => "ssh",
+            Protocol::Http => "http",
+            Protocol::Http2 => "http2",
+            Protocol::Unknown => "unknown",
+        }
+    }
+}
+
+impl std::str::FromStr for Protocol {
+    type Err = ();
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value.to_ascii_lowercase().as_str() {
+            "tls" => Ok(Protocol::Tls),
+            "ssh" => Ok(Protocol::Ssh),
+            "http" => Ok(Protocol::Http),
+            "http2" => Ok(Protocol::Http2),
+            "unknown" => Ok(Protocol::Unknown),
+            _ => Err(()),
+        }
+    }
+}
+
+// Protocols that may be tunnelled, written as `tls, ssh`
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct ProtocolSet {
+    protocols: Vec<Protocol>,
+}
+
+impl ProtocolSet {
+    pub fn contains(&self, protocol: Protocol) -> bool {
+        self.protocols.contains(&protocol)
+    }
+}
+
+impl std::str::FromStr for ProtocolSet {
+    type Err = ();
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        let protocols = value.split(',')
+            .map(str::trim)
+            .filter(|entry| !entry.is_empty())
+            .map(str::parse)
+            .collect::<Result<_, _>>()?;
+
+        Ok(ProtocolSet { protocols })
+    }
+}
+
+// Classify a stream from its first bytes; None while more bytes are needed
+fn detect(buf: &[u8]) -> Option<Protocol> {
+    match buf {
+        [] | [0x16] => return None,
+        [0x16, 0x03, ..] => return Some(Protocol::Tls),
+        _ => {}
+    }
+
+    for (signature, protocol) in SIGNATURES {
+        if buf.starts_with(signature) {
+            return Some(*protocol);
+        }
+    }
+    let partial = SIGNATURES.iter().any(|(signature, _)| signature.starts_with(buf));
+
+    match partial && buf.len() < MAX_SIGNATURE_BYTES {
+        true => None,
+        false => Some(Protocol::Unknown),
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Direction {
+    Client, // Bytes sent by the client
+    Server, // Bytes sent by the target
+}
+
+// Watches one direction of a tunnel. The first direction to be classified names the
+// tunnel's protocol, which is checked against TUNNEL_PROTOCOLS; TLS streams are
+// followed until the ClientHello (JA4) or ServerHello (negotiated version) is seen.
+pub struct Sniffer {
+    monitoring_state: OptimizedMonitoringState,
+    conn_key: String,
+    allowed: Option<ProtocolSet>,
+    direction: Direction,
+    protocol: Option<Protocol>,
+    seen: Vec<u8>,
+    done: bool,
+}
+
+impl Sniffer {
+    pub fn new(app_state: &AppState, conn_key: &str, direction: Direction) -> Self {
+        Sniffer {
+            monitoring_state: app_state.monitoring_state.clone(),
+            conn_key: conn_key.to_string(),
+            allowed: app_state.config.tunnel_protocols.clone(),
+            direction,
+            protocol: None,
+            seen: Vec::new(),
+            done: false,
+        }
+    }
+
+    // Look at the next bytes in this direction before they are forwarded. Fails when
+    // they reveal a protocol that may not be tunnelled.
+    pub fn observe(&mut self, data: &[u8]) -> io::Result<()> {
+        if self.done || data.is_empty() {
+            return Ok(());
+        }
+        self.seen.extend_from_slice(data);
+
+        let protocol = match self.protocol {
+            Some(protocol) => protocol,
+            None => {
+                let Some(protocol) = detect(&self.seen) else {
+                    return Ok(());
+                };
+                self.protocol = Some(protocol);
+                self.record_protocol(protocol)?;
+                protocol
+            }
+        };
+
+        if protocol != Protocol::Tls {
+            self.finish();
+            return Ok(());
+        }
+
+        let complete = match self.direction {
+            Direction::Client => match sni::parse_client_hello(&self.seen) {
+                Parsed::Complete(hello) => {
+                    self.update(|conn| conn.ja4 = Some(ja4(&hello)));
+                    true
+                }
+                Parsed::Incomplete => false,
+                Parsed::NotTls => true,
+            },
+            Direction::Server => match sni::parse_server_hello(&self.seen) {
+                Parsed::Complete(version) => {
+                    self.update(|conn| conn.tls_version = Some(tls_version_name(version)));
+                    true
+                }
+                Parsed::Incomplete => false,
+                Parsed::NotTls => true,
+            },
+        };
+        if complete || self.seen.len() >= sni::MAX_CLIENT_HELLO_BYTES {
+            self.finish();
+        }
+
+        Ok(())
+    }
+
+    fn record_protocol(&self, protocol: Protocol) -> io::Result<()> {
+        let Some(mut conn) = self.monitoring_state.get_mut(&self.conn_key) else {
+            return Ok(());
+        };
+        if conn.protocol.is_some() {
+            return Ok(());
+        }
+        conn.protocol = Some(protocol.as_str().to_string());
+
+        if self.allowed.as_ref().is_some_and(|allowed| !allowed.contains(protocol)) {
+            tracing::warn!("🚫 Protocol {} may not be tunnelled: {} → {}",
+                protocol.as_str(), conn.client_ip, conn.target_host);
+            conn.status = "protocol_denied".to_string();
+            conn.duration_ms = Some(0);
+            return Err(io::Error::new(
+                io::ErrorKind::PermissionDenied,
+                format!("Protocol {} is not allowed", protocol.as_str()),
+            ));
+        }
+
+        Ok(())
+    }
+
+    fn update(&self, apply: impl FnOnce(&mut crate::ConnectionInfo)) {
+        if let Some(mut conn) = self.monitoring_state.get_mut(&self.conn_key) {
+            apply(&mut conn);
+        }
+    }
+
+    fn finish(&mut self) {
+        self.done = true;
+        self.seen = Vec::new();
+    }
+}
+
+// A stream whose reads pass through a Sniffer
+pub struct Sniffed<T> {
+    inner: T,
+    sniffer: Sniffer,
+}
+
+impl<T> Sniffed<T> {
+    pub fn new(inner: T, sniffer: Sniffer) -> Self {
+        Sniffed { inner, sniffer }
+    }
+}
+
+impl<T: AsyncRead + Unpin> AsyncRead for Sniffed<T> {
+    fn poll_read(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+        let before = buf.filled().len();
+        let this = &mut *self;
+
+        match Pin::new(&mut this.inner).poll_read(cx, buf) {
+            Poll::Ready(Ok(())) => {
+                if let Err(e) = this.sniffer.observe(&buf.filled()[before..]) {
+                    // Drop the bytes that revealed the protocol, so they are never forwarded
+                    buf.set_filled(before);
+                    return Poll::Ready(Err(e));
+                }
+                Poll::Ready(Ok(()))
+            }
+            other => other,
+        }
+    }
+}
+
+impl<T: AsyncWrite + Unpin> AsyncWrite for Sniffed<T> {
+    fn poll_write(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.inner).poll_write(cx, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
+
+// Counts the bytes read from and written to a client's connection
+pub struct CountingIo<T> {
+    inner: T,
+    transferred: Transferred,
+}
+
+impl<T> CountingIo<T> {
+    pub fn new(inner: T, transferred: Transferred) -> Self {
+        CountingIo { inner, transferred }
+    }
+}
+
+impl<T: AsyncRead + Unpin> AsyncRead for CountingIo<T> {
+    fn poll_read(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+        let before = buf.filled().len();
+        let result = Pin::new(&mut self.inner).poll_read(cx, buf);
+        self.transferred.add_sent((buf.filled().len() - before) as u64);
+        result
+    }
+}
+
+impl<T: AsyncWrite + Unpin> AsyncWrite for CountingIo<T> {
+    fn poll_write(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        let result = Pin::new(&mut self.inner).poll_write(cx, buf);
+        if let Poll::Ready(Ok(n)) = &result {
+            self.transferred.add_received(*n as u64);
+        }
+        result
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
+
+pub fn tls_version_name(version: u16) -> String {
+    match version {
+        0x0304 => "TLSv1.3".to_string(),
+        0x0303 => "TLSv1.2".to_string(),
+        0x0302 => "TLSv1.1".to_string(),
+        0x0301 => "TLSv1.0".to_string(),
+        0x0300 => "SSLv3".to_string(),
+        other => format!("{:#06x}", other),
+    }
+}
+
+// JA4 fingerprint of a ClientHello (FoxIO JA4 specification), e.g.
+// `t13d1516h2_8daaf6152771_e5627efa2ab1`
+fn ja4(hello: &ClientHello) -> String {
+    let ciphers: Vec<u16> = hello.cipher_suites.iter().copied().filter(|&value| !is_grease(value)).collect();
+    let extensions: Vec<u16> = hello.extensions.iter().copied().filter(|&value| !is_grease(value)).collect();
+
+    let version = hello.supported_versions.iter()
+        .copied()
+        .filter(|&value| !is_grease(value))
+        .max()
+        .unwrap_or(hello.version);
+    let version = match version {
+        0x0304 => "13",
+        0x0303 => "12",
+        0x0302 => "11",
+        0x0301 => "10",
+        0x0300 => "s3",
+        _ => "00",
+    };
+    let sni = if hello.sni.is_some() { 'd' } else { 'i' };
+    let alpn = match hello.alpn.first().map(String::as_bytes) {
+        Some([first, .., last]) if first.is_ascii_alphanumeric() && last.is_ascii_alphanumeric() => {
+            format!("{}{}", *first as char, *last as char)
+        }
+        Some([only]) if only.is_ascii_alphanumeric() => format!("{}{}", *only as char, *only as char),
+        Some(value @ [_, ..]) => {
+            let hex = format!("{:02x}{:02x}", value[0], value[value.len() - 1]);
+            format!("{}{}", &hex[..1], &hex[3..])
+        }
+        _ => "00".to_string(),
+    };
+
+    let part_a = format!("t{}{}{:02}{:02}{}", version, sni, ciphers.len().min(99), extensions.len().min(99), alpn);
+
+    let mut sorted_ciphers = ciphers;
+    sorted_ciphers.sort_unstable();
+    let part_b = truncated_hash(&hex_list(&sorted_ciphers));
+
+    // The SNI and ALPN extensions are already represented in the first part
+    let mut sorted_extensions: Vec<u16> = extensions.into_iter().filter(|&value| value != 0x0000 && value != 0x0010).collect();
+    sorted_extensions.sort_unstable();
+    let part_c = match (sorted_extensions.is_empty(), hello.signature_algorithms.is_empty()) {
+        (true, _) => truncated_hash(""),
+        (false, true) => truncated_hash(&hex_list(&sorted_extensions)),
+        (false, false) => truncated_hash(&format!(
+            "{}_{}",
+            hex_list(&sorted_extensions),
+            hex_list(&hello.signature_algorithms)
+        )),
+    };
+
+    format!("{}_{}_{}", part_a, part_b, part_c)
+}
+
+// GREASE values (RFC 8701) are random per connection and left out of fingerprints
+fn is_grease(value: u16) -> bool {
+    value & 0x0f0f == 0x0a0a && value >> 8 == value & 0xff
+}
+
+fn hex_list(values: &[u16]) -> String {
+    values.iter().map(|value| format!("{:04x}", value)).collect::<Vec<_>>().join(",")
+}
+
+// First 12 hex characters of the SHA-256 of `value`, or zeros when there is nothing to hash
+fn truncated_hash(value: &str) -> String {
+    if value.is_empty() {
+        return "000000000000".to_string();
+    }
+
+    digest(&SHA256, value.as_bytes()).as_ref()[..6]
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect()
+}
+
+// A stream that first yields bytes already read from it
+pub struct Rewind<T> {
+    prefix: Vec<u8>,
+    position: usize,
+    inner: T,
+}
+
+impl<T> Rewind<T> {
+    pub fn new(inner: T, prefix: Vec<u8>) -> Self {
+        Rewind { prefix, position: 0, inner }
+    }
+}
+
+impl<T: AsyncRead + Unpin> AsyncRead for Rewind<T> {
+    fn poll_read(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+        let this = &mut *self;
+        if this.position < this.prefix.len() {
+            let len = buf.remaining().min(this.prefix.len() - this.position);
+            buf.put_slice(&this.prefix[this.position..this.position + len]);
+            this.position += len;
+            return Poll::Ready(Ok(()));
+        }
+        Pin::new(&mut this.inner).poll_read(cx, buf)
+    }
+}
+
+impl<T: AsyncWrite + Unpin> AsyncWrite for Rewind<T> {
+    fn poll_write(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.inner).poll_write(cx, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
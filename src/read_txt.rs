@@ -1,24 +1,19 @@
-use std::{
-    io::{BufReader,BufRead, Result},
-    fs,
-};
+use std::fs;
 
-pub fn read_file_lines_to_vec(filename: &str) -> Vec<String>{
- let file_in=match fs::File::open(filename){
-    Ok(v)=>{
-        v
-    },
-    Err(e)=>{
-        println!("{}",e);
-        fs::File::create("blocked_sites.txt").unwrap()
-    }
- }; 
- let file_reader = BufReader::new(file_in); 
- file_reader.lines().filter_map(Result::ok).collect()
+// Credentials for authenticated SOCKS5 clients, one `username:password` per line.
+// A missing or empty file means SOCKS5 clients may connect without authentication.
+pub fn read_socks_users() -> Vec<(String, String)> {
+ fs::read_to_string("./socks_users.txt")
+    .unwrap_or_default()
+    .lines()
+    .filter_map(|line| line.split_once(':'))
+    .map(|(user, pass)| (user.trim().to_string(), pass.trim().to_string()))
+    .filter(|(user, _)| !user.is_empty())
+    .collect()
 }
 
-pub fn check_address_block(address_to_check: &str) -> bool {
- let addresses_blocked: Vec<String> = read_file_lines_to_vec(&"./blocked_sites.txt");
- let address_in = addresses_blocked.contains(&address_to_check.to_string());
- return address_in
+pub fn check_socks_credentials(username: &str, password: &str) -> bool {
+ read_socks_users()
+    .iter()
+    .any(|(user, pass)| user == username && pass == password)
 }
@@ -0,0 +1,411 @@
+use base64::Engine;
+use std::fs;
+use std::net::{IpAddr, SocketAddr};
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+use tokio::task::JoinSet;
+
+use crate::dns::Resolver;
+use crate::groups::{client_groups, ip_matches};
+use crate::socks::connect_via_socks5;
+use crate::ClientIdentity;
+
+const MAX_UPSTREAM_RESPONSE_HEADER: usize = 8192;
+const CONNECTION_ATTEMPT_DELAY_MS: u64 = 250; // Head start each address gets before the next one is tried
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum UpstreamKind {
+    HttpConnect,
+    Socks5,
+}
+
+// A parent proxy that tunnels can be chained through
+#[derive(Clone, Debug)]
+pub struct Upstream {
+    pub name: String,
+    pub kind: UpstreamKind,
+    pub addr: String,
+    pub credentials: Option<(String, String)>,
+}
+
+// How a pool picks the upstream for a new tunnel
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PoolStrategy {
+    RoundRobin,
+    LeastConnections,
+    ConsistentHash,
+}
+
+impl PoolStrategy {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            PoolStrategy::RoundRobin => "round-robin",
+            PoolStrategy::LeastConnections => "least-connections",
+            PoolStrategy::ConsistentHash => "consistent-hash",
+        }
+    }
+}
+
+impl std::str::FromStr for PoolStrategy {
+    type Err = ();
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value.to_ascii_lowercase().as_str() {
+            "round-robin" => Ok(PoolStrategy::RoundRobin),
+            "least-connections" => Ok(PoolStrategy::LeastConnections),
+            "consistent-hash" => Ok(PoolStrategy::ConsistentHash),
+            _ => Err(()),
+        }
+    }
+}
+
+// A set of interchangeable upstreams, optionally health checked by connecting
+// through each member to a probe target
+#[derive(Clone, Debug)]
+pub struct Pool {
+    pub name: String,
+    pub strategy: PoolStrategy,
+    pub members: Vec<Upstream>,
+    pub probe: Option<String>,
+}
+
+#[derive(Clone, Debug)]
+pub enum Route {
+    Direct,
+    Upstream(Upstream),
+    Pool(Pool),
+}
+
+impl Route {
+    // Recorded on the connection as "direct", the upstream's name or "pool/upstream"
+    pub fn label(&self, upstream: Option<&Upstream>) -> String {
+        match (self, upstream) {
+            (Route::Pool(pool), Some(upstream)) => format!("{}/{}", pool.name, upstream.name),
+            (_, Some(upstream)) => upstream.name.clone(),
+            _ => "direct".to_string(),
+        }
+    }
+}
+
+struct RouteRule {
+    destination: String,
+    target: String,
+    group: Option<String>,
+}
+
+pub struct RoutingTable {
+    pub upstreams: Vec<Upstream>,
+    pub pools: Vec<Pool>,
+    rules: Vec<RouteRule>,
+}
+
+// Upstreams and routing rules from `routes.txt`:
+//
+//   upstream <name> <http|socks5>://[user:password@]host:port
+//   pool <name> <round-robin|least-connections|consistent-hash> <upstream>... [probe=host:port]
+//   route <destination-pattern> <direct|upstream-name|pool-name> [group=<client-group>]
+//
+// Routes are evaluated top to bottom and the first match wins; without a match
+// the tunnel goes direct.
+pub fn read_routing() -> RoutingTable {
+    let mut upstreams = Vec::new();
+    let mut pool_lines = Vec::new();
+    let mut rules = Vec::new();
+
+    let contents = fs::read_to_string("./routes.txt").unwrap_or_default();
+    for line in contents.lines() {
+        let fields: Vec<&str> = line.split_whitespace().collect();
+        match fields.as_slice() {
+            ["upstream", name, url] => match parse_upstream(name, url) {
+                Some(upstream) => upstreams.push(upstream),
+                None => tracing::warn!("⚠️ Ignoring invalid upstream in routes.txt: {}", line),
+            },
+            ["pool", name, strategy, members @ ..] => match strategy.parse::<PoolStrategy>() {
+                Ok(strategy) => pool_lines.push((name.to_string(), strategy, members.to_vec())),
+                Err(_) => tracing::warn!("⚠️ Ignoring pool with unknown strategy in routes.txt: {}", line),
+            },
+            ["route", destination, target, options @ ..] => {
+                let group = options.iter()
+                    .find_map(|option| option.strip_prefix("group="))
+                    .map(|group| group.to_string());
+                rules.push(RouteRule {
+                    destination: destination.to_string(),
+                    target: target.to_string(),
+                    group,
+                });
+            }
+            [] => {}
+            [comment, ..] if comment.starts_with('#') => {}
+            _ => tracing::warn!("⚠️ Ignoring invalid line in routes.txt: {}", line),
+        }
+    }
+
+    // Pools may list upstreams defined further down the file
+    let pools = pool_lines.into_iter()
+        .map(|(name, strategy, fields)| {
+            let probe = fields.iter()
+                .find_map(|field| field.strip_prefix("probe="))
+                .map(|probe| probe.to_string());
+            let members = fields.iter()
+                .filter(|field| !field.contains('='))
+                .filter_map(|member| {
+                    let upstream = upstreams.iter().find(|upstream| upstream.name == *member);
+                    if upstream.is_none() {
+                        tracing::warn!("⚠️ Pool {} names unknown upstream {}", name, member);
+                    }
+                    upstream.cloned()
+                })
+                .collect();
+            Pool { name, strategy, members, probe }
+        })
+        .collect();
+
+    RoutingTable { upstreams, pools, rules }
+}
+
+impl RoutingTable {
+    // The rules that apply to a client in `groups`, in order, as each destination pattern
+    // and whether it is reached through an upstream. Rules naming an unknown upstream or
+    // an empty pool are left out, as `select_route` skips them.
+    pub fn client_routes(&self, groups: &[String]) -> Vec<(String, bool)> {
+        self.rules.iter()
+            .filter(|rule| rule.group.as_ref().is_none_or(|group| groups.contains(group)))
+            .filter_map(|rule| {
+                let upstream = if rule.target == "direct" {
+                    false
+                } else if self.upstreams.iter().any(|upstream| upstream.name == rule.target)
+                    || self.pools.iter().any(|pool| pool.name == rule.target && !pool.members.is_empty())
+                {
+                    true
+                } else {
+                    return None;
+                };
+                Some((rule.destination.clone(), upstream))
+            })
+            .collect()
+    }
+}
+
+fn parse_upstream(name: &str, url: &str) -> Option<Upstream> {
+    let (scheme, rest) = url.split_once("://")?;
+    let kind = match scheme.to_ascii_lowercase().as_str() {
+        "http" => UpstreamKind::HttpConnect,
+        "socks5" | "socks5h" => UpstreamKind::Socks5,
+        _ => return None,
+    };
+
+    let rest = rest.trim_end_matches('/');
+    let (credentials, addr) = match rest.rsplit_once('@') {
+        Some((userinfo, addr)) => {
+            let (user, pass) = userinfo.split_once(':').unwrap_or((userinfo, ""));
+            (Some((user.to_string(), pass.to_string())), addr)
+        }
+        None => (None, rest),
+    };
+
+    split_host_port(addr).1?;
+
+    Some(Upstream {
+        name: name.to_string(),
+        kind,
+        addr: addr.to_string(),
+        credentials,
+    })
+}
+
+// Pick direct, an upstream or an upstream pool for this client and destination
+pub fn select_route(client: &ClientIdentity, host_addr: &str) -> Route {
+    let RoutingTable { upstreams, pools, rules } = read_routing();
+    let mut groups: Option<Vec<String>> = None;
+
+    for rule in rules {
+        if !destination_matches(&rule.destination, host_addr) {
+            continue;
+        }
+        if let Some(group) = &rule.group {
+            let groups = groups.get_or_insert_with(|| client_groups(client));
+            if !groups.contains(group) {
+                continue;
+            }
+        }
+
+        if rule.target == "direct" {
+            return Route::Direct;
+        }
+        if let Some(upstream) = upstreams.iter().find(|upstream| upstream.name == rule.target) {
+            return Route::Upstream(upstream.clone());
+        }
+        match pools.iter().find(|pool| pool.name == rule.target) {
+            Some(pool) if !pool.members.is_empty() => return Route::Pool(pool.clone()),
+            Some(_) => tracing::warn!("⚠️ Route for {} names empty pool {}", rule.destination, rule.target),
+            None => tracing::warn!("⚠️ Route for {} names unknown upstream {}", rule.destination, rule.target),
+        }
+    }
+
+    Route::Direct
+}
+
+// Match a `host:port` target against a pattern: `*`, `example.com`, `*.example.com`,
+// an IP address or CIDR range, each optionally followed by `:port` or `:*`
+pub fn destination_matches(pattern: &str, host_addr: &str) -> bool {
+    let (host, port) = split_host_port(host_addr);
+    let (host_pattern, port_pattern) = split_host_port(pattern);
+
+    if let Some(port_pattern) = port_pattern {
+        if port_pattern != "*" && Some(port_pattern) != port {
+            return false;
+        }
+    }
+
+    let host = host.trim_start_matches('[').trim_end_matches(']');
+    let host_pattern = host_pattern.trim_start_matches('[').trim_end_matches(']');
+
+    if host_pattern == "*" {
+        return true;
+    }
+    if let Some(suffix) = host_pattern.strip_prefix("*.") {
+        let host = host.to_ascii_lowercase();
+        let suffix = suffix.to_ascii_lowercase();
+        return host.ends_with(&format!(".{}", suffix));
+    }
+    if let Ok(ip) = host.parse::<IpAddr>() {
+        if ip_matches(host_pattern, ip) {
+            return true;
+        }
+    }
+
+    host.eq_ignore_ascii_case(host_pattern)
+}
+
+// Split `host:port` or `[v6]:port`; a bare host (or IPv6 address) has no port
+pub fn split_host_port(addr: &str) -> (&str, Option<&str>) {
+    if addr.starts_with('[') {
+        return match addr.split_once("]:") {
+            Some((host, port)) => (&addr[..host.len() + 1], Some(port)),
+            None => (addr, None),
+        };
+    }
+
+    match addr.rsplit_once(':') {
+        Some((host, port)) if !host.contains(':') => (host, Some(port)),
+        _ => (addr, None),
+    }
+}
+
+// The upstream was reached and answered, but would not connect to the target, e.g. a
+// `403` or `502` reply to CONNECT. This says nothing about the upstream's health.
+#[derive(Debug)]
+pub struct TargetRefused(pub String);
+
+impl std::fmt::Display for TargetRefused {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl std::error::Error for TargetRefused {}
+
+pub fn is_target_refusal(error: &std::io::Error) -> bool {
+    error.get_ref().is_some_and(|inner| inner.is::<TargetRefused>())
+}
+
+// An open stream to the target, the address that won the connection race and the
+// time spent resolving the name (the target itself, or the upstream when chained)
+pub struct Connection {
+    pub stream: TcpStream,
+    pub peer_addr: SocketAddr,
+    pub dns_ms: Option<u64>,
+}
+
+// Open a TCP stream to the target, either directly or tunnelled through the upstream
+pub async fn connect(resolver: &Resolver, upstream: Option<&Upstream>, host_addr: &str) -> std::io::Result<Connection> {
+    let Some(upstream) = upstream else {
+        return connect_tcp(resolver, host_addr).await;
+    };
+
+    let mut connection = connect_tcp(resolver, &upstream.addr).await?;
+    match upstream.kind {
+        UpstreamKind::HttpConnect => http_connect(&mut connection.stream, upstream, host_addr).await?,
+        UpstreamKind::Socks5 => connect_via_socks5(&mut connection.stream, host_addr, upstream.credentials.as_ref()).await?,
+    }
+
+    Ok(connection)
+}
+
+// Resolve `host_addr` and race connections to its addresses, Happy Eyeballs style
+// (RFC 8305): a new attempt starts whenever the previous one fails or has not
+// succeeded within the attempt delay, and the first to connect wins
+async fn connect_tcp(resolver: &Resolver, host_addr: &str) -> std::io::Result<Connection> {
+    let resolution = resolver.resolve(host_addr).await?;
+    let mut pending = resolution.addrs.into_iter();
+    // Dropping the set aborts attempts that are still running
+    let mut attempts = JoinSet::new();
+    let mut last_error = None;
+
+    loop {
+        if let Some(addr) = pending.next() {
+            attempts.spawn(async move { (addr, TcpStream::connect(addr).await) });
+        } else if attempts.is_empty() {
+            break;
+        }
+
+        let finished = if pending.len() > 0 {
+            match tokio::time::timeout(Duration::from_millis(CONNECTION_ATTEMPT_DELAY_MS), attempts.join_next()).await {
+                Ok(finished) => finished,
+                Err(_) => continue,
+            }
+        } else {
+            attempts.join_next().await
+        };
+
+        match finished {
+            Some(Ok((peer_addr, Ok(stream)))) => {
+                return Ok(Connection { stream, peer_addr, dns_ms: resolution.duration_ms });
+            }
+            Some(Ok((addr, Err(e)))) => {
+                tracing::debug!("Connection attempt to {} ({}) failed: {}", host_addr, addr, e);
+                last_error = Some(e);
+            }
+            Some(Err(e)) => last_error = Some(std::io::Error::other(e)),
+            None => {}
+        }
+    }
+
+    Err(last_error.unwrap_or_else(|| std::io::Error::new(std::io::ErrorKind::NotFound, "No address resolved")))
+}
+
+async fn http_connect(stream: &mut TcpStream, upstream: &Upstream, host_addr: &str) -> std::io::Result<()> {
+    let mut request = format!("CONNECT {0} HTTP/1.1\r\nHost: {0}\r\n", host_addr);
+    if let Some((user, pass)) = &upstream.credentials {
+        let token = base64::engine::general_purpose::STANDARD.encode(format!("{}:{}", user, pass));
+        request.push_str(&format!("Proxy-Authorization: Basic {}\r\n", token));
+    }
+    request.push_str("\r\n");
+    stream.write_all(request.as_bytes()).await?;
+
+    // Read the response head byte by byte so no tunnel data is consumed
+    let mut head = Vec::new();
+    while !head.ends_with(b"\r\n\r\n") {
+        if head.len() >= MAX_UPSTREAM_RESPONSE_HEADER {
+            return Err(std::io::Error::new(std::io::ErrorKind::InvalidData, "Upstream response header too large"));
+        }
+        head.push(stream.read_u8().await?);
+    }
+
+    let status_line = String::from_utf8_lossy(&head);
+    let status = status_line.split_whitespace().nth(1).unwrap_or_default();
+    // Rejected credentials are the upstream's problem, any other refusal is about the target
+    if status == "407" {
+        return Err(std::io::Error::new(std::io::ErrorKind::PermissionDenied, format!(
+            "Upstream {} rejected the proxy credentials", upstream.name
+        )));
+    }
+    if !status.starts_with('2') {
+        return Err(std::io::Error::other(TargetRefused(format!(
+            "Upstream {} refused CONNECT with status {}", upstream.name, status
+        ))));
+    }
+
+    Ok(())
+}
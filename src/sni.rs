@@ -0,0 +1,490 @@
+use std::net::IpAddr;
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncReadExt};
+
+use crate::policy::{Access, Verdict};
+use crate::routing::split_host_port;
+use crate::{check_policy, AppState, ClientIdentity};
+
+pub const CLIENT_HELLO_TIMEOUT_SECS: u64 = 5; // Give up waiting for server-speaks-first protocols
+pub const MAX_CLIENT_HELLO_BYTES: usize = 64 * 1024;
+
+const RECORD_HANDSHAKE: u8 = 0x16;
+const HANDSHAKE_CLIENT_HELLO: u8 = 0x01;
+const HANDSHAKE_SERVER_HELLO: u8 = 0x02;
+const EXTENSION_SERVER_NAME: u16 = 0x0000;
+const EXTENSION_SIGNATURE_ALGORITHMS: u16 = 0x000d;
+const EXTENSION_ALPN: u16 = 0x0010;
+const EXTENSION_SUPPORTED_VERSIONS: u16 = 0x002b;
+
+// What to do when the ClientHello names a different host than the CONNECT request
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SniMismatchAction {
+    Allow,
+    Log,
+    Block,
+}
+
+impl std::str::FromStr for SniMismatchAction {
+    type Err = ();
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value.to_ascii_lowercase().as_str() {
+            "allow" => Ok(SniMismatchAction::Allow),
+            "log" => Ok(SniMismatchAction::Log),
+            "block" => Ok(SniMismatchAction::Block),
+            _ => Err(()),
+        }
+    }
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct ClientHello {
+    pub sni: Option<String>,
+    pub alpn: Vec<String>,
+    pub version: u16, // legacy_version field
+    pub cipher_suites: Vec<u16>,
+    pub extensions: Vec<u16>, // Extension types in the order sent
+    pub supported_versions: Vec<u16>,
+    pub signature_algorithms: Vec<u16>,
+}
+
+pub enum Parsed<T = ClientHello> {
+    Complete(T),
+    Incomplete,
+    NotTls,
+}
+
+// Read from the client until a whole ClientHello has arrived. Returns every byte read,
+// which must be forwarded to the target, and the parsed hello if the stream is TLS.
+pub async fn read_client_hello<T>(client: &mut T) -> std::io::Result<(Vec<u8>, Option<ClientHello>)>
+where
+    T: AsyncRead + Unpin,
+{
+    let mut buf = Vec::new();
+
+    let hello = match tokio::time::timeout(
+        Duration::from_secs(CLIENT_HELLO_TIMEOUT_SECS),
+        read_until_client_hello(client, &mut buf)
+    ).await {
+        Ok(result) => result?,
+        Err(_) => None,
+    };
+
+    Ok((buf, hello))
+}
+
+async fn read_until_client_hello<T>(client: &mut T, buf: &mut Vec<u8>) -> std::io::Result<Option<ClientHello>>
+where
+    T: AsyncRead + Unpin,
+{
+    let mut chunk = [0u8; 4096];
+
+    loop {
+        let n = client.read(&mut chunk).await?;
+        if n == 0 {
+            return Ok(None);
+        }
+        buf.extend_from_slice(&chunk[..n]);
+
+        match parse_client_hello(buf) {
+            Parsed::Complete(hello) => return Ok(Some(hello)),
+            Parsed::NotTls => return Ok(None),
+            Parsed::Incomplete if buf.len() >= MAX_CLIENT_HELLO_BYTES => return Ok(None),
+            Parsed::Incomplete => {}
+        }
+    }
+}
+
+// Parse a ClientHello from the start of a TLS stream
+pub fn parse_client_hello(buf: &[u8]) -> Parsed {
+    match read_handshake(buf, HANDSHAKE_CLIENT_HELLO) {
+        Parsed::Complete(body) => match parse_client_hello_body(&body) {
+            Some(hello) => Parsed::Complete(hello),
+            None => Parsed::NotTls,
+        },
+        Parsed::Incomplete => Parsed::Incomplete,
+        Parsed::NotTls => Parsed::NotTls,
+    }
+}
+
+// Parse the ServerHello from the start of the server's side of a TLS stream, returning
+// the negotiated version (from supported_versions for TLS 1.3)
+pub fn parse_server_hello(buf: &[u8]) -> Parsed<u16> {
+    match read_handshake(buf, HANDSHAKE_SERVER_HELLO) {
+        Parsed::Complete(body) => match parse_server_hello_body(&body) {
+            Some(version) => Parsed::Complete(version),
+            None => Parsed::NotTls,
+        },
+        Parsed::Incomplete => Parsed::Incomplete,
+        Parsed::NotTls => Parsed::NotTls,
+    }
+}
+
+// Body of the first handshake message, which must be of `message_type`, reassembled
+// when it is split across several records
+fn read_handshake(buf: &[u8], message_type: u8) -> Parsed<Vec<u8>> {
+    let mut handshake = Vec::new();
+    let mut offset = 0;
+
+    loop {
+        let Some(header) = buf.get(offset..offset + 5) else {
+            return Parsed::Incomplete;
+        };
+        // Content type handshake, protocol major version 3
+        if header[0] != RECORD_HANDSHAKE || header[1] != 0x03 {
+            return Parsed::NotTls;
+        }
+
+        let record_len = u16::from_be_bytes([header[3], header[4]]) as usize;
+        let Some(fragment) = buf.get(offset + 5..offset + 5 + record_len) else {
+            return Parsed::Incomplete;
+        };
+        handshake.extend_from_slice(fragment);
+        offset += 5 + record_len;
+
+        if handshake.len() < 4 {
+            continue;
+        }
+        if handshake[0] != message_type {
+            return Parsed::NotTls;
+        }
+        let message_len = u32::from_be_bytes([0, handshake[1], handshake[2], handshake[3]]) as usize;
+        if handshake.len() < 4 + message_len {
+            continue;
+        }
+
+        handshake.truncate(4 + message_len);
+        return Parsed::Complete(handshake.split_off(4));
+    }
+}
+
+fn parse_client_hello_body(body: &[u8]) -> Option<ClientHello> {
+    let mut reader = Reader { buf: body, pos: 0 };
+    let mut hello = ClientHello { version: reader.u16()?, ..ClientHello::default() };
+    reader.skip(32)?; // random
+    let session_id_len = reader.u8()? as usize;
+    reader.skip(session_id_len)?;
+    let cipher_suites_len = reader.u16()? as usize;
+    let mut cipher_suites = Reader { buf: reader.take(cipher_suites_len)?, pos: 0 };
+    while cipher_suites.remaining() > 0 {
+        hello.cipher_suites.push(cipher_suites.u16()?);
+    }
+    let compression_len = reader.u8()? as usize;
+    reader.skip(compression_len)?;
+
+    if reader.remaining() == 0 {
+        return Some(hello);
+    }
+
+    let extensions_len = reader.u16()? as usize;
+    let mut extensions = Reader { buf: reader.take(extensions_len)?, pos: 0 };
+    while extensions.remaining() > 0 {
+        let extension_type = extensions.u16()?;
+        let extension_len = extensions.u16()? as usize;
+        let mut data = Reader { buf: extensions.take(extension_len)?, pos: 0 };
+        hello.extensions.push(extension_type);
+
+        match extension_type {
+            EXTENSION_SERVER_NAME => {
+                let list_len = data.u16()? as usize;
+                let mut names = Reader { buf: data.take(list_len)?, pos: 0 };
+                while names.remaining() > 0 {
+                    let name_type = names.u8()?;
+                    let name_len = names.u16()? as usize;
+                    let name = names.take(name_len)?;
+                    if name_type == 0 {
+                        hello.sni = Some(String::from_utf8_lossy(name).to_ascii_lowercase());
+                    }
+                }
+            }
+            EXTENSION_ALPN => {
+                let list_len = data.u16()? as usize;
+                let mut protocols = Reader { buf: data.take(list_len)?, pos: 0 };
+                while protocols.remaining() > 0 {
+                    let protocol_len = protocols.u8()? as usize;
+                    hello.alpn.push(String::from_utf8_lossy(protocols.take(protocol_len)?).into_owned());
+                }
+            }
+            EXTENSION_SUPPORTED_VERSIONS => {
+                let list_len = data.u8()? as usize;
+                let mut versions = Reader { buf: data.take(list_len)?, pos: 0 };
+                while versions.remaining() > 0 {
+                    hello.supported_versions.push(versions.u16()?);
+                }
+            }
+            EXTENSION_SIGNATURE_ALGORITHMS => {
+                let list_len = data.u16()? as usize;
+                let mut algorithms = Reader { buf: data.take(list_len)?, pos: 0 };
+                while algorithms.remaining() > 0 {
+                    hello.signature_algorithms.push(algorithms.u16()?);
+                }
+            }
+            _ => {}
+        }
+    }
+
+    Some(hello)
+}
+
+fn parse_server_hello_body(body: &[u8]) -> Option<u16> {
+    let mut reader = Reader { buf: body, pos: 0 };
+    let legacy_version = reader.u16()?;
+    reader.skip(32)?; // random
+    let session_id_len = reader.u8()? as usize;
+    reader.skip(session_id_len)?;
+    reader.skip(2 + 1)?; // cipher_suite, compression_method
+
+    if reader.remaining() == 0 {
+        return Some(legacy_version);
+    }
+
+    let extensions_len = reader.u16()? as usize;
+    let mut extensions = Reader { buf: reader.take(extensions_len)?, pos: 0 };
+    while extensions.remaining() > 0 {
+        let extension_type = extensions.u16()?;
+        let extension_len = extensions.u16()? as usize;
+        let mut data = Reader { buf: extensions.take(extension_len)?, pos: 0 };
+        if extension_type == EXTENSION_SUPPORTED_VERSIONS {
+            return data.u16();
+        }
+    }
+
+    Some(legacy_version)
+}
+
+struct Reader<'a> {
+    buf: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn remaining(&self) -> usize {
+        self.buf.len() - self.pos
+    }
+
+    fn take(&mut self, len: usize) -> Option<&'a [u8]> {
+        let bytes = self.buf.get(self.pos..self.pos + len)?;
+        self.pos += len;
+        Some(bytes)
+    }
+
+    fn skip(&mut self, len: usize) -> Option<()> {
+        self.take(len).map(|_| ())
+    }
+
+    fn u8(&mut self) -> Option<u8> {
+        self.take(1).map(|bytes| bytes[0])
+    }
+
+    fn u16(&mut self) -> Option<u16> {
+        self.take(2).map(|bytes| u16::from_be_bytes([bytes[0], bytes[1]]))
+    }
+}
+
+// Re-run the policy against the ClientHello: the SNI must not be blocked and, depending
+// on SNI_MISMATCH, must name the CONNECT host. Returns the outcome to record on rejection.
+pub async fn check_client_hello(
+    app_state: &AppState,
+    client: &ClientIdentity,
+    host_addr: &str,
+    hello: &ClientHello,
+) -> Result<(), &'static str> {
+    let Some(sni) = &hello.sni else {
+        return Ok(());
+    };
+    let (host, port) = split_host_port(host_addr);
+    let port = port.unwrap_or_default();
+
+    // The same evaluation as the CONNECT itself, so shadow mode and `/policy/evaluate`
+    // see the SNI decision too. An SNI naming the CONNECT host was already evaluated.
+    if !host.eq_ignore_ascii_case(sni) {
+        let evaluation = check_policy(app_state, client, &format!("{}:{}", sni, port), Access::Connect);
+        if evaluation.verdict == Verdict::Blocked {
+            tracing::warn!("🚫 SNI {} is blocked by {} (CONNECT {})", sni, evaluation.rule.unwrap_or_default(), host_addr);
+            return Err("sni_blocked");
+        }
+    }
+
+    let action = app_state.config.sni_mismatch;
+    if action == SniMismatchAction::Allow || sni_matches(app_state, host, sni, port).await {
+        return Ok(());
+    }
+
+    tracing::warn!("⚠️ SNI mismatch: CONNECT {} but ClientHello names {}", host_addr, sni);
+    match action {
+        SniMismatchAction::Block => Err("sni_mismatch"),
+        _ => Ok(()),
+    }
+}
+
+// A CONNECT to a name must carry the same name; a CONNECT to an IP address must
+// carry a name that resolves to that address
+async fn sni_matches(app_state: &AppState, host: &str, sni: &str, port: &str) -> bool {
+    let host = host.trim_start_matches('[').trim_end_matches(']');
+
+    match host.parse::<IpAddr>() {
+        Ok(ip) => match app_state.resolver.resolve(&format!("{}:{}", sni, port)).await {
+            Ok(resolution) => resolution.addrs.iter().any(|addr| addr.ip() == ip),
+            Err(_) => false,
+        },
+        Err(_) => host.eq_ignore_ascii_case(sni),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn with_u16_len(data: &[u8]) -> Vec<u8> {
+        [&(data.len() as u16).to_be_bytes()[..], data].concat()
+    }
+
+    fn extension(extension_type: u16, data: &[u8]) -> Vec<u8> {
+        [&extension_type.to_be_bytes()[..], &with_u16_len(data)].concat()
+    }
+
+    fn handshake(message_type: u8, body: &[u8]) -> Vec<u8> {
+        let len = (body.len() as u32).to_be_bytes();
+        [&[message_type, len[1], len[2], len[3]][..], body].concat()
+    }
+
+    fn record(fragment: &[u8]) -> Vec<u8> {
+        [&[RECORD_HANDSHAKE, 0x03, 0x01][..], &with_u16_len(fragment)].concat()
+    }
+
+    fn client_hello(extensions: &[Vec<u8>]) -> Vec<u8> {
+        let mut body = vec![0x03, 0x03];
+        body.extend_from_slice(&[0u8; 32]);
+        body.extend_from_slice(&[0x00]); // empty session ID
+        body.extend_from_slice(&with_u16_len(&[0x13, 0x01, 0xc0, 0x2f]));
+        body.extend_from_slice(&[0x01, 0x00]); // null compression
+        body.extend_from_slice(&with_u16_len(&extensions.concat()));
+        handshake(HANDSHAKE_CLIENT_HELLO, &body)
+    }
+
+    fn server_name(name: &str) -> Vec<u8> {
+        let entry = [&[0x00][..], &with_u16_len(name.as_bytes())].concat();
+        extension(EXTENSION_SERVER_NAME, &with_u16_len(&entry))
+    }
+
+    fn parsed_client_hello(buf: &[u8]) -> Option<ClientHello> {
+        match parse_client_hello(buf) {
+            Parsed::Complete(hello) => Some(hello),
+            Parsed::Incomplete | Parsed::NotTls => None,
+        }
+    }
+
+    #[test]
+    fn parses_the_fields_used_for_matching_and_fingerprints() {
+        let extensions = [
+            server_name("Example.COM"),
+            extension(EXTENSION_ALPN, &with_u16_len(b"\x02h2\x08http/1.1")),
+            extension(EXTENSION_SUPPORTED_VERSIONS, &[0x04, 0x03, 0x04, 0x03, 0x03]),
+            extension(EXTENSION_SIGNATURE_ALGORITHMS, &with_u16_len(&[0x04, 0x03, 0x08, 0x04])),
+            extension(0xff01, &[0x00]),
+        ];
+
+        let hello = parsed_client_hello(&record(&client_hello(&extensions))).unwrap();
+
+        assert_eq!(hello, ClientHello {
+            sni: Some("example.com".to_string()),
+            alpn: vec!["h2".to_string(), "http/1.1".to_string()],
+            version: 0x0303,
+            cipher_suites: vec![0x1301, 0xc02f],
+            extensions: vec![0x0000, 0x0010, 0x002b, 0x000d, 0xff01],
+            supported_versions: vec![0x0304, 0x0303],
+            signature_algorithms: vec![0x0403, 0x0804],
+        });
+    }
+
+    #[test]
+    fn parses_a_hello_without_extensions() {
+        let mut message = client_hello(&[]);
+        message.truncate(message.len() - 2);
+        message[3] -= 2;
+
+        let hello = parsed_client_hello(&record(&message)).unwrap();
+
+        assert_eq!(hello.sni, None);
+        assert_eq!(hello.cipher_suites, vec![0x1301, 0xc02f]);
+        assert!(hello.extensions.is_empty());
+    }
+
+    #[test]
+    fn reassembles_a_hello_split_across_records() {
+        let message = client_hello(&[server_name("split.example")]);
+        let (first, second) = message.split_at(3);
+        let stream = [record(first), record(second)].concat();
+
+        assert!(matches!(parse_client_hello(&stream[..stream.len() - 1]), Parsed::Incomplete));
+        assert_eq!(parsed_client_hello(&stream).unwrap().sni.as_deref(), Some("split.example"));
+    }
+
+    #[test]
+    fn recognises_streams_that_are_not_a_client_hello() {
+        assert!(matches!(parse_client_hello(b"GET / HTTP/1.1\r\n\r\n"), Parsed::NotTls));
+        assert!(matches!(parse_client_hello(&[RECORD_HANDSHAKE, 0x03]), Parsed::Incomplete));
+        assert!(matches!(
+            parse_client_hello(&record(&handshake(HANDSHAKE_SERVER_HELLO, &[0x03, 0x03]))),
+            Parsed::NotTls
+        ));
+
+        // A server_name list longer than its extension
+        let broken = extension(EXTENSION_SERVER_NAME, &[0x00, 0x10, 0x00]);
+        assert!(matches!(parse_client_hello(&record(&client_hello(&[broken]))), Parsed::NotTls));
+    }
+
+    #[tokio::test]
+    async fn keeps_every_byte_read_for_forwarding() {
+        let mut stream = record(&client_hello(&[server_name("example.com")]));
+        stream.extend_from_slice(b"early data");
+
+        let (buf, hello) = read_client_hello(&mut stream.as_slice()).await.unwrap();
+        assert_eq!(buf, stream);
+        assert_eq!(hello.unwrap().sni.as_deref(), Some("example.com"));
+
+        let (buf, hello) = read_client_hello(&mut &b"SSH-2.0-OpenSSH_9.6\r\n"[..]).await.unwrap();
+        assert_eq!(buf, b"SSH-2.0-OpenSSH_9.6\r\n");
+        assert_eq!(hello, None);
+    }
+
+    fn server_hello(extensions: Option<&[Vec<u8>]>) -> Vec<u8> {
+        let mut body = vec![0x03, 0x03];
+        body.extend_from_slice(&[0u8; 32]);
+        body.extend_from_slice(&[0x00, 0x13, 0x01, 0x00]); // session ID, cipher suite, compression
+        if let Some(extensions) = extensions {
+            body.extend_from_slice(&with_u16_len(&extensions.concat()));
+        }
+        handshake(HANDSHAKE_SERVER_HELLO, &body)
+    }
+
+    fn negotiated_version(buf: &[u8]) -> Option<u16> {
+        match parse_server_hello(buf) {
+            Parsed::Complete(version) => Some(version),
+            Parsed::Incomplete | Parsed::NotTls => None,
+        }
+    }
+
+    #[test]
+    fn reads_tls_1_3_from_supported_versions() {
+        let extensions = [extension(0x0033, &[0x00, 0x1d]), extension(EXTENSION_SUPPORTED_VERSIONS, &[0x03, 0x04])];
+
+        assert_eq!(negotiated_version(&record(&server_hello(Some(&extensions)))), Some(0x0304));
+    }
+
+    #[test]
+    fn falls_back_to_the_legacy_version() {
+        assert_eq!(negotiated_version(&record(&server_hello(None))), Some(0x0303));
+        assert_eq!(negotiated_version(&record(&server_hello(Some(&[extension(0xff01, &[0x00])])))), Some(0x0303));
+    }
+
+    #[test]
+    fn waits_for_the_whole_server_hello() {
+        let stream = record(&server_hello(None));
+
+        assert!(matches!(parse_server_hello(&stream[..20]), Parsed::Incomplete));
+        assert!(matches!(parse_server_hello(&record(&client_hello(&[]))), Parsed::NotTls));
+        assert!(matches!(parse_server_hello(&[0x15, 0x03, 0x03, 0x00, 0x02, 0x02, 0x28]), Parsed::NotTls));
+    }
+}
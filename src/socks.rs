@@ -0,0 +1,679 @@
+use std::collections::{HashMap, HashSet};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::time::Duration;
+use chrono::Utc;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpStream, UdpSocket};
+use tokio::sync::mpsc;
+
+use crate::read_txt::{check_socks_credentials, read_socks_users};
+use crate::{
+    AppState,
+    ClientIdentity,
+    CONNECTION_TIMEOUT_SECS,
+    TUNNEL_TIMEOUT_SECS,
+    check_policy,
+    dial,
+    mark_connection_failed,
+    record_allowed,
+    record_blocked,
+    inspect_client_hello,
+    record_tunnel_result,
+    relay_with_preface,
+    terminable,
+    transferred,
+};
+use crate::mitm;
+use crate::policy::{Access, Verdict};
+use crate::routing::{split_host_port, TargetRefused};
+use crate::udp::{self, UDP_CHANNEL_CAPACITY};
+
+// SOCKS5 protocol constants (RFC 1928 / RFC 1929)
+pub const SOCKS_VERSION: u8 = 0x05;
+const AUTH_VERSION: u8 = 0x01;
+
+const METHOD_NO_AUTH: u8 = 0x00;
+const METHOD_USER_PASS: u8 = 0x02;
+const METHOD_NO_ACCEPTABLE: u8 = 0xff;
+
+const CMD_CONNECT: u8 = 0x01;
+const CMD_UDP_ASSOCIATE: u8 = 0x03;
+
+const ATYP_IPV4: u8 = 0x01;
+const ATYP_DOMAIN: u8 = 0x03;
+const ATYP_IPV6: u8 = 0x04;
+
+const REP_SUCCEEDED: u8 = 0x00;
+const REP_GENERAL_FAILURE: u8 = 0x01;
+const REP_NOT_ALLOWED: u8 = 0x02;
+const REP_NETWORK_UNREACHABLE: u8 = 0x03;
+const REP_HOST_UNREACHABLE: u8 = 0x04;
+const REP_CONNECTION_REFUSED: u8 = 0x05;
+const REP_TTL_EXPIRED: u8 = 0x06;
+const REP_COMMAND_NOT_SUPPORTED: u8 = 0x07;
+const REP_ADDRESS_NOT_SUPPORTED: u8 = 0x08;
+
+// A parsed SOCKS5 request: the command, the target as a `host:port` authority
+// and the username the client authenticated with, if any
+struct SocksRequest {
+    command: u8,
+    host_addr: String,
+    user: Option<String>,
+}
+
+pub async fn handle_socks5(
+    mut stream: TcpStream,
+    client_ip: IpAddr,
+    app_state: AppState,
+) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    let request = tokio::time::timeout(
+        Duration::from_secs(CONNECTION_TIMEOUT_SECS),
+        negotiate(&mut stream, &client_ip.to_string())
+    ).await??;
+
+    let Some(request) = request else {
+        return Ok(());
+    };
+
+    let client = ClientIdentity::new(client_ip.to_string(), request.user);
+
+    if request.command == CMD_UDP_ASSOCIATE {
+        udp_associate(stream, client, app_state).await?;
+        return Ok(());
+    }
+
+    if request.command != CMD_CONNECT {
+        tracing::warn!("⚠️ Unsupported SOCKS5 command {:#04x} from {}", request.command, client);
+        send_reply(&mut stream, REP_COMMAND_NOT_SUPPORTED, None).await?;
+        return Ok(());
+    }
+
+    let host_addr = request.host_addr;
+
+    // Same policy check as the HTTP CONNECT handler
+    let evaluation = check_policy(&app_state, &client, &host_addr, Access::Connect);
+    if evaluation.verdict != Verdict::Allowed {
+        record_blocked(&app_state, &client, &host_addr, None, "tcp", evaluation.verdict.as_str()).await;
+        send_reply(&mut stream, REP_NOT_ALLOWED, None).await?;
+        return Ok(());
+    }
+
+    let conn_key = record_allowed(&app_state, &client, &host_addr, None, "tcp").await;
+
+    terminable(&app_state, &conn_key, &client, &host_addr, connect_tunnel(stream, &app_state, &conn_key, &client, &host_addr))
+        .await
+        .unwrap_or(Ok(()))
+}
+
+// Connect to an allowed target, reply to the client and relay until either side is done
+async fn connect_tunnel(
+    mut stream: TcpStream,
+    app_state: &AppState,
+    conn_key: &str,
+    client: &ClientIdentity,
+    host_addr: &str,
+) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    let server = match dial(app_state, conn_key, client, host_addr).await {
+        Ok(server) => server,
+        Err(e) => {
+            tracing::error!("❌ Tunnel error: {} → {} | Error: {}", client, host_addr, e);
+            mark_connection_failed(&app_state.monitoring_state, conn_key);
+            send_reply(&mut stream, reply_code_for(&e), None).await?;
+            return Ok(());
+        }
+    };
+
+    let start_time = Utc::now();
+    if let Err(e) = send_reply(&mut stream, REP_SUCCEEDED, server.local_addr().ok()).await {
+        record_tunnel_result(app_state, conn_key, client, host_addr, start_time, Ok(Err(e))).await;
+        return Ok(());
+    }
+
+    let tunnel_result = tokio::time::timeout(
+        Duration::from_secs(TUNNEL_TIMEOUT_SECS),
+        async {
+            if mitm::should_intercept(app_state, host_addr) {
+                return mitm::intercept(stream, server, app_state, conn_key, client, host_addr).await;
+            }
+            let preface = inspect_client_hello(&mut stream, app_state, conn_key, client, host_addr).await?;
+            relay_with_preface(app_state, conn_key, stream, server, &preface).await
+        }
+    ).await;
+
+    record_tunnel_result(app_state, conn_key, client, host_addr, start_time, tunnel_result).await;
+
+    Ok(())
+}
+
+// Run method selection, optional username/password authentication and read the request.
+// Returns `None` when the client was rejected and a reply has already been sent.
+async fn negotiate(stream: &mut TcpStream, client_ip: &str) -> std::io::Result<Option<SocksRequest>> {
+    let version = stream.read_u8().await?;
+    if version != SOCKS_VERSION {
+        return Err(std::io::Error::new(std::io::ErrorKind::InvalidData, "Unsupported SOCKS version"));
+    }
+
+    let method_count = stream.read_u8().await? as usize;
+    let mut methods = vec![0u8; method_count];
+    stream.read_exact(&mut methods).await?;
+
+    let auth_required = !read_socks_users().is_empty();
+    let method = if auth_required { METHOD_USER_PASS } else { METHOD_NO_AUTH };
+
+    if !methods.contains(&method) {
+        tracing::warn!("🚫 SOCKS5 client {} offered no acceptable auth method", client_ip);
+        stream.write_all(&[SOCKS_VERSION, METHOD_NO_ACCEPTABLE]).await?;
+        return Ok(None);
+    }
+    stream.write_all(&[SOCKS_VERSION, method]).await?;
+
+    let user = if method == METHOD_USER_PASS {
+        match authenticate(stream, client_ip).await? {
+            Some(user) => Some(user),
+            None => return Ok(None),
+        }
+    } else {
+        None
+    };
+
+    let version = stream.read_u8().await?;
+    if version != SOCKS_VERSION {
+        return Err(std::io::Error::new(std::io::ErrorKind::InvalidData, "Unsupported SOCKS version"));
+    }
+    let command = stream.read_u8().await?;
+    let _reserved = stream.read_u8().await?;
+
+    let host = match stream.read_u8().await? {
+        ATYP_IPV4 => {
+            let mut octets = [0u8; 4];
+            stream.read_exact(&mut octets).await?;
+            IpAddr::V4(Ipv4Addr::from(octets)).to_string()
+        }
+        ATYP_IPV6 => {
+            let mut octets = [0u8; 16];
+            stream.read_exact(&mut octets).await?;
+            format!("[{}]", Ipv6Addr::from(octets))
+        }
+        ATYP_DOMAIN => {
+            let len = stream.read_u8().await? as usize;
+            let mut domain = vec![0u8; len];
+            stream.read_exact(&mut domain).await?;
+            match String::from_utf8(domain) {
+                Ok(domain) => domain,
+                Err(_) => {
+                    send_reply(stream, REP_ADDRESS_NOT_SUPPORTED, None).await?;
+                    return Ok(None);
+                }
+            }
+        }
+        _ => {
+            send_reply(stream, REP_ADDRESS_NOT_SUPPORTED, None).await?;
+            return Ok(None);
+        }
+    };
+    let port = stream.read_u16().await?;
+
+    Ok(Some(SocksRequest {
+        command,
+        host_addr: format!("{}:{}", host, port),
+        user,
+    }))
+}
+
+// Username/password sub-negotiation (RFC 1929), returning the authenticated username
+async fn authenticate(stream: &mut TcpStream, client_ip: &str) -> std::io::Result<Option<String>> {
+    let version = stream.read_u8().await?;
+    if version != AUTH_VERSION {
+        return Err(std::io::Error::new(std::io::ErrorKind::InvalidData, "Unsupported SOCKS auth version"));
+    }
+
+    let username_len = stream.read_u8().await? as usize;
+    let mut username = vec![0u8; username_len];
+    stream.read_exact(&mut username).await?;
+
+    let password_len = stream.read_u8().await? as usize;
+    let mut password = vec![0u8; password_len];
+    stream.read_exact(&mut password).await?;
+
+    let username = String::from_utf8_lossy(&username);
+    let password = String::from_utf8_lossy(&password);
+
+    if check_socks_credentials(&username, &password) {
+        tracing::debug!("SOCKS5 client {} authenticated as {}", client_ip, username);
+        stream.write_all(&[AUTH_VERSION, 0x00]).await?;
+        Ok(Some(username.into_owned()))
+    } else {
+        tracing::warn!("🚫 SOCKS5 authentication failed for {} (user {})", client_ip, username);
+        stream.write_all(&[AUTH_VERSION, 0x01]).await?;
+        Ok(None)
+    }
+}
+
+// Client side of SOCKS5 for chaining through an upstream proxy: negotiate (with
+// username/password when configured) and CONNECT to `host_addr`
+pub async fn connect_via_socks5(
+    stream: &mut TcpStream,
+    host_addr: &str,
+    credentials: Option<&(String, String)>,
+) -> std::io::Result<()> {
+    let method = if credentials.is_some() { METHOD_USER_PASS } else { METHOD_NO_AUTH };
+    stream.write_all(&[SOCKS_VERSION, 1, method]).await?;
+
+    let mut choice = [0u8; 2];
+    stream.read_exact(&mut choice).await?;
+    if choice[0] != SOCKS_VERSION || choice[1] != method {
+        return Err(std::io::Error::new(std::io::ErrorKind::PermissionDenied, "Upstream SOCKS5 proxy rejected the auth method"));
+    }
+
+    if let Some((user, pass)) = credentials {
+        let mut buf = vec![AUTH_VERSION];
+        push_field(&mut buf, user, "username")?;
+        push_field(&mut buf, pass, "password")?;
+        stream.write_all(&buf).await?;
+
+        let mut status = [0u8; 2];
+        stream.read_exact(&mut status).await?;
+        if status[1] != 0x00 {
+            return Err(std::io::Error::new(std::io::ErrorKind::PermissionDenied, "Upstream SOCKS5 authentication failed"));
+        }
+    }
+
+    let (host, port) = split_host_port(host_addr);
+    let port: u16 = port.and_then(|port| port.parse().ok())
+        .ok_or_else(|| std::io::Error::new(std::io::ErrorKind::InvalidInput, "Target has no valid port"))?;
+    let host = host.trim_start_matches('[').trim_end_matches(']');
+
+    let mut request = vec![SOCKS_VERSION, CMD_CONNECT, 0x00];
+    match host.parse::<IpAddr>() {
+        Ok(ip) => encode_addr(&mut request, SocketAddr::new(ip, port)),
+        Err(_) => {
+            request.push(ATYP_DOMAIN);
+            push_field(&mut request, host, "target host")?;
+            request.extend_from_slice(&port.to_be_bytes());
+        }
+    }
+    stream.write_all(&request).await?;
+
+    let mut reply = [0u8; 4];
+    stream.read_exact(&mut reply).await?;
+    let bound_len = match reply[3] {
+        ATYP_IPV4 => 4,
+        ATYP_IPV6 => 16,
+        ATYP_DOMAIN => stream.read_u8().await? as usize,
+        _ => return Err(std::io::Error::new(std::io::ErrorKind::InvalidData, "Invalid upstream SOCKS5 reply")),
+    };
+    let mut bound_addr = vec![0u8; bound_len + 2];
+    stream.read_exact(&mut bound_addr).await?;
+
+    if reply[1] != REP_SUCCEEDED {
+        return Err(std::io::Error::other(TargetRefused(format!(
+            "Upstream SOCKS5 proxy refused CONNECT with reply {:#04x}", reply[1]
+        ))));
+    }
+
+    Ok(())
+}
+
+// UDP ASSOCIATE: relay datagrams for the client until its control connection closes.
+// Every destination gets its own flow with a policy check, idle timeout and connection record.
+async fn udp_associate(mut stream: TcpStream, client: ClientIdentity, app_state: AppState) -> std::io::Result<()> {
+    let relay_socket = UdpSocket::bind(SocketAddr::new(stream.local_addr()?.ip(), 0)).await?;
+    send_reply(&mut stream, REP_SUCCEEDED, relay_socket.local_addr().ok()).await?;
+
+    tracing::info!("📦 UDP ASSOCIATE: {} via {}", client, relay_socket.local_addr()?);
+
+    let (inbound_tx, mut inbound_rx) = mpsc::channel::<Vec<u8>>(UDP_CHANNEL_CAPACITY);
+    let mut flows: HashMap<String, mpsc::Sender<Vec<u8>>> = HashMap::new();
+    let mut denied: HashSet<String> = HashSet::new();
+    let mut client_addr: Option<SocketAddr> = None;
+
+    let mut buf = vec![0u8; 65535];
+    let mut control_buf = [0u8; 1];
+
+    loop {
+        tokio::select! {
+            read = stream.read(&mut control_buf) => match read {
+                Ok(0) | Err(_) => break,
+                Ok(_) => continue,
+            },
+            Some(datagram) = inbound_rx.recv() => {
+                if let Some(addr) = client_addr {
+                    let _ = relay_socket.send_to(&datagram, addr).await;
+                }
+            }
+            received = relay_socket.recv_from(&mut buf) => {
+                let (len, from) = received?;
+                if from.ip().to_string() != client.ip {
+                    continue;
+                }
+                client_addr = Some(from);
+
+                let Some((host_addr, payload)) = parse_udp_datagram(&buf[..len]) else {
+                    continue;
+                };
+
+                if let Some(flow) = flows.get(&host_addr) {
+                    match flow.try_send(payload.to_vec()) {
+                        Err(mpsc::error::TrySendError::Closed(_)) => {
+                            flows.remove(&host_addr);
+                        }
+                        _ => continue,
+                    }
+                }
+
+                if denied.contains(&host_addr) {
+                    continue;
+                }
+
+                let evaluation = check_policy(&app_state, &client, &host_addr, Access::Udp);
+                if evaluation.verdict != Verdict::Allowed {
+                    record_blocked(&app_state, &client, &host_addr, None, "udp", evaluation.verdict.as_str()).await;
+                    denied.insert(host_addr);
+                    continue;
+                }
+
+                let (flow_tx, flow_rx) = mpsc::channel::<Vec<u8>>(UDP_CHANNEL_CAPACITY);
+                let _ = flow_tx.try_send(payload.to_vec());
+                flows.insert(host_addr.clone(), flow_tx);
+
+                tokio::spawn(socks_udp_flow(
+                    app_state.clone(),
+                    client.clone(),
+                    host_addr,
+                    flow_rx,
+                    inbound_tx.clone(),
+                ));
+            }
+        }
+    }
+
+    tracing::debug!("UDP ASSOCIATE closed for {}", client);
+    Ok(())
+}
+
+async fn socks_udp_flow(
+    app_state: AppState,
+    client: ClientIdentity,
+    host_addr: String,
+    outbound: mpsc::Receiver<Vec<u8>>,
+    inbound: mpsc::Sender<Vec<u8>>,
+) {
+    let conn_key = record_allowed(&app_state, &client, &host_addr, None, "udp").await;
+
+    terminable(&app_state, &conn_key, &client, &host_addr, async {
+        let socket = match udp::connect_target(&app_state.resolver, &host_addr).await {
+            Ok(socket) => socket,
+            Err(e) => {
+                tracing::error!("❌ UDP target error: {} → {} | Error: {}", client, host_addr, e);
+                mark_connection_failed(&app_state.monitoring_state, &conn_key);
+                return;
+            }
+        };
+
+        let mut header = vec![0x00, 0x00, 0x00];
+        if let Ok(peer) = socket.peer_addr() {
+            encode_addr(&mut header, peer);
+        }
+
+        let start_time = Utc::now();
+        let result = udp::run_flow(socket, outbound, inbound, |data| [header.as_slice(), data].concat(), transferred(&app_state, &conn_key)).await;
+
+        record_tunnel_result(&app_state, &conn_key, &client, &host_addr, start_time, Ok(result)).await;
+    }).await;
+}
+
+// Split a client datagram (RSV, FRAG, ATYP, DST.ADDR, DST.PORT, DATA) into target and payload.
+// Fragmented datagrams are not supported and are dropped.
+fn parse_udp_datagram(buf: &[u8]) -> Option<(String, &[u8])> {
+    if buf.len() < 4 || buf[2] != 0x00 {
+        return None;
+    }
+
+    let (host, rest) = match buf[3] {
+        ATYP_IPV4 if buf.len() >= 10 => {
+            let octets: [u8; 4] = buf[4..8].try_into().ok()?;
+            (Ipv4Addr::from(octets).to_string(), &buf[8..])
+        }
+        ATYP_IPV6 if buf.len() >= 22 => {
+            let octets: [u8; 16] = buf[4..20].try_into().ok()?;
+            (format!("[{}]", Ipv6Addr::from(octets)), &buf[20..])
+        }
+        ATYP_DOMAIN if buf.len() >= 5 => {
+            let len = buf[4] as usize;
+            let domain = buf.get(5..5 + len)?;
+            (String::from_utf8(domain.to_vec()).ok()?, &buf[5 + len..])
+        }
+        _ => return None,
+    };
+
+    let port = u16::from_be_bytes(rest.get(..2)?.try_into().ok()?);
+    Some((format!("{}:{}", host, port), &rest[2..]))
+}
+
+// A length byte followed by the value, as SOCKS5 encodes domain names and credentials.
+// Longer values cannot be sent, so they are refused rather than truncated.
+fn push_field(buf: &mut Vec<u8>, value: &str, name: &str) -> std::io::Result<()> {
+    let len = u8::try_from(value.len()).map_err(|_| {
+        std::io::Error::new(std::io::ErrorKind::InvalidInput, format!("SOCKS5 {} is longer than 255 bytes", name))
+    })?;
+    buf.push(len);
+    buf.extend_from_slice(value.as_bytes());
+    Ok(())
+}
+
+fn encode_addr(buf: &mut Vec<u8>, addr: SocketAddr) {
+    match addr.ip() {
+        IpAddr::V4(ip) => {
+            buf.push(ATYP_IPV4);
+            buf.extend_from_slice(&ip.octets());
+        }
+        IpAddr::V6(ip) => {
+            buf.push(ATYP_IPV6);
+            buf.extend_from_slice(&ip.octets());
+        }
+    }
+    buf.extend_from_slice(&addr.port().to_be_bytes());
+}
+
+async fn send_reply(stream: &mut TcpStream, reply: u8, bind_addr: Option<SocketAddr>) -> std::io::Result<()> {
+    let bind_addr = bind_addr.unwrap_or_else(|| SocketAddr::from(([0, 0, 0, 0], 0)));
+
+    let mut buf = vec![SOCKS_VERSION, reply, 0x00];
+    encode_addr(&mut buf, bind_addr);
+
+    stream.write_all(&buf).await
+}
+
+fn reply_code_for(err: &std::io::Error) -> u8 {
+    match err.kind() {
+        std::io::ErrorKind::ConnectionRefused => REP_CONNECTION_REFUSED,
+        std::io::ErrorKind::NetworkUnreachable => REP_NETWORK_UNREACHABLE,
+        std::io::ErrorKind::HostUnreachable => REP_HOST_UNREACHABLE,
+        std::io::ErrorKind::TimedOut => REP_TTL_EXPIRED,
+        _ => REP_GENERAL_FAILURE,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::net::TcpListener;
+
+    // Both ends of a loopback TCP connection: (client, proxy side)
+    async fn socket_pair() -> (TcpStream, TcpStream) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let (client, accepted) = tokio::join!(TcpStream::connect(listener.local_addr().unwrap()), listener.accept());
+        (client.unwrap(), accepted.unwrap().0)
+    }
+
+    #[tokio::test]
+    async fn negotiates_a_connect_to_a_domain() {
+        let (mut client, mut server) = socket_pair().await;
+        client.write_all(&[SOCKS_VERSION, 1, METHOD_NO_AUTH]).await.unwrap();
+        client.write_all(&[SOCKS_VERSION, CMD_CONNECT, 0x00, ATYP_DOMAIN, 11]).await.unwrap();
+        client.write_all(b"example.com\x01\xbb").await.unwrap();
+
+        let request = negotiate(&mut server, "192.0.2.1").await.unwrap().unwrap();
+        assert_eq!(request.command, CMD_CONNECT);
+        assert_eq!(request.host_addr, "example.com:443");
+        assert_eq!(request.user, None);
+
+        let mut choice = [0u8; 2];
+        client.read_exact(&mut choice).await.unwrap();
+        assert_eq!(choice, [SOCKS_VERSION, METHOD_NO_AUTH]);
+    }
+
+    #[tokio::test]
+    async fn negotiates_ip_addresses() {
+        let (mut client, mut server) = socket_pair().await;
+        client.write_all(&[SOCKS_VERSION, 1, METHOD_NO_AUTH]).await.unwrap();
+        client.write_all(&[SOCKS_VERSION, CMD_UDP_ASSOCIATE, 0x00, ATYP_IPV6]).await.unwrap();
+        client.write_all(&Ipv6Addr::LOCALHOST.octets()).await.unwrap();
+        client.write_all(&8443u16.to_be_bytes()).await.unwrap();
+
+        let request = negotiate(&mut server, "192.0.2.1").await.unwrap().unwrap();
+        assert_eq!(request.command, CMD_UDP_ASSOCIATE);
+        assert_eq!(request.host_addr, "[::1]:8443");
+
+        let (mut client, mut server) = socket_pair().await;
+        client.write_all(&[SOCKS_VERSION, 1, METHOD_NO_AUTH]).await.unwrap();
+        client.write_all(&[SOCKS_VERSION, CMD_CONNECT, 0x00, ATYP_IPV4, 192, 0, 2, 10, 0x00, 0x50]).await.unwrap();
+
+        let request = negotiate(&mut server, "192.0.2.1").await.unwrap().unwrap();
+        assert_eq!(request.host_addr, "192.0.2.10:80");
+    }
+
+    #[tokio::test]
+    async fn refuses_clients_without_an_acceptable_method() {
+        let (mut client, mut server) = socket_pair().await;
+        client.write_all(&[SOCKS_VERSION, 1, METHOD_USER_PASS]).await.unwrap();
+
+        assert!(negotiate(&mut server, "192.0.2.1").await.unwrap().is_none());
+
+        let mut choice = [0u8; 2];
+        client.read_exact(&mut choice).await.unwrap();
+        assert_eq!(choice, [SOCKS_VERSION, METHOD_NO_ACCEPTABLE]);
+    }
+
+    #[tokio::test]
+    async fn refuses_unknown_address_types() {
+        let (mut client, mut server) = socket_pair().await;
+        client.write_all(&[SOCKS_VERSION, 1, METHOD_NO_AUTH]).await.unwrap();
+        client.write_all(&[SOCKS_VERSION, CMD_CONNECT, 0x00, 0x05]).await.unwrap();
+
+        assert!(negotiate(&mut server, "192.0.2.1").await.unwrap().is_none());
+
+        let mut replies = [0u8; 12];
+        client.read_exact(&mut replies).await.unwrap();
+        assert_eq!(&replies[2..4], &[SOCKS_VERSION, REP_ADDRESS_NOT_SUPPORTED]);
+    }
+
+    #[tokio::test]
+    async fn rejects_other_protocol_versions() {
+        let (mut client, mut server) = socket_pair().await;
+        client.write_all(&[0x04, 1, METHOD_NO_AUTH]).await.unwrap();
+
+        let error = negotiate(&mut server, "192.0.2.1").await.err().unwrap();
+        assert_eq!(error.kind(), std::io::ErrorKind::InvalidData);
+    }
+
+    #[tokio::test]
+    async fn connects_through_an_upstream_with_credentials() {
+        let (mut upstream, mut proxy) = socket_pair().await;
+
+        let exchange = async {
+            let mut greeting = [0u8; 3];
+            upstream.read_exact(&mut greeting).await.unwrap();
+            upstream.write_all(&[SOCKS_VERSION, METHOD_USER_PASS]).await.unwrap();
+
+            let mut auth = [0u8; 10];
+            upstream.read_exact(&mut auth).await.unwrap();
+            upstream.write_all(&[AUTH_VERSION, 0x00]).await.unwrap();
+
+            let mut request = [0u8; 18];
+            upstream.read_exact(&mut request).await.unwrap();
+            upstream.write_all(&[SOCKS_VERSION, REP_SUCCEEDED, 0x00, ATYP_IPV4, 0, 0, 0, 0, 0, 0]).await.unwrap();
+            (greeting, auth, request)
+        };
+
+        let credentials = ("alice".to_string(), "pw".to_string());
+        let (result, (greeting, auth, request)) = tokio::join!(
+            connect_via_socks5(&mut proxy, "example.com:443", Some(&credentials)),
+            exchange
+        );
+
+        result.unwrap();
+        assert_eq!(greeting, [SOCKS_VERSION, 1, METHOD_USER_PASS]);
+        assert_eq!(&auth, b"\x01\x05alice\x02pw");
+        assert_eq!(&request, b"\x05\x01\x00\x03\x0bexample.com\x01\xbb");
+    }
+
+    #[tokio::test]
+    async fn reports_a_refused_target_as_a_target_refusal() {
+        let (mut upstream, mut proxy) = socket_pair().await;
+
+        let exchange = async {
+            let mut greeting = [0u8; 3];
+            upstream.read_exact(&mut greeting).await.unwrap();
+            upstream.write_all(&[SOCKS_VERSION, METHOD_NO_AUTH]).await.unwrap();
+
+            let mut request = [0u8; 10];
+            upstream.read_exact(&mut request).await.unwrap();
+            upstream.write_all(&[SOCKS_VERSION, REP_NOT_ALLOWED, 0x00, ATYP_IPV4, 0, 0, 0, 0, 0, 0]).await.unwrap();
+        };
+
+        let (result, ()) = tokio::join!(connect_via_socks5(&mut proxy, "192.0.2.10:443", None), exchange);
+
+        assert!(crate::routing::is_target_refusal(&result.unwrap_err()));
+    }
+
+    #[tokio::test]
+    async fn refuses_credentials_longer_than_255_bytes() {
+        let (mut upstream, mut proxy) = socket_pair().await;
+
+        let exchange = async {
+            let mut greeting = [0u8; 3];
+            upstream.read_exact(&mut greeting).await.unwrap();
+            upstream.write_all(&[SOCKS_VERSION, METHOD_USER_PASS]).await.unwrap();
+        };
+
+        let credentials = ("a".repeat(256), "pw".to_string());
+        let (result, ()) = tokio::join!(connect_via_socks5(&mut proxy, "example.com:443", Some(&credentials)), exchange);
+
+        assert_eq!(result.unwrap_err().kind(), std::io::ErrorKind::InvalidInput);
+    }
+
+    #[test]
+    fn parses_udp_datagrams_for_each_address_type() {
+        let ipv4 = [0, 0, 0, ATYP_IPV4, 192, 0, 2, 1, 0x00, 0x35, b'h', b'i'];
+        assert_eq!(parse_udp_datagram(&ipv4), Some(("192.0.2.1:53".to_string(), &b"hi"[..])));
+
+        let mut ipv6 = vec![0, 0, 0, ATYP_IPV6];
+        ipv6.extend_from_slice(&Ipv6Addr::LOCALHOST.octets());
+        ipv6.extend_from_slice(&[0x01, 0xbb, b'x']);
+        assert_eq!(parse_udp_datagram(&ipv6), Some(("[::1]:443".to_string(), &b"x"[..])));
+
+        let domain = b"\x00\x00\x00\x03\x0bexample.com\x00\x35query";
+        assert_eq!(parse_udp_datagram(domain), Some(("example.com:53".to_string(), &b"query"[..])));
+    }
+
+    #[test]
+    fn drops_fragmented_and_truncated_datagrams() {
+        assert_eq!(parse_udp_datagram(&[0, 0, 1, ATYP_IPV4, 192, 0, 2, 1, 0, 53]), None);
+        assert_eq!(parse_udp_datagram(&[0, 0, 0, ATYP_IPV4, 192, 0, 2]), None);
+        assert_eq!(parse_udp_datagram(b"\x00\x00\x00\x03\x0bexample"), None);
+        assert_eq!(parse_udp_datagram(b"\x00\x00\x00\x03\x0bexample.com\x00"), None);
+        assert_eq!(parse_udp_datagram(&[0, 0, 0, 0x09, 0, 0]), None);
+    }
+
+    #[test]
+    fn reply_headers_parse_back_to_their_address() {
+        for addr in ["198.51.100.7:5353", "[2001:db8::1]:443"] {
+            let mut datagram = vec![0x00, 0x00, 0x00];
+            encode_addr(&mut datagram, addr.parse().unwrap());
+            datagram.extend_from_slice(b"payload");
+
+            assert_eq!(parse_udp_datagram(&datagram), Some((addr.to_string(), &b"payload"[..])));
+        }
+    }
+}
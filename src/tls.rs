@@ -0,0 +1,137 @@
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, SystemTime};
+use tokio_rustls::rustls::{
+    self,
+    pki_types::{pem::PemObject, CertificateDer, CertificateRevocationListDer, PrivateKeyDer},
+    server::WebPkiClientVerifier,
+    RootCertStore,
+    ServerConfig,
+};
+use tokio_rustls::TlsAcceptor;
+use x509_parser::prelude::{FromDer, GeneralName, X509Certificate};
+
+use crate::config::{ClientIdentitySource, Config};
+
+const TLS_RELOAD_INTERVAL_SECS: u64 = 30; // How often certificate files are checked for changes
+
+// The active TLS configuration, swapped out when the certificate files change
+pub type SharedTlsConfig = Arc<RwLock<Arc<ServerConfig>>>;
+
+pub fn load_server_config(config: &Config) -> Result<ServerConfig, Box<dyn std::error::Error + Send + Sync>> {
+    let (Some(cert_file), Some(key_file)) = (&config.tls_cert_file, &config.tls_key_file) else {
+        return Err("TLS_CERT_FILE and TLS_KEY_FILE must both be set".into());
+    };
+
+    let certs = CertificateDer::pem_file_iter(cert_file)?
+        .collect::<Result<Vec<_>, _>>()?;
+    if certs.is_empty() {
+        return Err(format!("no certificates found in {}", cert_file).into());
+    }
+    let key = PrivateKeyDer::from_pem_file(key_file)?;
+
+    let provider = Arc::new(rustls::crypto::ring::default_provider());
+    let builder = ServerConfig::builder_with_provider(provider.clone())
+        .with_safe_default_protocol_versions()?;
+
+    // Mutual TLS: only clients presenting a certificate signed by the configured CA are accepted
+    let builder = match &config.tls_client_ca_file {
+        Some(ca_file) => {
+            let mut roots = RootCertStore::empty();
+            for ca_cert in CertificateDer::pem_file_iter(ca_file)? {
+                roots.add(ca_cert?)?;
+            }
+
+            let mut verifier = WebPkiClientVerifier::builder_with_provider(Arc::new(roots), provider);
+            if let Some(crl_file) = &config.tls_crl_file {
+                let crls = CertificateRevocationListDer::pem_file_iter(crl_file)?
+                    .collect::<Result<Vec<_>, _>>()?;
+                verifier = verifier.with_crls(crls).only_check_end_entity_revocation();
+            }
+
+            builder.with_client_cert_verifier(verifier.build()?)
+        }
+        None => builder.with_no_client_auth(),
+    };
+
+    let mut server_config = builder.with_single_cert(certs, key)?;
+    server_config.alpn_protocols = vec![b"h2".to_vec(), b"http/1.1".to_vec()];
+
+    Ok(server_config)
+}
+
+pub fn acceptor(tls_config: &SharedTlsConfig) -> TlsAcceptor {
+    let current = tls_config.read().unwrap().clone();
+    TlsAcceptor::from(current)
+}
+
+// Poll the certificate, key, client CA and CRL files and reload them when any changes.
+// A broken replacement is logged and the previous certificate stays in use.
+pub async fn watch_certificates(tls_config: SharedTlsConfig, config: Config) {
+    let mut reload_interval = tokio::time::interval(Duration::from_secs(TLS_RELOAD_INTERVAL_SECS));
+    let mut last_modified = files_modified(&config);
+
+    loop {
+        reload_interval.tick().await;
+
+        let modified = files_modified(&config);
+        if modified == last_modified {
+            continue;
+        }
+        last_modified = modified;
+
+        match load_server_config(&config) {
+            Ok(server_config) => {
+                *tls_config.write().unwrap() = Arc::new(server_config);
+                tracing::info!("🔐 TLS certificate reloaded");
+            }
+            Err(e) => {
+                tracing::error!("❌ Failed to reload TLS certificate, keeping the previous one: {}", e);
+            }
+        }
+    }
+}
+
+// Map the verified client certificate to a user identity, preferring the configured
+// source and falling back to the other one
+pub fn client_identity(peer_certificates: Option<&[CertificateDer<'_>]>, config: &Config) -> Option<String> {
+    let der = peer_certificates?.first()?;
+    let (_, cert) = X509Certificate::from_der(der.as_ref()).ok()?;
+
+    let common_name = || {
+        cert.subject()
+            .iter_common_name()
+            .next()
+            .and_then(|cn| cn.as_str().ok())
+            .map(|cn| cn.to_string())
+    };
+    let subject_alt_name = || {
+        let san = cert.subject_alternative_name().ok()??;
+        san.value.general_names.iter().find_map(|name| match name {
+            GeneralName::RFC822Name(name) | GeneralName::DNSName(name) | GeneralName::URI(name) => {
+                Some(name.to_string())
+            }
+            _ => None,
+        })
+    };
+
+    match config.tls_client_identity {
+        ClientIdentitySource::CommonName => common_name().or_else(subject_alt_name),
+        ClientIdentitySource::SubjectAltName => subject_alt_name().or_else(common_name),
+    }
+}
+
+fn files_modified(config: &Config) -> Vec<Option<SystemTime>> {
+    [
+        &config.tls_cert_file,
+        &config.tls_key_file,
+        &config.tls_client_ca_file,
+        &config.tls_crl_file,
+    ]
+        .into_iter()
+        .map(|path| {
+            path.as_ref()
+                .and_then(|path| std::fs::metadata(path).ok())
+                .and_then(|metadata| metadata.modified().ok())
+        })
+        .collect()
+}
@@ -0,0 +1,416 @@
+use axum::{
+    body::Body,
+    extract::Request,
+    http::{header, Method, StatusCode},
+    response::{IntoResponse, Response},
+};
+use chrono::Utc;
+use hyper_util::rt::TokioIo;
+use std::net::SocketAddr;
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::net::UdpSocket;
+use tokio::sync::mpsc;
+
+use crate::dns::Resolver;
+use crate::handlers::connections::Transferred;
+use crate::pages::{self, Page, PageDetails};
+use crate::policy::{Access, Verdict};
+use crate::{
+    AppState,
+    ClientIdentity,
+    UDP_IDLE_TIMEOUT_SECS,
+    check_policy,
+    mark_connection_failed,
+    record_allowed,
+    record_blocked,
+    record_tunnel_result,
+    terminable,
+    transferred,
+};
+
+// Datagrams queued per flow before new ones are dropped
+pub const UDP_CHANNEL_CAPACITY: usize = 256;
+const MAX_DATAGRAM_SIZE: usize = 65535;
+
+// RFC 9298 capsule and URI template constants
+const CONNECT_UDP_PATH_PREFIX: &str = "/.well-known/masque/udp/";
+const CAPSULE_TYPE_DATAGRAM: u64 = 0x00;
+const MAX_CAPSULE_SIZE: u64 = MAX_DATAGRAM_SIZE as u64 + 8;
+
+// Resolve a `host:port` target and open a UDP socket connected to it,
+// so only datagrams from that peer are received on it. Addresses are tried in the
+// resolver's order; one the host has no route to fails here and the next is used.
+pub async fn connect_target(resolver: &Resolver, host_addr: &str) -> std::io::Result<UdpSocket> {
+    let mut last_error = None;
+
+    for target in resolver.resolve(host_addr).await?.addrs {
+        match connect_socket(target).await {
+            Ok(socket) => return Ok(socket),
+            Err(e) => {
+                tracing::debug!("UDP target {} ({}) unusable: {}", host_addr, target, e);
+                last_error = Some(e);
+            }
+        }
+    }
+
+    Err(last_error.unwrap_or_else(|| {
+        std::io::Error::new(std::io::ErrorKind::NotFound, format!("No addresses for {}", host_addr))
+    }))
+}
+
+async fn connect_socket(target: SocketAddr) -> std::io::Result<UdpSocket> {
+    let bind_addr: SocketAddr = if target.is_ipv4() {
+        SocketAddr::from(([0, 0, 0, 0], 0))
+    } else {
+        SocketAddr::from(([0u16; 8], 0))
+    };
+
+    let socket = UdpSocket::bind(bind_addr).await?;
+    socket.connect(target).await?;
+    Ok(socket)
+}
+
+// Relay one UDP flow: datagrams from `outbound` are sent to the target and replies are
+// framed and pushed to `inbound`. Ends once the flow has been idle for the idle timeout
+// or either side goes away, returning the payload bytes sent and received.
+pub async fn run_flow<F>(
+    socket: UdpSocket,
+    mut outbound: mpsc::Receiver<Vec<u8>>,
+    inbound: mpsc::Sender<Vec<u8>>,
+    frame: F,
+    transferred: Transferred,
+) -> std::io::Result<(u64, u64)>
+where
+    F: Fn(&[u8]) -> Vec<u8>,
+{
+    let idle_timeout = Duration::from_secs(UDP_IDLE_TIMEOUT_SECS);
+    let idle = tokio::time::sleep(idle_timeout);
+    tokio::pin!(idle);
+
+    let mut buf = vec![0u8; MAX_DATAGRAM_SIZE];
+    let mut bytes_sent = 0u64;
+    let mut bytes_received = 0u64;
+
+    loop {
+        tokio::select! {
+            datagram = outbound.recv() => {
+                let Some(datagram) = datagram else { break };
+                socket.send(&datagram).await?;
+                transferred.add_sent(datagram.len() as u64);
+                bytes_sent += datagram.len() as u64;
+            }
+            received = socket.recv(&mut buf) => {
+                let len = match received {
+                    Ok(len) => len,
+                    // ICMP port unreachable from the target surfaces here; keep the flow open
+                    Err(e) if e.kind() == std::io::ErrorKind::ConnectionRefused => continue,
+                    Err(e) => return Err(e),
+                };
+                transferred.add_received(len as u64);
+                bytes_received += len as u64;
+                if inbound.send(frame(&buf[..len])).await.is_err() {
+                    break;
+                }
+            }
+            _ = &mut idle => break,
+        }
+
+        idle.as_mut().reset(tokio::time::Instant::now() + idle_timeout);
+    }
+
+    Ok((bytes_sent, bytes_received))
+}
+
+// Whether the request asks for an RFC 9298 UDP tunnel, either as an HTTP/1.1 Upgrade
+// or as an HTTP/2 extended CONNECT with `:protocol = connect-udp`
+pub fn is_connect_udp(req: &Request) -> bool {
+    if !req.uri().path().starts_with(CONNECT_UDP_PATH_PREFIX) {
+        return false;
+    }
+
+    if req.method() == Method::CONNECT {
+        return req.extensions().get::<hyper::ext::Protocol>()
+            .is_some_and(|protocol| protocol.as_str().eq_ignore_ascii_case("connect-udp"));
+    }
+
+    req.method() == Method::GET
+        && req.headers().get(header::UPGRADE)
+            .and_then(|v| v.to_str().ok())
+            .is_some_and(|v| v.eq_ignore_ascii_case("connect-udp"))
+}
+
+// Parse `/.well-known/masque/udp/{target_host}/{target_port}/` into a `host:port` authority
+fn connect_udp_target(path: &str) -> Option<String> {
+    let rest = path.strip_prefix(CONNECT_UDP_PATH_PREFIX)?;
+    let mut parts = rest.trim_end_matches('/').splitn(2, '/');
+    let host = parts.next()?.replace("%3A", ":").replace("%3a", ":");
+    let port: u16 = parts.next()?.parse().ok()?;
+
+    if host.is_empty() {
+        return None;
+    }
+
+    if host.contains(':') {
+        Some(format!("[{}]:{}", host, port))
+    } else {
+        Some(format!("{}:{}", host, port))
+    }
+}
+
+pub async fn connect_udp(
+    req: Request,
+    app_state: AppState,
+    client: ClientIdentity,
+) -> Result<Response, hyper::Error> {
+    let user_agent = req.headers().get("user-agent")
+        .and_then(|v| v.to_str().ok())
+        .map(|s| s.to_string());
+    let http2 = req.method() == Method::CONNECT;
+
+    let Some(host_addr) = connect_udp_target(req.uri().path()) else {
+        tracing::warn!("⚠️ Invalid CONNECT-UDP request from {}: {:?}", client, req.uri());
+        return Ok((
+            StatusCode::BAD_REQUEST,
+            "CONNECT-UDP target must be /.well-known/masque/udp/{host}/{port}/",
+        ).into_response());
+    };
+
+    let evaluation = check_policy(&app_state, &client, &host_addr, Access::Udp);
+    if evaluation.verdict != Verdict::Allowed {
+        let conn_key = record_blocked(&app_state, &client, &host_addr, user_agent, "udp", evaluation.verdict.as_str()).await;
+        return Ok(pages::render(Page::Blocked, PageDetails {
+            rule: evaluation.rule,
+            ..PageDetails::for_connection(&client, &host_addr, &conn_key)
+        }, req.headers()));
+    }
+
+    let conn_key = record_allowed(&app_state, &client, &host_addr, user_agent, "udp").await;
+
+    let socket = match connect_target(&app_state.resolver, &host_addr).await {
+        Ok(socket) => socket,
+        Err(e) => {
+            tracing::error!("❌ UDP target error: {} → {} | Error: {}", client, host_addr, e);
+            mark_connection_failed(&app_state.monitoring_state, &conn_key);
+            return Ok((StatusCode::BAD_GATEWAY, "Unable to reach UDP target").into_response());
+        }
+    };
+
+    tokio::task::spawn(async move {
+        terminable(&app_state, &conn_key, &client, &host_addr, async {
+            match hyper::upgrade::on(req).await {
+                Ok(upgraded) => {
+                    let start_time = Utc::now();
+                    let result = relay_capsules(TokioIo::new(upgraded), socket, transferred(&app_state, &conn_key)).await;
+                    record_tunnel_result(&app_state, &conn_key, &client, &host_addr, start_time, Ok(result)).await;
+                }
+                Err(e) => {
+                    tracing::warn!("❌ Upgrade error: {} → {} | Error: {}", client, host_addr, e);
+                    mark_connection_failed(&app_state.monitoring_state, &conn_key);
+                }
+            }
+        }).await;
+    });
+
+    // HTTP/2 extended CONNECT is accepted with a 200, HTTP/1.1 switches protocols
+    let response = if http2 {
+        Response::builder().status(StatusCode::OK)
+    } else {
+        Response::builder()
+            .status(StatusCode::SWITCHING_PROTOCOLS)
+            .header(header::CONNECTION, "Upgrade")
+            .header(header::UPGRADE, "connect-udp")
+    };
+
+    Ok(response
+        .header("Capsule-Protocol", "?1")
+        .body(Body::empty())
+        .unwrap())
+}
+
+// Carry UDP payloads as DATAGRAM capsules over an upgraded HTTP stream
+pub async fn relay_capsules<T>(io: T, socket: UdpSocket, transferred: Transferred) -> std::io::Result<(u64, u64)>
+where
+    T: AsyncRead + AsyncWrite + Send + 'static,
+{
+    let (mut reader, mut writer) = tokio::io::split(io);
+    let (outbound_tx, outbound_rx) = mpsc::channel::<Vec<u8>>(UDP_CHANNEL_CAPACITY);
+    let (inbound_tx, mut inbound_rx) = mpsc::channel::<Vec<u8>>(UDP_CHANNEL_CAPACITY);
+
+    let reader_task = tokio::spawn(async move {
+        while let Ok(Some(payload)) = read_datagram_capsule(&mut reader).await {
+            // Drop rather than stall the reader when the flow is saturated
+            if let Err(mpsc::error::TrySendError::Closed(_)) = outbound_tx.try_send(payload) {
+                break;
+            }
+        }
+    });
+
+    let writer_task = tokio::spawn(async move {
+        while let Some(capsule) = inbound_rx.recv().await {
+            if writer.write_all(&capsule).await.is_err() {
+                break;
+            }
+        }
+        let _ = writer.shutdown().await;
+    });
+
+    let result = run_flow(socket, outbound_rx, inbound_tx, encode_datagram_capsule, transferred).await;
+
+    reader_task.abort();
+    let _ = writer_task.await;
+
+    result
+}
+
+// Read capsules until a DATAGRAM with context ID 0 arrives; other capsules are skipped.
+// Returns `None` on a clean end of stream.
+async fn read_datagram_capsule<R>(reader: &mut R) -> std::io::Result<Option<Vec<u8>>>
+where
+    R: AsyncRead + Unpin,
+{
+    loop {
+        let capsule_type = match read_varint(reader).await {
+            Ok(value) => value,
+            Err(e) if e.kind() == std::io::ErrorKind::UnexpectedEof => return Ok(None),
+            Err(e) => return Err(e),
+        };
+        let length = read_varint(reader).await?;
+        if length > MAX_CAPSULE_SIZE {
+            return Err(std::io::Error::new(std::io::ErrorKind::InvalidData, "Capsule too large"));
+        }
+
+        let mut payload = vec![0u8; length as usize];
+        reader.read_exact(&mut payload).await?;
+
+        if capsule_type != CAPSULE_TYPE_DATAGRAM {
+            continue;
+        }
+
+        match decode_varint(&payload) {
+            Some((0, offset)) => return Ok(Some(payload[offset..].to_vec())),
+            _ => continue,
+        }
+    }
+}
+
+fn encode_datagram_capsule(data: &[u8]) -> Vec<u8> {
+    let mut capsule = Vec::with_capacity(data.len() + 10);
+    encode_varint(CAPSULE_TYPE_DATAGRAM, &mut capsule);
+    // Context ID 0 takes one byte on the wire
+    encode_varint(data.len() as u64 + 1, &mut capsule);
+    encode_varint(0, &mut capsule);
+    capsule.extend_from_slice(data);
+    capsule
+}
+
+// QUIC variable-length integer encoding (RFC 9000 §16)
+fn encode_varint(value: u64, buf: &mut Vec<u8>) {
+    if value < 1 << 6 {
+        buf.push(value as u8);
+    } else if value < 1 << 14 {
+        buf.extend_from_slice(&((value as u16) | 0x4000).to_be_bytes());
+    } else if value < 1 << 30 {
+        buf.extend_from_slice(&((value as u32) | 0x8000_0000).to_be_bytes());
+    } else {
+        buf.extend_from_slice(&(value | 0xc000_0000_0000_0000).to_be_bytes());
+    }
+}
+
+fn decode_varint(buf: &[u8]) -> Option<(u64, usize)> {
+    let first = *buf.first()?;
+    let len = 1usize << (first >> 6);
+    if buf.len() < len {
+        return None;
+    }
+
+    let value = buf[1..len].iter()
+        .fold((first & 0x3f) as u64, |acc, b| (acc << 8) | *b as u64);
+    Some((value, len))
+}
+
+async fn read_varint<R>(reader: &mut R) -> std::io::Result<u64>
+where
+    R: AsyncRead + Unpin,
+{
+    let first = reader.read_u8().await?;
+    let len = 1usize << (first >> 6);
+
+    let mut value = (first & 0x3f) as u64;
+    for _ in 1..len {
+        value = (value << 8) | reader.read_u8().await? as u64;
+    }
+    Ok(value)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn decodes_the_rfc_9000_varint_examples() {
+        assert_eq!(decode_varint(&[0x25]), Some((37, 1)));
+        assert_eq!(decode_varint(&[0x40, 0x25]), Some((37, 2)));
+        assert_eq!(decode_varint(&[0x7b, 0xbd]), Some((15293, 2)));
+        assert_eq!(decode_varint(&[0x9d, 0x7f, 0x3e, 0x7d]), Some((494878333, 4)));
+        assert_eq!(
+            decode_varint(&[0xc2, 0x19, 0x7c, 0x5e, 0xff, 0x14, 0xe8, 0x8c]),
+            Some((151288809941952652, 8)),
+        );
+        assert_eq!(decode_varint(&[0x9d, 0x7f]), None);
+        assert_eq!(decode_varint(&[]), None);
+    }
+
+    #[tokio::test]
+    async fn varints_round_trip_at_each_length() {
+        for value in [0, 63, 64, 16383, 16384, (1 << 30) - 1, 1 << 30, (1 << 62) - 1] {
+            let mut buf = Vec::new();
+            encode_varint(value, &mut buf);
+
+            assert_eq!(decode_varint(&buf), Some((value, buf.len())));
+            assert_eq!(read_varint(&mut buf.as_slice()).await.unwrap(), value);
+        }
+    }
+
+    #[tokio::test]
+    async fn reads_back_an_encoded_datagram_capsule() {
+        let capsule = encode_datagram_capsule(b"hello");
+        assert_eq!(capsule, [0x00, 0x06, 0x00, b'h', b'e', b'l', b'l', b'o']);
+
+        let mut reader = capsule.as_slice();
+        assert_eq!(read_datagram_capsule(&mut reader).await.unwrap(), Some(b"hello".to_vec()));
+        assert_eq!(read_datagram_capsule(&mut reader).await.unwrap(), None);
+    }
+
+    #[tokio::test]
+    async fn skips_other_capsule_types_and_contexts() {
+        let mut stream = vec![0x17, 0x02, 0xaa, 0xbb];
+        stream.extend_from_slice(&[0x00, 0x03, 0x02, b'n', b'o']);
+        stream.extend_from_slice(&encode_datagram_capsule(b"yes"));
+
+        let mut reader = stream.as_slice();
+        assert_eq!(read_datagram_capsule(&mut reader).await.unwrap(), Some(b"yes".to_vec()));
+    }
+
+    #[tokio::test]
+    async fn rejects_oversized_and_truncated_capsules() {
+        let mut oversized = vec![0x00];
+        encode_varint(MAX_CAPSULE_SIZE + 1, &mut oversized);
+        let error = read_datagram_capsule(&mut oversized.as_slice()).await.unwrap_err();
+        assert_eq!(error.kind(), std::io::ErrorKind::InvalidData);
+
+        let truncated = [0x00, 0x06, 0x00, b'h'];
+        let error = read_datagram_capsule(&mut truncated.as_slice()).await.unwrap_err();
+        assert_eq!(error.kind(), std::io::ErrorKind::UnexpectedEof);
+    }
+
+    #[test]
+    fn parses_connect_udp_targets() {
+        assert_eq!(connect_udp_target("/.well-known/masque/udp/example.com/53/"), Some("example.com:53".to_string()));
+        assert_eq!(connect_udp_target("/.well-known/masque/udp/192.0.2.1/443"), Some("192.0.2.1:443".to_string()));
+        assert_eq!(connect_udp_target("/.well-known/masque/udp/2001%3Adb8%3A%3A1/53/"), Some("[2001:db8::1]:53".to_string()));
+        assert_eq!(connect_udp_target("/.well-known/masque/udp//53/"), None);
+        assert_eq!(connect_udp_target("/.well-known/masque/udp/example.com/dns/"), None);
+        assert_eq!(connect_udp_target("/other/example.com/53/"), None);
+    }
+}